@@ -1,4 +1,3 @@
-use hyper::Client;
 use std::borrow::Borrow;
 use std::fmt::Write;
 use test_crs::engine::{get_value_from_source, SourceType};
@@ -45,27 +44,14 @@ fn main() -> Result<(), CRSError> {
         }
     }
 
-    // rt.block_on(async {
-    //     let client = Client::new();
-    //
-    //     for stage in ftw_stages {
-    //         let ftw::Stage { input, output } = stage;
-    //         if let Ok(req) = input.request() {
-    //             let mut res = client.request(req).await?;
-    //             println!("Response: {}", res.status());
-    //             println!("Headers: {:#?}\n", res.headers());
-    //         }
-    //     }
-    //
-    //     // // Stream the body, writing each chunk to stdout as we get it
-    //     // // (instead of buffering and printing at the end).
-    //     // while let Some(next) = res.data().await {
-    //     //     let chunk = next?;
-    //     //     io::stdout().write_all(&chunk).await?;
-    //     // }
-    //     Ok(())
-    // })
-    // .unwrap();
+    let runner = ftw::Runner::new(Default::default())?;
+    rt.block_on(async {
+        for file in &ftw_files {
+            for result in runner.run_file(file).await {
+                println!("{}: {}", result.title, result.status);
+            }
+        }
+    });
 
     Ok(())
 }
@@ -0,0 +1,99 @@
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use test_crs::syntax::pretty;
+
+const USAGE: &str = "usage: crs-fmt [--check] [FILE]...
+
+Formats ModSecurity/CRS rule configs in the CRS house style. Files are rewritten in place, or if
+no files are given, input is read from stdin and written to stdout.
+
+options:
+    --check    don't write anything, exit with a non-zero status if any file isn't formatted";
+
+fn main() -> ExitCode {
+    let mut check = false;
+    let mut paths = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n\n{}", arg, USAGE);
+                return ExitCode::from(2);
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    let result = if paths.is_empty() {
+        format_stdin(check)
+    } else {
+        // every file is formatted, even after one fails
+        let mut already_formatted = true;
+        let mut failed = false;
+        for path in &paths {
+            match format_file(path, check) {
+                Ok(formatted) => already_formatted &= formatted,
+                Err(()) => failed = true,
+            }
+        }
+        if failed {
+            Err(())
+        } else {
+            Ok(already_formatted)
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(()) => ExitCode::from(2),
+    }
+}
+
+/// Formats the given input, printing any parse errors. `name` is used in error messages.
+fn format(name: &str, input: &str) -> Result<String, ()> {
+    pretty::format_str(input).map_err(|errors| {
+        for error in errors {
+            eprintln!("{}: {}", name, error);
+        }
+    })
+}
+
+/// Returns whether the input was already formatted.
+fn format_stdin(check: bool) -> Result<bool, ()> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|err| eprintln!("<stdin>: {}", err))?;
+
+    let formatted = format("<stdin>", &input)?;
+    if !check {
+        io::stdout()
+            .write_all(formatted.as_bytes())
+            .map_err(|err| eprintln!("<stdout>: {}", err))?;
+    }
+    Ok(formatted == input)
+}
+
+/// Returns whether the file was already formatted.
+fn format_file(path: &PathBuf, check: bool) -> Result<bool, ()> {
+    let name = path.display().to_string();
+    let input = std::fs::read_to_string(path).map_err(|err| eprintln!("{}: {}", name, err))?;
+
+    let formatted = format(&name, &input)?;
+    if formatted == input {
+        return Ok(true);
+    }
+
+    if check {
+        println!("{} is not formatted", name);
+    } else {
+        std::fs::write(path, formatted).map_err(|err| eprintln!("{}: {}", name, err))?;
+    }
+    Ok(false)
+}
@@ -0,0 +1,98 @@
+use std::path::Path;
+use std::process::ExitCode;
+use test_crs::lint::{self, Diagnostic, Level};
+use test_crs::syntax::{self, CRSParseError, ParseReport};
+
+const USAGE: &str = "usage: crs-lint [--format text|json] PATH...
+
+Checks ModSecurity/CRS rule configs against CRS conventions. Each PATH can be a rule config or a
+directory of rule configs.
+
+options:
+    --format FORMAT    output format, either text (the default) or json";
+
+enum Format {
+    Text,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let mut format = Format::Text;
+    let mut paths = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => match args.next().as_deref() {
+                Some("text") => format = Format::Text,
+                Some("json") => format = Format::Json,
+                _ => {
+                    eprintln!("--format must be one of text, json\n\n{}", USAGE);
+                    return ExitCode::from(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n\n{}", arg, USAGE);
+                return ExitCode::from(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    }
+
+    let mut files = vec![];
+    let mut diagnostics = vec![];
+    for path in &paths {
+        match load(Path::new(path)) {
+            Ok(reports) => {
+                for report in reports {
+                    let (file, errors) = report.into_file();
+                    files.push(file);
+                    diagnostics.extend(errors.iter().map(Diagnostic::from_parse_error));
+                }
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    diagnostics.extend(lint::lint(&files));
+
+    match format {
+        Format::Text => {
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+        }
+        Format::Json => match serde_json::to_string_pretty(&diagnostics) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::from(2);
+            }
+        },
+    }
+
+    if diagnostics.iter().any(|d| d.level == Level::Error) {
+        ExitCode::from(1)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<ParseReport>, CRSParseError> {
+    if path.is_dir() {
+        syntax::parse_all_conf_recovering(path)
+    } else {
+        Ok(vec![syntax::parse_conf_recovering(path)?])
+    }
+}
@@ -0,0 +1,549 @@
+//! Engine-side effects of rule actions.
+//!
+//! Non-disruptive actions are applied directly to the [`Transaction`] when a rule matches, while
+//! disruptive and flow actions are returned as an [`Outcome`], since whether (and when) they take
+//! effect depends on the rest of the chain and on the engine mode.
+
+use super::collections::{self, PERSISTENT_COLLECTIONS};
+use super::operators::resolve_path;
+use super::transaction::{RuleEngine, Transaction};
+use crate::syntax::atoi;
+use crate::syntax::{Action, ActionType};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use std::{fs, io};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("action {0} requires an argument")]
+    MissingArgument(&'static str),
+    #[error("invalid argument for action {action}, {reason}")]
+    InvalidArgument {
+        action: &'static str,
+        reason: String,
+    },
+    #[error("collection {0} is not supported")]
+    UnsupportedCollection(String),
+    #[error("collection {0} hasn't been initialised with initcol")]
+    UninitialisedCollection(String),
+    #[error(transparent)]
+    CollectionError(#[from] collections::Error),
+    #[error("{0} is not in the exec allowlist")]
+    NotAllowed(String),
+    #[error("failed to run {path}, {source}")]
+    ExecError { path: String, source: io::Error },
+}
+
+/// Settings that restrict what actions are allowed to do.
+#[derive(Debug, Clone, Default)]
+pub struct ActionConfig {
+    /// Scripts that `exec` is allowed to run. Any other script is rejected, so by default `exec`
+    /// can't be used at all. As with `@inspectFile`, a relative script in a rule is relative to the
+    /// config file the rule is defined in.
+    pub exec_allowlist: Vec<PathBuf>,
+}
+
+/// How much of the transaction `allow` skips.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllowScope {
+    /// Skip all remaining rules, for both the request and the response.
+    Transaction,
+    /// Skip the remaining rules in the current phase only.
+    Phase,
+    /// Skip the remaining request phases, but still inspect the response.
+    Request,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Disruption {
+    Allow(AllowScope),
+    /// Use the disruptive action from `SecDefaultAction`.
+    Block,
+    Deny,
+    Drop,
+    Pass,
+    Pause(Duration),
+    Proxy(String),
+    Redirect(String),
+}
+
+/// What the engine should do after an action has been applied.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Continue,
+    Disrupt(Disruption),
+    /// Skip the given number of rules (or chains).
+    Skip(usize),
+    /// Skip to the rule or marker with the given ID.
+    SkipAfter(String),
+}
+
+/// Metadata from a rule's actions, used when reporting matches.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RuleMetadata {
+    pub id: Option<u64>,
+    pub phase: Option<u8>,
+    pub msg: Option<String>,
+    /// The rule's `logdata`, with its macros unexpanded.
+    pub logdata: Option<String>,
+    pub tags: Vec<String>,
+    pub revision: Option<String>,
+    pub version: Option<String>,
+    /// Numeric severity on the syslog scale, where 0 is the most severe.
+    pub severity: Option<u8>,
+    pub accuracy: Option<u8>,
+    pub maturity: Option<u8>,
+    /// Whether matches must not be written to the error log, set with `nolog`.
+    pub nolog: bool,
+}
+
+fn invalid(action: ActionType, reason: impl Into<String>) -> Error {
+    Error::InvalidArgument {
+        action: action.name(),
+        reason: reason.into(),
+    }
+}
+
+fn required(action: &Action) -> Result<&str, Error> {
+    action
+        .argument()
+        .ok_or_else(|| Error::MissingArgument(action.action.name()))
+}
+
+fn parse<T: std::str::FromStr>(action: &Action) -> Result<T, Error> {
+    let arg = required(action)?;
+    arg.trim()
+        .parse()
+        .map_err(|_| invalid(action.action, format!("{} is not a valid value", arg)))
+}
+
+/// Parses a number that must be within `range`.
+fn parse_level(action: &Action, range: RangeInclusive<u8>) -> Result<u8, Error> {
+    let level: u8 = parse(action)?;
+    if range.contains(&level) {
+        Ok(level)
+    } else {
+        Err(invalid(
+            action.action,
+            format!(
+                "{} is not between {} and {}",
+                level,
+                range.start(),
+                range.end()
+            ),
+        ))
+    }
+}
+
+fn parse_severity(action: &Action) -> Result<u8, Error> {
+    const NAMES: [&str; 8] = [
+        "EMERGENCY",
+        "ALERT",
+        "CRITICAL",
+        "ERROR",
+        "WARNING",
+        "NOTICE",
+        "INFO",
+        "DEBUG",
+    ];
+    let arg = required(action)?.trim();
+    match NAMES.iter().position(|name| name.eq_ignore_ascii_case(arg)) {
+        Some(severity) => Ok(severity as u8),
+        None => parse_level(action, 0..=7),
+    }
+}
+
+fn parse_phase(action: &Action) -> Result<u8, Error> {
+    match required(action)?.trim() {
+        "request" => Ok(2),
+        "response" => Ok(4),
+        "logging" => Ok(5),
+        _ => parse_level(action, 1..=5),
+    }
+}
+
+impl RuleMetadata {
+    pub fn from_actions(actions: &[Action]) -> Result<Self, Error> {
+        use ActionType::*;
+
+        let mut metadata = Self::default();
+        for action in actions {
+            let text = || required(action).map(String::from);
+            match action.action {
+                Id => metadata.id = Some(parse(action)?),
+                Phase => metadata.phase = Some(parse_phase(action)?),
+                Msg => metadata.msg = Some(text()?),
+                LogData => metadata.logdata = Some(text()?),
+                Tag => metadata.tags.push(text()?),
+                Revision => metadata.revision = Some(text()?),
+                Version => metadata.version = Some(text()?),
+                Severity => metadata.severity = Some(parse_severity(action)?),
+                Accuracy => metadata.accuracy = Some(parse_level(action, 1..=9)?),
+                Maturity => metadata.maturity = Some(parse_level(action, 1..=9)?),
+                Log => metadata.nolog = false,
+                NoLog => metadata.nolog = true,
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Splits `name=value`, returning `None` as the value if there's no `=`.
+fn split_assignment(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value)),
+        None => (arg.trim(), None),
+    }
+}
+
+/// Splits a variable like `ip.counter` into its collection and name.
+fn split_variable(action: &Action, target: &str) -> Result<(String, String), Error> {
+    match target.split_once('.') {
+        Some((collection, name)) => Ok((collection.trim().to_lowercase(), name.to_lowercase())),
+        None => Err(invalid(
+            action.action,
+            format!("{} is not a variable", target),
+        )),
+    }
+}
+
+/// The new value of a variable set with `setvar`, given its current value. `=+` and `=-`
+/// increment and decrement it, and a missing value sets it to `1`.
+fn setvar_value(current: Option<&str>, value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "1".to_string(),
+    };
+    match value.strip_prefix('+').or_else(|| value.strip_prefix('-')) {
+        Some(amount) => {
+            let current = current.map_or(0, |v| atoi(v.as_bytes()));
+            let amount = atoi(amount.as_bytes());
+            match value.starts_with('+') {
+                true => current.saturating_add(amount).to_string(),
+                false => current.saturating_sub(amount).to_string(),
+            }
+        }
+        None => value.to_string(),
+    }
+}
+
+fn apply_setvar(action: &Action, transaction: &mut Transaction) -> Result<(), Error> {
+    let arg = transaction.expand(required(action)?);
+    let (target, value) = split_assignment(&arg);
+    let (remove, target) = match target.strip_prefix('!') {
+        Some(target) => (true, target),
+        None => (false, target),
+    };
+    let (collection, name) = split_variable(action, target)?;
+
+    if collection == "tx" {
+        if remove {
+            transaction.tx.remove(&name);
+        } else {
+            let value = setvar_value(transaction.tx.get(&name).map(String::as_str), value);
+            transaction.tx.insert(name, value);
+        }
+        return Ok(());
+    }
+
+    persistent_collection(transaction, &collection)?;
+    // the new value is computed while the record is locked, so that concurrent increments
+    // aren't lost
+    transaction
+        .collections
+        .update(&collection, |record, now| match remove {
+            true => record.remove(&name),
+            false => {
+                let value = setvar_value(record.get(&name, now).as_deref(), value);
+                record.set(&name, value, now);
+            }
+        })?;
+    Ok(())
+}
+
+/// Checks that a collection is a persistent collection that the transaction has opened.
+fn persistent_collection(transaction: &Transaction, collection: &str) -> Result<(), Error> {
+    if !PERSISTENT_COLLECTIONS.contains(&collection) {
+        Err(Error::UnsupportedCollection(collection.into()))
+    } else if !transaction.collections.is_open(collection) {
+        Err(Error::UninitialisedCollection(collection.into()))
+    } else {
+        Ok(())
+    }
+}
+
+fn apply_initcol(action: &Action, transaction: &mut Transaction) -> Result<(), Error> {
+    let arg = transaction.expand(required(action)?);
+    match split_assignment(&arg) {
+        (collection, Some(key)) => {
+            let collection = collection.to_lowercase();
+            if !PERSISTENT_COLLECTIONS.contains(&collection.as_str()) {
+                return Err(Error::UnsupportedCollection(collection));
+            }
+            transaction.collections.open(&collection, key.trim())?;
+            Ok(())
+        }
+        _ => Err(invalid(
+            action.action,
+            format!("expected collection=key, got {}", arg),
+        )),
+    }
+}
+
+/// Applies `expirevar:ip.blocked=60`, or `deprecatevar:ip.score=60/300`.
+fn apply_expiry(action: &Action, transaction: &mut Transaction) -> Result<(), Error> {
+    let arg = transaction.expand(required(action)?);
+    let (target, value) = split_assignment(&arg);
+    let (collection, name) = split_variable(action, target)?;
+    let value = value.unwrap_or_default().trim();
+    let number = |n: &str| {
+        n.trim()
+            .parse::<u64>()
+            .map_err(|_| invalid(action.action, format!("{} is not a number", n)))
+    };
+
+    persistent_collection(transaction, &collection)?;
+    if action.action == ActionType::ExpireVar {
+        let seconds = number(value)?;
+        transaction.collections.update(&collection, |record, now| {
+            record.expire(&name, seconds, now)
+        })?;
+    } else {
+        let (amount, period) = value
+            .split_once('/')
+            .ok_or_else(|| invalid(action.action, format!("expected N/S, got {}", value)))?;
+        let (amount, period) = (number(amount)?, number(period)?);
+        let amount = amount.min(i64::MAX as u64) as i64;
+        transaction.collections.update(&collection, |record, now| {
+            record.deprecate(&name, amount, period, now)
+        })?;
+    }
+    Ok(())
+}
+
+fn apply_ctl(action: &Action, transaction: &mut Transaction) -> Result<(), Error> {
+    let arg = required(action)?;
+    let (option, value) = match split_assignment(arg) {
+        (option, Some(value)) => (option, value.trim()),
+        _ => {
+            return Err(invalid(
+                action.action,
+                format!("expected option=value, got {}", arg),
+            ))
+        }
+    };
+
+    let config = &mut transaction.config;
+    match option {
+        "ruleEngine" => {
+            config.rule_engine = Some(match value.to_ascii_lowercase().as_str() {
+                "on" => RuleEngine::On,
+                "off" => RuleEngine::Off,
+                "detectiononly" => RuleEngine::DetectionOnly,
+                _ => {
+                    return Err(invalid(
+                        action.action,
+                        format!("invalid rule engine {}", value),
+                    ))
+                }
+            })
+        }
+        "ruleRemoveById" => {
+            let parse_id = |id: &str| {
+                id.trim()
+                    .parse::<u64>()
+                    .map_err(|_| invalid(action.action, format!("invalid rule id {}", id)))
+            };
+            let range = match value.split_once('-') {
+                Some((start, end)) => parse_id(start)?..=parse_id(end)?,
+                None => parse_id(value)?..=parse_id(value)?,
+            };
+            config.removed_rule_ids.push(range);
+        }
+        "ruleRemoveByTag" => config.removed_rule_tags.push(value.into()),
+        "requestBodyProcessor" => config.request_body_processor = Some(value.into()),
+        "auditEngine" => config.audit_engine = Some(value.into()),
+        _ => config.other.push((option.into(), value.into())),
+    }
+    Ok(())
+}
+
+fn apply_exec(action: &Action, config: &ActionConfig) -> Result<(), Error> {
+    let script = required(action)?.trim();
+    let not_allowed = || Error::NotAllowed(script.into());
+    let path = resolve_path(Path::new(script), &action.span);
+    let canonical = fs::canonicalize(path).map_err(|_| not_allowed())?;
+    let allowed = config
+        .exec_allowlist
+        .iter()
+        .filter_map(|allowed| fs::canonicalize(allowed).ok())
+        .any(|allowed| allowed == canonical);
+    if !allowed {
+        return Err(not_allowed());
+    }
+
+    Command::new(&canonical)
+        .output()
+        .map_err(|source| Error::ExecError {
+            path: script.into(),
+            source,
+        })?;
+    Ok(())
+}
+
+/// Applies a single action of a rule that matched.
+pub fn apply(
+    action: &Action,
+    transaction: &mut Transaction,
+    config: &ActionConfig,
+) -> Result<Outcome, Error> {
+    use ActionType::*;
+
+    let expanded = |transaction: &Transaction| required(action).map(|arg| transaction.expand(arg));
+
+    match action.action {
+        Allow => {
+            let scope = match action.argument() {
+                None => AllowScope::Transaction,
+                Some("phase") => AllowScope::Phase,
+                Some("request") => AllowScope::Request,
+                Some(scope) => {
+                    return Err(invalid(action.action, format!("unknown scope {}", scope)))
+                }
+            };
+            return Ok(Outcome::Disrupt(Disruption::Allow(scope)));
+        }
+        Block => return Ok(Outcome::Disrupt(Disruption::Block)),
+        Deny => return Ok(Outcome::Disrupt(Disruption::Deny)),
+        Drop => return Ok(Outcome::Disrupt(Disruption::Drop)),
+        Pass => return Ok(Outcome::Disrupt(Disruption::Pass)),
+        Pause => {
+            let millis: u64 = parse(action)?;
+            return Ok(Outcome::Disrupt(Disruption::Pause(Duration::from_millis(
+                millis,
+            ))));
+        }
+        Proxy => return Ok(Outcome::Disrupt(Disruption::Proxy(expanded(transaction)?))),
+        Redirect => {
+            return Ok(Outcome::Disrupt(Disruption::Redirect(expanded(
+                transaction,
+            )?)))
+        }
+        Skip => match parse::<usize>(action)? {
+            0 => return Err(invalid(action.action, "must skip at least one rule")),
+            count => return Ok(Outcome::Skip(count)),
+        },
+        SkipAfter => return Ok(Outcome::SkipAfter(required(action)?.trim().into())),
+        Status => {
+            let status: u16 = parse(action)?;
+            if !(100..=599).contains(&status) {
+                return Err(invalid(action.action, format!("invalid status {}", status)));
+            }
+            transaction.status = Some(status);
+        }
+        AuditLog => transaction.audit_log = true,
+        Setvar => apply_setvar(action, transaction)?,
+        SetEnv => {
+            let arg = expanded(transaction)?;
+            match split_assignment(&arg) {
+                (name, _) if name.starts_with('!') => {
+                    transaction.env.remove(&name[1..]);
+                }
+                (name, value) => {
+                    let value = value.unwrap_or("1").to_string();
+                    transaction.env.insert(name.into(), value);
+                }
+            }
+        }
+        // like ModSecurity, these also open the matching persistent collection
+        SetUser => {
+            let id = expanded(transaction)?;
+            transaction.collections.open("user", &id)?;
+            transaction.user_id = Some(id);
+        }
+        SetSession => {
+            let id = expanded(transaction)?;
+            transaction.collections.open("session", &id)?;
+            transaction.session_id = Some(id);
+        }
+        SetResource => {
+            let id = expanded(transaction)?;
+            transaction.collections.open("resource", &id)?;
+            transaction.resource_id = Some(id);
+        }
+        SanitiseArg => {
+            let name = required(action)?.to_lowercase();
+            transaction.sanitise.args.insert(name);
+        }
+        SanitiseRequestHeader => {
+            let name = required(action)?.to_lowercase();
+            transaction.sanitise.request_headers.insert(name);
+        }
+        SanitiseResponseHeader => {
+            let name = required(action)?.to_lowercase();
+            transaction.sanitise.response_headers.insert(name);
+        }
+        SanitiseMatched => {
+            if let Some(name) = transaction.matched_var.as_ref().map(|m| m.name.clone()) {
+                transaction.sanitise.variables.insert(name);
+            }
+        }
+        SanitiseMatchedBytes => {
+            let keep = match action.argument() {
+                Some(arg) => {
+                    let (start, end) = arg.split_once('/').unwrap_or((arg, "0"));
+                    match (start.trim().parse(), end.trim().parse()) {
+                        (Ok(start), Ok(end)) => (start, end),
+                        _ => {
+                            return Err(invalid(
+                                action.action,
+                                format!("expected N/M, got {}", arg),
+                            ))
+                        }
+                    }
+                }
+                None => (0, 0),
+            };
+            if let Some(name) = transaction.matched_var.as_ref().map(|m| m.name.clone()) {
+                transaction.sanitise.matched_bytes.insert(name, keep);
+            }
+        }
+        Append => {
+            let content = expanded(transaction)?;
+            transaction.response_append.extend(content.into_bytes());
+        }
+        Prepend => {
+            let content = expanded(transaction)?;
+            transaction.response_prepend.extend(content.into_bytes());
+        }
+        XmlNs => {
+            let arg = required(action)?;
+            match split_assignment(arg) {
+                (prefix, Some(uri)) if !prefix.is_empty() => {
+                    let uri = uri.trim().trim_matches('"');
+                    transaction.xml_namespaces.insert(prefix.into(), uri.into());
+                }
+                _ => {
+                    return Err(invalid(
+                        action.action,
+                        format!("expected prefix=uri, got {}", arg),
+                    ))
+                }
+            }
+        }
+        Ctl => apply_ctl(action, transaction)?,
+        Exec => apply_exec(action, config)?,
+        InitCollection => apply_initcol(action, transaction)?,
+        ExpireVar | DeprecateVar => apply_expiry(action, transaction)?,
+        // handled while evaluating the rule, rather than after it matched
+        Capture | Chain | MultiMatch | Transform => {}
+        // only used when reporting the match
+        Accuracy | Id | Log | LogData | Maturity | Msg | NoAuditLog | NoLog | Phase | Revision
+        | Severity | Tag | Version => {}
+    }
+
+    Ok(Outcome::Continue)
+}
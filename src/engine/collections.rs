@@ -0,0 +1,690 @@
+//! Persistent collections (`IP`, `SESSION`, `GLOBAL`, `USER` and `RESOURCE`), which keep state
+//! across transactions, e.g. for DoS protection or counting failed logins.
+//!
+//! A collection has to be opened with `initcol` (or `setsid`, `setuid` and `setrsc`) before a
+//! transaction can use it, which selects the record to use, e.g. the record of the client's IP
+//! address. Records are kept in a [`CollectionStore`], which is shared between concurrent
+//! transactions, and every change is applied to the store atomically so that concurrent
+//! increments aren't lost. Stores that persist records write them once per transaction, when
+//! [`Collections::flush`] is called, and expired records are removed when collections are
+//! opened.
+
+use crate::syntax::atoi;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+/// The collections that can be opened with `initcol`.
+pub const PERSISTENT_COLLECTIONS: [&str; 5] = ["global", "ip", "resource", "session", "user"];
+
+/// How long records are kept after their last update, unless changed with
+/// `SecCollectionTimeout` or by setting the record's `TIMEOUT` variable.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to access {path}, {source}")]
+    IoError { path: String, source: io::Error },
+    #[error("invalid collection file {path}, {source}")]
+    InvalidFile {
+        path: String,
+        source: serde_json::Error,
+    },
+}
+
+/// A variable of a persistent collection.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredVar {
+    pub value: String,
+    /// When the variable was last set or deprecated, in seconds since the epoch.
+    pub updated: u64,
+    /// When the variable expires, as set by `expirevar`.
+    pub expires: Option<u64>,
+}
+
+/// A record of a persistent collection, e.g. the `IP` collection of one client.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    /// Creation time, in seconds since the epoch.
+    pub created: u64,
+    /// Time of the last update, in seconds since the epoch.
+    pub updated: u64,
+    pub update_counter: u64,
+    /// Number of seconds after the last update that the record expires.
+    pub timeout: u64,
+    /// Variables, keyed by lowercase name.
+    pub vars: BTreeMap<String, StoredVar>,
+}
+
+impl Record {
+    pub fn new(key: &str, now: u64, timeout: u64) -> Self {
+        Self {
+            key: key.into(),
+            created: now,
+            updated: now,
+            update_counter: 0,
+            timeout,
+            vars: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.updated.saturating_add(self.timeout)
+    }
+
+    /// Looks up a variable, including the built-in ones like `TIMEOUT`, ignoring expired
+    /// variables.
+    pub fn get(&self, name: &str, now: u64) -> Option<String> {
+        let name = name.to_lowercase();
+        let builtin = match name.as_str() {
+            "key" => Some(self.key.clone()),
+            "create_time" => Some(self.created.to_string()),
+            "last_update_time" => Some(self.updated.to_string()),
+            "update_counter" => Some(self.update_counter.to_string()),
+            "timeout" => Some(self.timeout.to_string()),
+            _ => None,
+        };
+        builtin.or_else(|| {
+            self.vars
+                .get(&name)
+                .filter(|var| var.expires.is_none_or(|expires| now < expires))
+                .map(|var| var.value.clone())
+        })
+    }
+
+    /// The variables that haven't expired, sorted by name.
+    pub fn vars(&self, now: u64) -> Vec<(String, String)> {
+        self.vars
+            .iter()
+            .filter(|(_, var)| var.expires.is_none_or(|expires| now < expires))
+            .map(|(name, var)| (name.clone(), var.value.clone()))
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: String, now: u64) {
+        let name = name.to_lowercase();
+        if name == "timeout" {
+            self.timeout = value.trim().parse().unwrap_or(self.timeout);
+            return;
+        }
+        self.vars.insert(
+            name,
+            StoredVar {
+                value,
+                updated: now,
+                expires: None,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.vars.remove(&name.to_lowercase());
+    }
+
+    /// Makes a variable expire after the given number of seconds.
+    pub fn expire(&mut self, name: &str, seconds: u64, now: u64) {
+        if let Some(var) = self.vars.get_mut(&name.to_lowercase()) {
+            var.expires = Some(now.saturating_add(seconds));
+        }
+    }
+
+    /// Decreases a numeric variable by `amount` for every `period` seconds since it was last
+    /// updated, without going below zero.
+    pub fn deprecate(&mut self, name: &str, amount: i64, period: u64, now: u64) {
+        let var = match self.vars.get_mut(&name.to_lowercase()) {
+            Some(var) => var,
+            None => return,
+        };
+        let periods = now.saturating_sub(var.updated) / period.max(1);
+        if periods == 0 {
+            return;
+        }
+        let value = atoi(var.value.as_bytes());
+        let decrease = amount.saturating_mul(periods.min(i64::MAX as u64) as i64);
+        var.value = value.saturating_sub(decrease).max(0).to_string();
+        var.updated = now;
+    }
+
+    /// Removes the variables that have expired.
+    fn remove_expired_vars(&mut self, now: u64) {
+        self.vars
+            .retain(|_, var| var.expires.is_none_or(|expires| now < expires));
+    }
+}
+
+/// Storage for the records of persistent collections. Implementations must be safe to share
+/// between concurrent transactions.
+pub trait CollectionStore: Debug + Send + Sync {
+    /// Gets a record, including a record that has expired.
+    fn get(&self, collection: &str, key: &str) -> Option<Record>;
+
+    /// Atomically updates a record, passing `None` to `update` if the record doesn't exist.
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        update: &mut dyn FnMut(Option<Record>) -> Record,
+    ) -> Result<(), Error>;
+
+    /// Removes every record that expired before `now`.
+    fn remove_expired(&self, now: u64) -> Result<(), Error>;
+
+    /// Writes the updates since the last flush to storage, if the store persists records.
+    /// Called once at the end of each transaction.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Records of every collection, keyed by collection name then record key.
+type Records = HashMap<String, HashMap<String, Record>>;
+
+fn lock(records: &Mutex<Records>) -> MutexGuard<'_, Records> {
+    // a panic while updating a record only loses that record, the others are still usable
+    records
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn update_records(
+    records: &mut Records,
+    collection: &str,
+    key: &str,
+    update: &mut dyn FnMut(Option<Record>) -> Record,
+) {
+    let records = records.entry(collection.into()).or_default();
+    let record = update(records.remove(key));
+    records.insert(key.into(), record);
+}
+
+/// Removes the records that have expired, returning true if there were any.
+fn remove_expired_records(records: &mut Records, now: u64) -> bool {
+    let mut removed = false;
+    for records in records.values_mut() {
+        let count = records.len();
+        records.retain(|_, record| !record.is_expired(now));
+        removed |= records.len() != count;
+    }
+    records.retain(|_, records| !records.is_empty());
+    removed
+}
+
+/// Keeps records in memory, so they only last as long as the process.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CollectionStore for MemoryStore {
+    fn get(&self, collection: &str, key: &str) -> Option<Record> {
+        lock(&self.records).get(collection)?.get(key).cloned()
+    }
+
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        update: &mut dyn FnMut(Option<Record>) -> Record,
+    ) -> Result<(), Error> {
+        update_records(&mut lock(&self.records), collection, key, update);
+        Ok(())
+    }
+
+    fn remove_expired(&self, now: u64) -> Result<(), Error> {
+        remove_expired_records(&mut lock(&self.records), now);
+        Ok(())
+    }
+}
+
+/// Keeps records in a JSON file, which is rewritten when the store is flushed after records
+/// changed, and when the store is dropped. The file is read once when the store is opened, so
+/// it mustn't be shared by several processes at the same time.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    records: Mutex<Records>,
+    /// Whether the records changed since they were last written.
+    dirty: AtomicBool,
+}
+
+impl FileStore {
+    /// Opens a store, reading the records from `path` if it exists.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let records = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|source| Error::InvalidFile {
+                path: path.display().to_string(),
+                source,
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(source) => {
+                return Err(Error::IoError {
+                    path: path.display().to_string(),
+                    source,
+                })
+            }
+        };
+        Ok(Self {
+            path,
+            records: Mutex::new(records),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the records to a temporary file that then replaces the store's file, so the file
+    /// is never left partially written.
+    fn save(&self, records: &Records) -> Result<(), Error> {
+        let io_error = |source| Error::IoError {
+            path: self.path.display().to_string(),
+            source,
+        };
+        let data = serde_json::to_vec(records).map_err(|source| Error::InvalidFile {
+            path: self.path.display().to_string(),
+            source,
+        })?;
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, data).map_err(io_error)?;
+        fs::rename(&temp, &self.path).map_err(io_error)
+    }
+}
+
+impl CollectionStore for FileStore {
+    fn get(&self, collection: &str, key: &str) -> Option<Record> {
+        lock(&self.records).get(collection)?.get(key).cloned()
+    }
+
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        update: &mut dyn FnMut(Option<Record>) -> Record,
+    ) -> Result<(), Error> {
+        update_records(&mut lock(&self.records), collection, key, update);
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn remove_expired(&self, now: u64) -> Result<(), Error> {
+        if remove_expired_records(&mut lock(&self.records), now) {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        // the lock is held while writing, so updates made meanwhile are flagged for the next
+        // flush rather than lost
+        let records = lock(&self.records);
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            if let Err(error) = self.save(&records) {
+                self.dirty.store(true, Ordering::Release);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        // there's no one left to report the error to
+        let _ = self.flush();
+    }
+}
+
+/// The current time in seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The persistent collections a transaction has opened.
+#[derive(Debug, Clone)]
+pub struct Collections {
+    store: Arc<dyn CollectionStore>,
+    /// The timeout of new records, like `SecCollectionTimeout`.
+    pub timeout: Duration,
+    /// The record key each collection was opened with, by lowercase collection name.
+    keys: HashMap<String, String>,
+    /// When expired records were last removed from the store, shared by the transactions
+    /// using the same collections so the store is swept at most once a second.
+    last_sweep: Arc<AtomicU64>,
+}
+
+/// Uses a store that isn't shared with any other transaction.
+impl Default for Collections {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStore::new()), DEFAULT_TIMEOUT)
+    }
+}
+
+impl Collections {
+    pub fn new(store: Arc<dyn CollectionStore>, timeout: Duration) -> Self {
+        Self {
+            store,
+            timeout,
+            keys: Default::default(),
+            last_sweep: Default::default(),
+        }
+    }
+
+    /// Returns true if the collection has been opened by this transaction.
+    pub fn is_open(&self, collection: &str) -> bool {
+        self.keys.contains_key(&collection.to_lowercase())
+    }
+
+    /// Opens a collection, using the record with the given key. The record is created (or
+    /// replaced, if it expired) when the collection is first updated. Expired records of every
+    /// collection are removed from the store first.
+    pub fn open(&mut self, collection: &str, key: &str) -> Result<(), Error> {
+        let now = now();
+        if self.last_sweep.swap(now, Ordering::AcqRel) != now {
+            self.store.remove_expired(now)?;
+        }
+        self.keys.insert(collection.to_lowercase(), key.into());
+        Ok(())
+    }
+
+    /// Writes the changes made by the transaction to the store's storage.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
+    /// The record the collection was opened with, unless it doesn't exist or has expired.
+    fn record(&self, collection: &str, now: u64) -> Option<Record> {
+        let collection = collection.to_lowercase();
+        let key = self.keys.get(&collection)?;
+        self.store
+            .get(&collection, key)
+            .filter(|record| !record.is_expired(now))
+    }
+
+    /// Looks up a variable of an open collection.
+    pub fn get(&self, collection: &str, name: &str) -> Option<String> {
+        let now = now();
+        self.record(collection, now)?.get(name, now)
+    }
+
+    /// The variables of an open collection, sorted by name.
+    pub fn vars(&self, collection: &str) -> Vec<(String, String)> {
+        let now = now();
+        self.record(collection, now)
+            .map(|record| record.vars(now))
+            .unwrap_or_default()
+    }
+
+    /// Atomically updates the record of an open collection, creating it if it doesn't exist or
+    /// has expired. Does nothing if the collection isn't open.
+    pub fn update(
+        &self,
+        collection: &str,
+        mut update: impl FnMut(&mut Record, u64),
+    ) -> Result<(), Error> {
+        let collection = collection.to_lowercase();
+        let key = match self.keys.get(&collection) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let now = now();
+        let timeout = self.timeout.as_secs();
+        self.store.update(&collection, key, &mut |record| {
+            let mut record = match record {
+                Some(record) if !record.is_expired(now) => record,
+                _ => Record::new(key, now, timeout),
+            };
+            record.remove_expired_vars(now);
+            update(&mut record, now);
+            record.updated = now;
+            record.update_counter += 1;
+            record
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::rule::EngineConfig;
+    use crate::engine::ruleset::RuleSet;
+    use crate::engine::transaction::Transaction;
+    use crate::syntax::parse_entries;
+    use std::thread;
+
+    /// A path in the temporary directory that's unique to the test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("test-crs-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn set(store: &dyn CollectionStore, collection: &str, record: Record) {
+        let key = record.key.clone();
+        store
+            .update(collection, &key, &mut |_| record.clone())
+            .unwrap();
+    }
+
+    #[test]
+    fn looks_up_variables_and_builtins() {
+        let mut record = Record::new("1.2.3.4", 100, 60);
+        record.set("Hits", "3".into(), 100);
+        assert_eq!(record.get("hits", 100).as_deref(), Some("3"));
+        assert_eq!(record.get("KEY", 100).as_deref(), Some("1.2.3.4"));
+        assert_eq!(record.get("create_time", 100).as_deref(), Some("100"));
+        assert_eq!(record.get("missing", 100), None);
+
+        // setting TIMEOUT changes the record's timeout rather than adding a variable
+        record.set("TIMEOUT", "600".into(), 100);
+        assert_eq!(record.timeout, 600);
+        assert_eq!(record.vars(100), [("hits".to_string(), "3".to_string())]);
+    }
+
+    #[test]
+    fn expires_records_after_their_timeout() {
+        let record = Record::new("k", 100, 60);
+        assert!(!record.is_expired(159));
+        assert!(record.is_expired(160));
+    }
+
+    #[test]
+    fn expires_variables() {
+        let mut record = Record::new("k", 100, 3600);
+        record.set("blocked", "1".into(), 100);
+        record.set("hits", "1".into(), 100);
+        record.expire("blocked", 60, 100);
+        assert_eq!(record.get("blocked", 159).as_deref(), Some("1"));
+        assert_eq!(record.get("blocked", 160), None);
+        assert_eq!(record.vars(160), [("hits".to_string(), "1".to_string())]);
+
+        record.remove_expired_vars(160);
+        assert!(!record.vars.contains_key("blocked"));
+    }
+
+    #[test]
+    fn deprecates_variables() {
+        let mut record = Record::new("k", 0, 3600);
+        record.set("score", "100".into(), 0);
+        record.deprecate("score", 30, 60, 59);
+        assert_eq!(record.get("score", 59).as_deref(), Some("100"));
+        record.deprecate("score", 30, 60, 120);
+        assert_eq!(record.get("score", 120).as_deref(), Some("40"));
+        record.deprecate("score", 30, 60, 300);
+        assert_eq!(record.get("score", 300).as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn removes_expired_records() {
+        let store = MemoryStore::new();
+        set(&store, "ip", Record::new("old", 0, 60));
+        set(&store, "ip", Record::new("new", 100, 60));
+        set(&store, "global", Record::new("global", 0, 60));
+        store.remove_expired(100).unwrap();
+        assert_eq!(store.get("ip", "old"), None);
+        assert!(store.get("ip", "new").is_some());
+        assert!(lock(&store.records).get("global").is_none());
+    }
+
+    #[test]
+    fn removes_expired_records_when_a_collection_is_opened() {
+        let store = Arc::new(MemoryStore::new());
+        set(&*store, "ip", Record::new("expired", 0, 60));
+        let mut collections = Collections::new(store.clone(), DEFAULT_TIMEOUT);
+        collections.open("IP", "1.2.3.4").unwrap();
+        assert!(collections.is_open("ip"));
+        assert_eq!(store.get("ip", "expired"), None);
+    }
+
+    #[test]
+    fn replaces_expired_records_when_updated() {
+        let store = Arc::new(MemoryStore::new());
+        let mut old = Record::new("1.2.3.4", 0, 60);
+        old.set("hits", "10".into(), 0);
+        set(&*store, "ip", old);
+
+        let mut collections = Collections::new(store.clone(), Duration::from_secs(300));
+        collections.keys.insert("ip".into(), "1.2.3.4".into());
+        assert_eq!(collections.get("ip", "hits"), None);
+        collections
+            .update("ip", |record, now| record.set("hits", "1".into(), now))
+            .unwrap();
+        let record = store.get("ip", "1.2.3.4").unwrap();
+        assert_eq!(record.get("hits", now()).as_deref(), Some("1"));
+        assert_eq!(record.timeout, 300);
+        assert_eq!(record.update_counter, 1);
+    }
+
+    #[test]
+    fn ignores_updates_of_collections_that_arent_open() {
+        let store = Arc::new(MemoryStore::new());
+        let collections = Collections::new(store.clone(), DEFAULT_TIMEOUT);
+        collections
+            .update("ip", |record, now| record.set("hits", "1".into(), now))
+            .unwrap();
+        assert_eq!(store.get("ip", ""), None);
+    }
+
+    #[test]
+    fn keeps_concurrent_increments() {
+        let store = Arc::new(MemoryStore::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let mut collections = Collections::new(store.clone(), DEFAULT_TIMEOUT);
+                thread::spawn(move || {
+                    collections.open("ip", "1.2.3.4").unwrap();
+                    for _ in 0..100 {
+                        collections
+                            .update("ip", |record, now| {
+                                let hits =
+                                    atoi(record.get("hits", now).unwrap_or_default().as_bytes());
+                                record.set("hits", (hits + 1).to_string(), now);
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let record = store.get("ip", "1.2.3.4").unwrap();
+        assert_eq!(record.get("hits", now()).as_deref(), Some("800"));
+        assert_eq!(record.update_counter, 800);
+    }
+
+    #[test]
+    fn writes_file_stores_when_flushed() {
+        let path = temp_path("flush");
+        let store = FileStore::open(&path).unwrap();
+        set(&store, "ip", Record::new("1.2.3.4", now(), 60));
+        // updates are only written when the store is flushed
+        assert!(!path.exists());
+        store.flush().unwrap();
+        let written = fs::read(&path).unwrap();
+
+        // flushing without changes doesn't write the file again
+        fs::remove_file(&path).unwrap();
+        store.flush().unwrap();
+        assert!(!path.exists());
+        fs::write(&path, written).unwrap();
+
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("ip", "1.2.3.4"), reopened.get("ip", "1.2.3.4"));
+        drop(reopened);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_file_stores_when_dropped() {
+        let path = temp_path("drop");
+        let store = FileStore::open(&path).unwrap();
+        set(&store, "global", Record::new("global", now(), 60));
+        drop(store);
+        let reopened = FileStore::open(&path).unwrap();
+        assert!(reopened.get("global", "global").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let path = temp_path("invalid");
+        fs::write(&path, "not json").unwrap();
+        assert!(matches!(
+            FileStore::open(&path),
+            Err(Error::InvalidFile { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rules_use_persistent_collections() {
+        let entries = parse_entries(
+            r#"SecCollectionTimeout 600
+SecAction "id:1,phase:1,nolog,pass,initcol:ip=1.2.3.4,setvar:ip.hits=+1,setvar:ip.blocked=1,expirevar:ip.blocked=60"
+SecRule IP:HITS "@ge 2" "id:2,phase:1,deny,log"
+"#,
+        )
+        .unwrap();
+        let report = RuleSet::compile(&entries, &EngineConfig::default());
+        assert!(report.is_ok(), "{:?}", report.errors);
+        let rules = report.rules;
+        assert_eq!(rules.collection_timeout, Some(Duration::from_secs(600)));
+
+        let path = temp_path("rules");
+        let store = Arc::new(FileStore::open(&path).unwrap());
+        let collections = Collections::new(store.clone(), DEFAULT_TIMEOUT);
+        let request = http::Request::get("/").body(vec![]).unwrap();
+
+        let mut transaction = Transaction::with_collections(collections.clone());
+        let verdict = rules.process(&request, &mut transaction);
+        assert!(verdict.errors.is_empty(), "{:?}", verdict.errors);
+        assert!(verdict.interruption.is_none());
+        // the transaction's updates were written when it ended
+        assert!(path.exists());
+
+        let mut transaction = Transaction::with_collections(collections);
+        let verdict = rules.process(&request, &mut transaction);
+        assert_eq!(verdict.matched_ids().collect::<Vec<_>>(), [1, 2]);
+
+        let record = store.get("ip", "1.2.3.4").unwrap();
+        assert_eq!(record.timeout, 600);
+        let blocked = &record.vars["blocked"];
+        assert_eq!(blocked.expires, Some(blocked.updated + 60));
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! Variables that aren't read directly from the request, but computed from the request and the
+//! state of the transaction, e.g. `ARGS_COMBINED_SIZE` or `TIME_HOUR`.
+
+use super::transaction::Transaction;
+use super::{get_value_from_source, RequestExt, SourceType};
+use crate::syntax::InputType;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use http::Request;
+
+/// Values that only depend on the transaction, so they're also available when expanding
+/// macros. Like ModSecurity, the `TIME` variables are in the server's local time zone.
+pub fn transaction_value(transaction: &Transaction, input: InputType) -> Option<String> {
+    use InputType::*;
+
+    let time: DateTime<Local> = transaction.started_at.into();
+
+    Some(match input {
+        Duration => transaction.duration().as_millis().to_string(),
+        UniqueId => transaction.unique_id.clone(),
+        Time => time.format("%H:%M:%S").to_string(),
+        TimeDay => time.day().to_string(),
+        TimeEpoch => time.timestamp().to_string(),
+        TimeHour => time.hour().to_string(),
+        TimeMin => time.minute().to_string(),
+        TimeMon => time.month0().to_string(),
+        TimeSec => time.second().to_string(),
+        TimeWday => time.weekday().num_days_from_sunday().to_string(),
+        TimeYear => time.year().to_string(),
+        _ => return None,
+    })
+}
+
+/// The name of the body processor that applies to the request, based on its content type unless
+/// it was forced with `ctl:requestBodyProcessor`.
+fn request_body_processor(request: &Request<Vec<u8>>, transaction: &Transaction) -> Option<String> {
+    if let Some(processor) = &transaction.config.request_body_processor {
+        return Some(processor.to_uppercase());
+    }
+    let mime = request.mime_type()?.ok()?;
+    let processor = match (mime.type_(), mime.subtype()) {
+        (mime::APPLICATION, mime::WWW_FORM_URLENCODED) => "URLENCODED",
+        (mime::MULTIPART, mime::FORM_DATA) => "MULTIPART",
+        (_, mime::XML) => "XML",
+        (_, mime::JSON) => "JSON",
+        (_, subtype) if subtype.as_str().ends_with("+xml") => "XML",
+        (_, subtype) if subtype.as_str().ends_with("+json") => "JSON",
+        _ => return None,
+    };
+    Some(processor.into())
+}
+
+/// Size of the request as it was sent: the request line, headers and body.
+fn full_request_length(request: &Request<Vec<u8>>) -> usize {
+    let request_line = request.method().as_str().len()
+        + 1
+        + request.uri().to_string().len()
+        + 1
+        + format!("{:?}", request.version()).len()
+        + 2;
+    let headers: usize = request
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + 2 + value.len() + 2)
+        .sum();
+    request_line + headers + 2 + request.body().len()
+}
+
+/// Computes the value of a variable that's derived from the request and transaction, returning
+/// `None` if the input isn't a computed variable.
+pub fn computed_value(
+    request: &Request<Vec<u8>>,
+    transaction: &Transaction,
+    input: InputType,
+) -> Option<String> {
+    use InputType::*;
+
+    Some(match input {
+        ArgsCombinedSize => {
+            let sources = [
+                SourceType::QueryArg,
+                SourceType::PostArg,
+                SourceType::JsonArg,
+            ];
+            sources
+                .into_iter()
+                .flat_map(|source| get_value_from_source(request, source))
+                .map(|arg| arg.name().map_or(0, <[u8]>::len) + arg.value().len())
+                .sum::<usize>()
+                .to_string()
+        }
+        RequestBodyLength => request.body().len().to_string(),
+        FullRequestLength => full_request_length(request).to_string(),
+        ReqBodyProcessor => request_body_processor(request, transaction)?,
+        // without a multipart body there are no uploaded files; multipart bodies aren't parsed,
+        // so their size is unknown
+        FilesCombinedSize => match request_body_processor(request, transaction) {
+            Some(processor) if processor == "MULTIPART" => return None,
+            _ => "0".into(),
+        },
+        input => return transaction_value(transaction, input),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content_type: &str, body: &str) -> Request<Vec<u8>> {
+        Request::post("/upload")
+            .header("Content-Type", content_type)
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn files_combined_size() {
+        let transaction = Transaction::new();
+        let urlencoded = request("application/x-www-form-urlencoded", "a=1&b=2");
+        assert_eq!(
+            computed_value(&urlencoded, &transaction, InputType::FilesCombinedSize),
+            Some("0".into())
+        );
+        let get = Request::get("/").body(vec![]).unwrap();
+        assert_eq!(
+            computed_value(&get, &transaction, InputType::FilesCombinedSize),
+            Some("0".into())
+        );
+        let multipart = request("multipart/form-data; boundary=x", "--x--\r\n");
+        assert_eq!(
+            computed_value(&multipart, &transaction, InputType::FilesCombinedSize),
+            None
+        );
+    }
+}
@@ -7,13 +7,22 @@ use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 use std::str::Utf8Error;
 
+pub mod actions;
+pub mod collections;
+pub mod computed;
 pub mod content_type;
 pub mod cookies;
+pub mod operators;
+pub mod rule;
+pub mod ruleset;
+pub mod transaction;
 pub mod transforms;
 pub mod value;
+pub mod xml;
 
-use crate::syntax::{Input, InputType};
+use crate::syntax::{Input, InputType, Selector};
 use content_type::www_form_urlencoded;
+use transaction::Transaction;
 use value::Value;
 
 macro_rules! sources {
@@ -47,18 +56,28 @@ macro_rules! sources {
 sources! {
     pub enum SourceType {
         Body,
+        /// A value computed from the request and transaction, e.g. `ARGS_COMBINED_SIZE`.
+        Computed,
         Cookie,
         CookieName,
+        /// The number of values selected by a `&` count selector, rather than a request value.
+        Count,
         Header,
         HeaderName,
         JsonArg,
         JsonArgName,
+        /// Variables recorded by earlier matches in the transaction, e.g. `MATCHED_VARS`.
+        Matched,
         Method,
+        /// Variables of persistent collections, e.g. `IP` or `SESSION`.
+        Persistent,
         PostArg,
         PostArgName,
         Protocol,
         QueryArg,
         QueryArgName,
+        /// Variables in the transaction's TX collection.
+        Tx,
         UriFull,
         UriPath,
         UriPathAndQuery,
@@ -70,7 +89,7 @@ sources! {
 }
 
 impl SourceType {
-    fn from_modsec_input(input: Input) -> Option<&'static [Self]> {
+    fn from_modsec_input(input: &Input) -> Option<&'static [Self]> {
         match input.input {
             InputType::ArgsGet => Some(&[Self::QueryArg]),
             InputType::ArgsGetNames => Some(&[Self::QueryArgName]),
@@ -90,8 +109,21 @@ impl SourceType {
             InputType::RequestProtocol => Some(&[Self::Protocol]),
             InputType::RequestUri => Some(&[Self::UriPathAndQuery]),
             InputType::RequestUriRaw => Some(&[Self::UriFull]),
+            InputType::Tx => Some(&[Self::Tx]),
+            InputType::Global
+            | InputType::Ip
+            | InputType::Resource
+            | InputType::Session
+            | InputType::User => Some(&[Self::Persistent]),
+            InputType::MatchedVar
+            | InputType::MatchedVarName
+            | InputType::MatchedVars
+            | InputType::MatchedVarsNames => Some(&[Self::Matched]),
+            // multipart bodies aren't parsed, so the uploaded files are unknown. Outside
+            // multipart bodies, FILES_COMBINED_SIZE is computed as 0
             InputType::FilesNames => None,
             InputType::Files => None,
+            InputType::FilesCombinedSize => None,
             InputType::MultipartPartHeaders => None,
             InputType::RemoteAddr => None,
             InputType::RequestBasename => None,
@@ -134,6 +166,20 @@ impl<T> RequestExt for Request<T> {
     }
 }
 
+/// Request headers that `http` can't represent, e.g. a header name with a space or a value with
+/// control characters, as their raw name and value. Parsers of raw requests attach them to the
+/// request as an extension, so that the rules can still inspect them.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RawHeaders(pub Vec<(Vec<u8>, Vec<u8>)>);
+
+fn raw_headers<T>(request: &Request<T>) -> impl Iterator<Item = &(Vec<u8>, Vec<u8>)> {
+    request
+        .extensions()
+        .get::<RawHeaders>()
+        .into_iter()
+        .flat_map(|raw| &raw.0)
+}
+
 pub fn get_value_from_source(request: &Request<Vec<u8>>, source: SourceType) -> Vec<Value> {
     use SourceType::*;
     // 2.1. Percent-Encoding: https://datatracker.ietf.org/doc/html/rfc3986#section-2.1
@@ -204,6 +250,7 @@ pub fn get_value_from_source(request: &Request<Vec<u8>>, source: SourceType) ->
             .map(|(name, value)| {
                 Value::new_named(Header, name.as_str().as_bytes(), value.as_bytes())
             })
+            .chain(raw_headers(request).map(|(name, value)| Value::new_named(Header, name, value)))
             .collect(),
 
         // Header Names
@@ -211,6 +258,7 @@ pub fn get_value_from_source(request: &Request<Vec<u8>>, source: SourceType) ->
             .headers()
             .iter()
             .map(|(name, _)| Value::from_str(HeaderName, name.as_str()))
+            .chain(raw_headers(request).map(|(name, _)| Value::new(HeaderName, name)))
             .collect(),
 
         // Cookie Values
@@ -298,5 +346,198 @@ pub fn get_value_from_source(request: &Request<Vec<u8>>, source: SourceType) ->
         Protocol => Default::default(),
 
         Body => vec![Value::new(Body, request.body())],
+
+        // Not part of the request
+        Computed | Count | Matched | Persistent | Tx => Default::default(),
+    }
+}
+
+/// The part of a selector that picks values by name, which is either a name (compared
+/// case-insensitively) or a regex between slashes, e.g. `/^arg_[0-9]+$/`.
+#[derive(Debug, Clone)]
+enum NameSelector {
+    Name(String),
+    Regex(regex::bytes::Regex),
+}
+
+impl NameSelector {
+    fn compile(selector: &str) -> Result<Self, regex::Error> {
+        Ok(
+            match selector.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+                Some(pattern) => {
+                    Self::Regex(crate::syntax::compile_regex(&format!("(?i){}", pattern))?)
+                }
+                None => Self::Name(selector.into()),
+            },
+        )
+    }
+
+    fn is_selected(&self, name: Option<&[u8]>) -> bool {
+        match (self, name) {
+            (_, None) => false,
+            (Self::Name(selector), Some(name)) => name.eq_ignore_ascii_case(selector.as_bytes()),
+            (Self::Regex(regex), Some(name)) => regex.is_match(name),
+        }
+    }
+}
+
+/// A rule input whose selector has been compiled, so that regex selectors are only compiled once
+/// when the rules are loaded, rather than for every value they're checked against.
+#[derive(Debug, Clone)]
+pub struct CompiledInput {
+    pub input: Input,
+    name: Option<NameSelector>,
+}
+
+impl CompiledInput {
+    pub fn compile(input: &Input) -> Result<Self, regex::Error> {
+        let name = match &input.selector {
+            Selector::Include(selector)
+            | Selector::Exclude(selector)
+            | Selector::Count(selector) => Some(NameSelector::compile(selector)?),
+            Selector::None | Selector::CountAll => None,
+        };
+        Ok(Self {
+            input: input.clone(),
+            name,
+        })
+    }
+
+    fn is_selected(&self, value: &Value) -> bool {
+        self.name
+            .as_ref()
+            .is_some_and(|selector| selector.is_selected(value.name()))
+    }
+}
+
+fn get_collection_values<'a>(
+    request: &'a Request<Vec<u8>>,
+    transaction: &'a Transaction,
+    input: &Input,
+) -> Vec<Value<'a>> {
+    if let Some(value) = computed::computed_value(request, transaction, input.input) {
+        return vec![Value::owned(SourceType::Computed, value)];
+    }
+
+    let sources = SourceType::from_modsec_input(input).unwrap_or_default();
+    let mut values = vec![];
+    for &source in sources {
+        match source {
+            SourceType::Tx => {
+                let mut vars: Vec<_> = transaction.tx.iter().collect();
+                vars.sort();
+                values.extend(vars.into_iter().map(|(name, value)| {
+                    Value::new_named(SourceType::Tx, name.as_bytes(), value.as_bytes())
+                }));
+            }
+            SourceType::Persistent => {
+                let vars = transaction.collections.vars(input.input.name());
+                values.extend(
+                    vars.into_iter().map(|(name, value)| {
+                        Value::owned_named(SourceType::Persistent, name, value)
+                    }),
+                );
+            }
+            SourceType::Matched => values.extend(matched_values(transaction, input.input)),
+            source => values.extend(get_value_from_source(request, source)),
+        }
     }
+    values
+}
+
+/// Gets the values of the `MATCHED_VAR` family of variables. `MATCHED_VARS` is named by the full
+/// name of each variable, so it can be filtered with selectors like `MATCHED_VARS:ARGS:foo`.
+fn matched_values(transaction: &Transaction, input: InputType) -> Vec<Value<'_>> {
+    let source = SourceType::Matched;
+    let last = transaction.matched_var.iter();
+    match input {
+        InputType::MatchedVar => last.map(|m| Value::new(source, &m.value)).collect(),
+        InputType::MatchedVarName => last.map(|m| Value::from_str(source, &m.name)).collect(),
+        InputType::MatchedVars => transaction
+            .matched_vars
+            .iter()
+            .map(|m| Value::new_named(source, m.name.as_bytes(), &m.value))
+            .collect(),
+        InputType::MatchedVarsNames => transaction
+            .matched_vars
+            .iter()
+            .map(|m| Value::from_str(source, &m.name))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The full name of a variable as ModSecurity reports it in `MATCHED_VAR_NAME`, e.g.
+/// `ARGS:foo`, `ARGS_NAMES:foo` or `REQUEST_URI`.
+pub fn full_name(input: InputType, value: &Value) -> String {
+    use SourceType::*;
+
+    let key = match value.source() {
+        // the names of a collection are keyed by themselves
+        CookieName | HeaderName | JsonArgName | PostArgName | QueryArgName | XmlPropName => {
+            Some(value.value())
+        }
+        _ if input == InputType::MatchedVarsNames => Some(value.value()),
+        _ => value.name(),
+    };
+    match key {
+        Some(key) => format!("{}:{}", input.name(), String::from_utf8_lossy(key)),
+        None => input.name().to_string(),
+    }
+}
+
+/// Gets the values of a single rule input, applying its selector.
+///
+/// Count selectors (`&ARGS`, `&REQUEST_HEADERS:Host`) produce a single value holding the number
+/// of values that would have been selected, while exclusions (`!ARGS:foo`) don't produce any
+/// values on their own, see [`get_target_values`].
+pub fn get_input_values<'a>(
+    request: &'a Request<Vec<u8>>,
+    transaction: &'a Transaction,
+    input: &CompiledInput,
+) -> Vec<Value<'a>> {
+    let values = get_collection_values(request, transaction, &input.input);
+    match &input.input.selector {
+        Selector::None => values,
+        Selector::Include(_) => values
+            .into_iter()
+            .filter(|value| input.is_selected(value))
+            .collect(),
+        Selector::Exclude(_) => vec![],
+        Selector::Count(_) => {
+            let count = values
+                .iter()
+                .filter(|value| input.is_selected(value))
+                .count();
+            vec![Value::owned(SourceType::Count, count.to_string())]
+        }
+        Selector::CountAll => vec![Value::owned(SourceType::Count, values.len().to_string())],
+    }
+}
+
+/// Gets the values of all inputs of a rule, e.g. `ARGS|REQUEST_HEADERS|!ARGS:foo`, along with
+/// the input each value was read from. Exclusions apply to every input of the same type,
+/// regardless of where they appear in the list.
+pub fn get_target_values<'a>(
+    request: &'a Request<Vec<u8>>,
+    transaction: &'a Transaction,
+    inputs: &[CompiledInput],
+) -> Vec<(InputType, Value<'a>)> {
+    let excluded = |input: &CompiledInput, value: &Value| {
+        inputs.iter().any(|exclusion| {
+            matches!(exclusion.input.selector, Selector::Exclude(_))
+                && exclusion.input.input == input.input.input
+                && exclusion.is_selected(value)
+        })
+    };
+
+    inputs
+        .iter()
+        .flat_map(|input| {
+            get_input_values(request, transaction, input)
+                .into_iter()
+                .filter(move |value| !excluded(input, value))
+                .map(move |value| (input.input.input, value))
+        })
+        .collect()
 }
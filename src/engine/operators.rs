@@ -0,0 +1,449 @@
+//! Evaluation of rule operators against variable values.
+//!
+//! Operators are compiled once, when the rules are loaded, so that regexes are built, phrase and
+//! IP lists are read from disk and XML schemas are parsed up front. Evaluating a compiled
+//! operator only needs the (transformed) value and a way to expand macros.
+
+use super::xml::{self, XmlValidator};
+use crate::syntax::{
+    atoi, compile_regex, Cidr, MacroString, Operator, OperatorArgument, OperatorType, Span,
+};
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{fs, io};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("operator @{0} is not supported")]
+    Unsupported(&'static str),
+    #[error("failed to read {path}, {source}")]
+    IoError { path: String, source: io::Error },
+    #[error("invalid entry in {path}, {reason}")]
+    InvalidFile { path: String, reason: String },
+    #[error(transparent)]
+    RegexError(#[from] regex::Error),
+    #[error(transparent)]
+    XmlError(#[from] xml::Error),
+    #[error("{0} is not in the @inspectFile allowlist")]
+    NotAllowed(String),
+    #[error("failed to run {path}, {source}")]
+    InspectFileError { path: String, source: io::Error },
+    #[error(transparent)]
+    PhraseListError(#[from] aho_corasick::BuildError),
+}
+
+/// Settings that restrict what operators are allowed to do.
+#[derive(Debug, Clone, Default)]
+pub struct OperatorConfig {
+    /// Scripts that `@inspectFile` is allowed to run. Rules referring to any other script fail to
+    /// compile, so by default `@inspectFile` can't be used at all.
+    pub inspect_file_allowlist: Vec<PathBuf>,
+}
+
+/// Looks up the values of macros such as `%{tx.anomaly_score}` when an operator argument is
+/// expanded.
+pub trait MacroResolver {
+    fn resolve(&self, collection: &str, key: Option<&str>) -> Option<String>;
+}
+
+/// Resolves every macro to an empty string.
+impl MacroResolver for () {
+    fn resolve(&self, _collection: &str, _key: Option<&str>) -> Option<String> {
+        None
+    }
+}
+
+impl<F> MacroResolver for F
+where
+    F: Fn(&str, Option<&str>) -> Option<String>,
+{
+    fn resolve(&self, collection: &str, key: Option<&str>) -> Option<String> {
+        self(collection, key)
+    }
+}
+
+/// The result of an operator that matched.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct OperatorMatch {
+    /// The data that matched, followed by any regex groups. Used by the `capture` action.
+    pub captures: Vec<Vec<u8>>,
+    /// The value after substitution, for `@rsub`.
+    pub replaced: Option<Vec<u8>>,
+}
+
+impl OperatorMatch {
+    fn capture(data: &[u8]) -> Self {
+        Self {
+            captures: vec![data.to_vec()],
+            replaced: None,
+        }
+    }
+}
+
+/// Single pattern search using the Boyer-Moore-Horspool algorithm.
+#[derive(Debug, Clone)]
+struct Horspool {
+    pattern: Vec<u8>,
+    shift: [usize; 256],
+}
+
+impl Horspool {
+    fn new(pattern: &[u8]) -> Self {
+        let len = pattern.len();
+        let mut shift = [len.max(1); 256];
+        for (i, &b) in pattern.iter().enumerate().take(len.saturating_sub(1)) {
+            shift[b as usize] = len - 1 - i;
+        }
+        Self {
+            pattern: pattern.to_vec(),
+            shift,
+        }
+    }
+
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let len = self.pattern.len();
+        let mut i = 0;
+        while i + len <= haystack.len() {
+            if haystack[i..i + len] == self.pattern[..] {
+                return Some(i);
+            }
+            i += self.shift[haystack[i + len - 1] as usize];
+        }
+        None
+    }
+}
+
+/// Checks a number using the Luhn algorithm, ignoring any non-digit characters.
+fn luhn(number: &[u8]) -> bool {
+    let digits: Vec<u32> = number
+        .iter()
+        .filter(|b| b.is_ascii_digit())
+        .map(|b| (b - b'0') as u32)
+        .collect();
+    if digits.is_empty() {
+        return false;
+    }
+
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match i % 2 {
+            0 => d,
+            _ if d > 4 => d * 2 - 9,
+            _ => d * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Returns true if every `%` in the input is followed by two hexadecimal digits.
+fn is_valid_url_encoding(input: &[u8]) -> bool {
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'%' {
+            match input.get(i + 1..i + 3) {
+                Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => i += 3,
+                _ => return false,
+            }
+        } else {
+            i += 1;
+        }
+    }
+    true
+}
+
+/// Files referred to by operators and actions are relative to the config file the rule is defined
+/// in.
+pub(crate) fn resolve_path(path: &Path, span: &Span) -> PathBuf {
+    match span.path().and_then(Path::parent) {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Reads the non-empty, non-comment lines of a data file, trimmed.
+fn read_lines(path: &Path) -> Result<Vec<String>, Error> {
+    let content = fs::read_to_string(path).map_err(|source| Error::IoError {
+        path: path.display().to_string(),
+        source,
+    })?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Into::into)
+        .collect())
+}
+
+fn phrase_matcher<I, P>(phrases: I) -> Result<AhoCorasick, Error>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<[u8]>,
+{
+    AhoCorasickBuilder::new()
+        .ascii_case_insensitive(true)
+        .build(phrases)
+        .map_err(Into::into)
+}
+
+#[derive(Debug, Clone)]
+enum Numeric {
+    Number(i64),
+    Macro(MacroString),
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Compares the input against an expanded macro string.
+    String(MacroString),
+    StringMatch(Box<Horspool>),
+    Regex(regex::bytes::Regex),
+    /// A regex containing macros, which has to be compiled on every evaluation.
+    DynamicRegex(MacroString),
+    Phrases(Box<AhoCorasick>),
+    IpMatch(Vec<Cidr>),
+    ByteRange(Box<[bool; 256]>),
+    Numeric(Numeric),
+    VerifyCC(regex::bytes::Regex),
+    Substitute(regex::bytes::Regex, Vec<u8>),
+    Xml(Box<XmlValidator>),
+    InspectFile(PathBuf),
+    Always(bool),
+    UrlEncoding,
+    Utf8Encoding,
+}
+
+/// An operator that's ready to be evaluated.
+#[derive(Debug, Clone)]
+pub struct CompiledOperator {
+    op: OperatorType,
+    matcher: Matcher,
+}
+
+impl CompiledOperator {
+    pub fn compile(operator: &Operator, config: &OperatorConfig) -> Result<Self, Error> {
+        use OperatorType::*;
+
+        let op = operator.op;
+        let macro_string = || match &operator.argument {
+            OperatorArgument::MacroString(macro_string) => macro_string.clone(),
+            _ => MacroString::parse(operator.arg.as_deref().unwrap_or_default()),
+        };
+        let files = || -> Vec<PathBuf> {
+            match &operator.argument {
+                OperatorArgument::FileList(files) => files
+                    .iter()
+                    .map(|file| resolve_path(file, &operator.span))
+                    .collect(),
+                _ => vec![],
+            }
+        };
+
+        let matcher = match (op, &operator.argument) {
+            (BeginsWith | Contains | EndsWith | StringEquals | Within, _) => {
+                Matcher::String(macro_string())
+            }
+            // the pattern can't contain macros here, since it's expanded before the search
+            (StringMatch, _) => Matcher::StringMatch(Box::new(Horspool::new(
+                macro_string().to_string().as_bytes(),
+            ))),
+            (Regex, OperatorArgument::Regex(pattern)) => Matcher::Regex(compile_regex(pattern)?),
+            (Regex, _) => Matcher::DynamicRegex(macro_string()),
+            (PatternMatch, OperatorArgument::PhraseList(phrases)) => {
+                Matcher::Phrases(Box::new(phrase_matcher(phrases)?))
+            }
+            (PatternMatchFromFile, _) => {
+                let mut phrases = vec![];
+                for file in files() {
+                    phrases.extend(read_lines(&file)?);
+                }
+                Matcher::Phrases(Box::new(phrase_matcher(phrases)?))
+            }
+            (IpMatch, OperatorArgument::CidrList(cidrs)) => Matcher::IpMatch(cidrs.clone()),
+            (IpMatchFromFile, _) => {
+                let mut cidrs = vec![];
+                for file in files() {
+                    for line in read_lines(&file)? {
+                        cidrs.push(line.parse().map_err(|reason| Error::InvalidFile {
+                            path: file.display().to_string(),
+                            reason,
+                        })?);
+                    }
+                }
+                Matcher::IpMatch(cidrs)
+            }
+            (ValidateByteRange, OperatorArgument::ByteRanges(ranges)) => {
+                let mut allowed = Box::new([false; 256]);
+                for range in ranges {
+                    for b in range.clone() {
+                        allowed[b as usize] = true;
+                    }
+                }
+                Matcher::ByteRange(allowed)
+            }
+            (Eq | Ge | Gt | Le | Lt, OperatorArgument::Number(n)) => {
+                Matcher::Numeric(Numeric::Number(*n))
+            }
+            (Eq | Ge | Gt | Le | Lt, _) => Matcher::Numeric(Numeric::Macro(macro_string())),
+            (VerifyCC, OperatorArgument::Regex(pattern)) => {
+                Matcher::VerifyCC(compile_regex(pattern)?)
+            }
+            (RegexSubstitute, OperatorArgument::Substitution(substitution)) => Matcher::Substitute(
+                compile_regex(&substitution.pattern)?,
+                substitution.replacement.as_bytes().to_vec(),
+            ),
+            (ValidateDTD, _) => {
+                let file = files().pop().unwrap_or_default();
+                Matcher::Xml(Box::new(XmlValidator::from_dtd(&file)?))
+            }
+            (ValidateSchema, _) => {
+                let file = files().pop().unwrap_or_default();
+                Matcher::Xml(Box::new(XmlValidator::from_schema(&file)?))
+            }
+            (InspectFile, _) => {
+                let file = files().pop().unwrap_or_default();
+                let canonical = fs::canonicalize(&file).map_err(|source| Error::IoError {
+                    path: file.display().to_string(),
+                    source,
+                })?;
+                let allowed = config
+                    .inspect_file_allowlist
+                    .iter()
+                    .filter_map(|allowed| fs::canonicalize(allowed).ok())
+                    .any(|allowed| allowed == canonical);
+                if !allowed {
+                    return Err(Error::NotAllowed(file.display().to_string()));
+                }
+                Matcher::InspectFile(canonical)
+            }
+            (NoMatch, _) => Matcher::Always(false),
+            (UnconditionalMatch, _) => Matcher::Always(true),
+            (ValidateUrlEncoding, _) => Matcher::UrlEncoding,
+            (ValidateUtf8Encoding, _) => Matcher::Utf8Encoding,
+            // libinjection, GeoIP databases and DNS lookups aren't available
+            (DetectSQLi | DetectXSS | GeoLookup | RealtimeBlackhole, _) => {
+                return Err(Error::Unsupported(op.name()))
+            }
+            // the parser guarantees the argument type for every other operator
+            _ => return Err(Error::Unsupported(op.name())),
+        };
+
+        Ok(Self { op, matcher })
+    }
+
+    #[inline]
+    pub fn operator_type(&self) -> OperatorType {
+        self.op
+    }
+
+    /// Evaluates the operator against a single value, returning `None` if it doesn't match.
+    pub fn evaluate(
+        &self,
+        input: &[u8],
+        macros: &dyn MacroResolver,
+    ) -> Result<Option<OperatorMatch>, Error> {
+        use OperatorType::*;
+
+        let expand = |macro_string: &MacroString| {
+            macro_string.expand(|collection, key| macros.resolve(collection, key))
+        };
+
+        Ok(match &self.matcher {
+            Matcher::String(param) => {
+                let param = expand(param);
+                let param = param.as_bytes();
+                let matched = match self.op {
+                    BeginsWith => input.starts_with(param),
+                    EndsWith => input.ends_with(param),
+                    StringEquals => input == param,
+                    Contains => Horspool::new(param).find(input).is_some(),
+                    Within => Horspool::new(input).find(param).is_some(),
+                    _ => unreachable!(),
+                };
+                matched.then(|| OperatorMatch::capture(param))
+            }
+            Matcher::StringMatch(horspool) => horspool
+                .find(input)
+                .map(|_| OperatorMatch::capture(&horspool.pattern)),
+            Matcher::Regex(regex) => regex_match(regex, input),
+            Matcher::DynamicRegex(pattern) => regex_match(&compile_regex(&expand(pattern))?, input),
+            Matcher::Phrases(phrases) => phrases
+                .find(input)
+                .map(|m| OperatorMatch::capture(&input[m.start()..m.end()])),
+            Matcher::IpMatch(cidrs) => std::str::from_utf8(input)
+                .ok()
+                .and_then(|addr| addr.trim().parse::<IpAddr>().ok())
+                .filter(|addr| cidrs.iter().any(|cidr| cidr.contains(addr)))
+                .map(|_| OperatorMatch::capture(input)),
+            Matcher::ByteRange(allowed) => input
+                .iter()
+                .find(|&&b| !allowed[b as usize])
+                .map(|&b| OperatorMatch::capture(&[b])),
+            Matcher::Numeric(param) => {
+                let param = match param {
+                    Numeric::Number(n) => *n,
+                    Numeric::Macro(macro_string) => atoi(expand(macro_string).as_bytes()),
+                };
+                let value = atoi(input);
+                let matched = match self.op {
+                    Eq => value == param,
+                    Ge => value >= param,
+                    Gt => value > param,
+                    Le => value <= param,
+                    Lt => value < param,
+                    _ => unreachable!(),
+                };
+                matched.then(|| OperatorMatch::capture(input))
+            }
+            Matcher::VerifyCC(regex) => regex
+                .find_iter(input)
+                .find(|m| luhn(m.as_bytes()))
+                .map(|m| OperatorMatch::capture(m.as_bytes())),
+            Matcher::Substitute(regex, replacement) => {
+                regex.is_match(input).then(|| OperatorMatch {
+                    captures: vec![input.to_vec()],
+                    replaced: Some(
+                        regex
+                            .replace_all(input, regex::bytes::NoExpand(replacement))
+                            .into_owned(),
+                    ),
+                })
+            }
+            // validation operators match when the input is invalid
+            Matcher::Xml(validator) => validator
+                .validate(input)
+                .err()
+                .map(|reason| OperatorMatch::capture(reason.as_bytes())),
+            Matcher::InspectFile(script) => {
+                let output = Command::new(script)
+                    .arg(String::from_utf8_lossy(input).as_ref())
+                    .output()
+                    .map_err(|source| Error::InspectFileError {
+                        path: script.display().to_string(),
+                        source,
+                    })?;
+                (!output.stdout.starts_with(b"1")).then(|| OperatorMatch::capture(input))
+            }
+            Matcher::Always(matched) => matched.then(OperatorMatch::default),
+            Matcher::UrlEncoding => {
+                (!is_valid_url_encoding(input)).then(|| OperatorMatch::capture(input))
+            }
+            Matcher::Utf8Encoding => std::str::from_utf8(input)
+                .is_err()
+                .then(|| OperatorMatch::capture(input)),
+        })
+    }
+}
+
+fn regex_match(regex: &regex::bytes::Regex, input: &[u8]) -> Option<OperatorMatch> {
+    regex.captures(input).map(|captures| OperatorMatch {
+        captures: captures
+            .iter()
+            .map(|group| group.map(|g| g.as_bytes().to_vec()).unwrap_or_default())
+            .collect(),
+        replaced: None,
+    })
+}
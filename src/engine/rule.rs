@@ -0,0 +1,359 @@
+//! Evaluation of rules (and rule chains) against a transaction.
+
+use super::actions::{self, ActionConfig, Disruption, Outcome, RuleMetadata};
+use super::collections;
+use super::operators::{self, CompiledOperator, OperatorConfig};
+use super::transaction::Transaction;
+use super::transforms::{self, Pipeline, TransformType};
+use super::{full_name, get_target_values, CompiledInput};
+use crate::syntax::{Action, ActionType, CRSEntry, Span};
+use http::Request;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{span}: {source}")]
+    OperatorError {
+        source: operators::Error,
+        span: Span,
+    },
+    #[error("{span}: {source}")]
+    ActionError { source: actions::Error, span: Span },
+    #[error("{span}: {source}")]
+    TransformError {
+        source: transforms::Error,
+        span: Span,
+    },
+    #[error("{span}: invalid selector, {source}")]
+    SelectorError { source: regex::Error, span: Span },
+    #[error("{0}: chain starter isn't followed by a rule")]
+    IncompleteChain(Span),
+    #[error("failed to save the persistent collections, {0}")]
+    CollectionError(#[from] collections::Error),
+}
+
+/// Settings used when compiling and evaluating rules.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    pub operators: OperatorConfig,
+    pub actions: ActionConfig,
+}
+
+/// A value that matched the operator of a rule.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatchedValue {
+    /// The full name of the variable, e.g. `ARGS:foo`.
+    pub name: String,
+    /// The value the operator matched, after transformation.
+    pub value: Vec<u8>,
+    /// The number of transformations that had been applied to produce the value, 0 for the
+    /// original value.
+    pub step: usize,
+    /// The transformation that produced the value, or `None` for the original value.
+    pub transform: Option<TransformType>,
+}
+
+/// The result of a rule (or a whole chain) matching.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RuleMatch {
+    /// Metadata of the rule, or of the chain starter for a chain.
+    pub metadata: RuleMetadata,
+    /// The rule message, with macros expanded.
+    pub msg: Option<String>,
+    /// The rule's `logdata`, with macros expanded.
+    pub logdata: Option<String>,
+    pub disruption: Option<Disruption>,
+    /// A `skip` or `skipAfter` to apply.
+    pub flow: Option<Outcome>,
+    /// The values that matched, for every rule in the chain in order.
+    pub matched: Vec<MatchedValue>,
+}
+
+/// A rule that's ready to be evaluated, along with any rules chained to it.
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+    pub metadata: RuleMetadata,
+    inputs: Vec<CompiledInput>,
+    /// The operator and whether it's inverted, or `None` for `SecAction`, which always matches.
+    test: Option<(bool, CompiledOperator)>,
+    actions: Vec<Action>,
+    transforms: Pipeline,
+    /// Whether the operator is checked after every transformation (`multiMatch`), rather than
+    /// only once they've all been applied.
+    multi_match: bool,
+    capture: bool,
+    chained: Option<Box<CompiledRule>>,
+    span: Span,
+}
+
+impl CompiledRule {
+    /// Compiles a single `SecRule` or `SecAction`, returning `None` for other directives.
+    fn compile(entry: &CRSEntry, config: &EngineConfig) -> Result<Option<Self>, Error> {
+        let (inputs, test, actions, span) = match entry {
+            CRSEntry::SecRule {
+                inputs,
+                test,
+                actions,
+                span,
+            } => {
+                let operator = CompiledOperator::compile(&test.operator, &config.operators)
+                    .map_err(|source| Error::OperatorError {
+                        source,
+                        span: test.operator.span.clone(),
+                    })?;
+                let inputs = inputs
+                    .iter()
+                    .map(|input| {
+                        CompiledInput::compile(input).map_err(|source| Error::SelectorError {
+                            source,
+                            span: input.span.clone(),
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                (inputs, Some((test.invert, operator)), actions, span)
+            }
+            CRSEntry::SecAction { actions, span } => (vec![], None, actions, span),
+            _ => return Ok(None),
+        };
+
+        let metadata =
+            RuleMetadata::from_actions(actions).map_err(|source| Error::ActionError {
+                source,
+                span: span.clone(),
+            })?;
+
+        let mut transforms = Pipeline::default();
+        for action in actions.iter().filter(|a| a.action == ActionType::Transform) {
+            transforms
+                .push(action.argument().unwrap_or_default())
+                .map_err(|source| Error::TransformError {
+                    source,
+                    span: action.span.clone(),
+                })?;
+        }
+
+        Ok(Some(Self {
+            metadata,
+            inputs,
+            test,
+            transforms,
+            multi_match: actions.iter().any(|a| a.action == ActionType::MultiMatch),
+            capture: actions.iter().any(|a| a.action == ActionType::Capture),
+            actions: actions.clone(),
+            chained: None,
+            span: span.clone(),
+        }))
+    }
+
+    #[inline]
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// The rules chained to this one, in order.
+    pub fn chain(&self) -> impl Iterator<Item = &CompiledRule> {
+        std::iter::successors(self.chained.as_deref(), |rule| rule.chained.as_deref())
+    }
+
+    /// Evaluates the rule, and if it matches, the rules chained to it. Non-disruptive actions are
+    /// applied to the transaction as each rule in the chain matches, while the disruptive action
+    /// is only returned if the whole chain matches.
+    pub fn evaluate(
+        &self,
+        request: &Request<Vec<u8>>,
+        transaction: &mut Transaction,
+        config: &ActionConfig,
+    ) -> Result<Option<RuleMatch>, Error> {
+        // MATCHED_VARS only holds the matches of the current rule (and its chain so far)
+        transaction.matched_vars.clear();
+        transaction.rule = Some(self.metadata.clone());
+        let mut outcomes = vec![];
+        let mut matched = vec![];
+        for rule in std::iter::once(self).chain(self.chain()) {
+            if !rule.evaluate_link(request, transaction, config, &mut outcomes, &mut matched)? {
+                return Ok(None);
+            }
+        }
+
+        let expand = |action: ActionType| {
+            self.actions
+                .iter()
+                .find(|a| a.action == action)
+                .and_then(Action::argument)
+                .map(|arg| transaction.expand(arg))
+        };
+
+        let mut rule_match = RuleMatch {
+            metadata: self.metadata.clone(),
+            msg: expand(ActionType::Msg),
+            logdata: expand(ActionType::LogData),
+            disruption: None,
+            flow: None,
+            matched,
+        };
+        // the chain starter's actions come first, and it's the only rule in a chain allowed to
+        // have disruptive or flow actions
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Continue => {}
+                Outcome::Disrupt(disruption) => {
+                    rule_match.disruption.get_or_insert(disruption);
+                }
+                flow => {
+                    rule_match.flow.get_or_insert(flow);
+                }
+            }
+        }
+        Ok(Some(rule_match))
+    }
+
+    /// Evaluates a single rule of a chain, returning whether it matched.
+    fn evaluate_link(
+        &self,
+        request: &Request<Vec<u8>>,
+        transaction: &mut Transaction,
+        config: &ActionConfig,
+        outcomes: &mut Vec<Outcome>,
+        matched: &mut Vec<MatchedValue>,
+    ) -> Result<bool, Error> {
+        let (invert, operator) = match &self.test {
+            Some((invert, operator)) => (*invert, operator),
+            None => {
+                self.apply_actions(transaction, config, outcomes)?;
+                return Ok(true);
+            }
+        };
+
+        // the values are copied, since the actions of a match can change them (e.g. TX)
+        let values: Vec<(String, Vec<u8>)> = get_target_values(request, transaction, &self.inputs)
+            .iter()
+            .map(|(input, value)| (full_name(*input, value), value.value().to_vec()))
+            .collect();
+
+        let mut any_matched = false;
+        for (name, value) in values {
+            for step in self.transforms.steps(&value, self.multi_match) {
+                let result = operator
+                    .evaluate(&step.value, transaction)
+                    .map_err(|source| Error::OperatorError {
+                        source,
+                        span: self.span.clone(),
+                    })?;
+                // like ModSecurity, an inverted operator that didn't match doesn't capture anything
+                let captures = match (result, invert) {
+                    (Some(result), false) => Some(result.captures),
+                    (None, true) => None,
+                    _ => continue,
+                };
+
+                any_matched = true;
+                if let Some(captures) = captures.filter(|_| self.capture) {
+                    if operator.operator_type().supports_capture() {
+                        transaction.store_captures(&captures);
+                    }
+                }
+                transaction.record_match(name.clone(), step.value.clone());
+                matched.push(MatchedValue {
+                    name: name.clone(),
+                    value: step.value,
+                    step: step.index,
+                    transform: step.transform,
+                });
+                // like ModSecurity, non-disruptive actions are applied for every value (and
+                // with multiMatch, every transformation step) that matched
+                self.apply_actions(transaction, config, outcomes)?;
+            }
+        }
+        Ok(any_matched)
+    }
+
+    fn apply_actions(
+        &self,
+        transaction: &mut Transaction,
+        config: &ActionConfig,
+        outcomes: &mut Vec<Outcome>,
+    ) -> Result<(), Error> {
+        for action in &self.actions {
+            let outcome = actions::apply(action, transaction, config).map_err(|source| {
+                Error::ActionError {
+                    source,
+                    span: action.span.clone(),
+                }
+            })?;
+            if outcome != Outcome::Continue {
+                outcomes.push(outcome);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The rules compiled from a list of entries.
+#[derive(Debug, Default)]
+pub struct CompiledRules {
+    pub rules: Vec<CompiledRule>,
+    /// The index in `rules` of the first rule after each `SecMarker`.
+    pub markers: HashMap<String, usize>,
+    /// Rules that failed to compile, e.g. because they use an unsupported operator. They're
+    /// left out, along with the rest of their chain.
+    pub errors: Vec<Error>,
+    /// The timeout of persistent collection records, from the last `SecCollectionTimeout`.
+    pub collection_timeout: Option<Duration>,
+}
+
+/// Compiles the rules among `entries`, attaching chained rules to their chain starter.
+pub fn compile_rules<'a>(
+    entries: impl IntoIterator<Item = &'a CRSEntry>,
+    config: &EngineConfig,
+) -> CompiledRules {
+    let mut compiled = CompiledRules::default();
+    // the chain currently being built, starter first
+    let mut chain: Vec<CompiledRule> = vec![];
+    // whether a rule of the current chain failed to compile
+    let mut broken = false;
+
+    for entry in entries {
+        let actions = match entry {
+            CRSEntry::SecMarker { marker, .. } => {
+                compiled
+                    .markers
+                    .insert(marker.clone(), compiled.rules.len());
+                continue;
+            }
+            CRSEntry::SecCollectionTimeout { seconds, .. } => {
+                compiled.collection_timeout = Some(Duration::from_secs(*seconds));
+                continue;
+            }
+            CRSEntry::SecRule { actions, .. } | CRSEntry::SecAction { actions, .. } => actions,
+            _ => continue,
+        };
+        match CompiledRule::compile(entry, config) {
+            Ok(Some(rule)) if !broken => chain.push(rule),
+            Ok(_) => {}
+            Err(error) => {
+                compiled.errors.push(error);
+                chain.clear();
+                broken = true;
+            }
+        }
+
+        if !actions.iter().any(|a| a.action == ActionType::Chain) {
+            if let Some(mut rule) = chain.pop() {
+                while let Some(mut previous) = chain.pop() {
+                    previous.chained = Some(Box::new(rule));
+                    rule = previous;
+                }
+                compiled.rules.push(rule);
+            }
+            broken = false;
+        }
+    }
+
+    if let Some(starter) = chain.first() {
+        compiled
+            .errors
+            .push(Error::IncompleteChain(starter.span.clone()));
+    }
+    compiled
+}
@@ -0,0 +1,207 @@
+//! Evaluation of a whole rule set against a transaction, phase by phase, the way ModSecurity
+//! processes a request.
+
+use super::actions::{ActionConfig, AllowScope, Disruption, Outcome};
+use super::rule::{compile_rules, CompiledRule, EngineConfig, Error, RuleMatch};
+use super::transaction::{RuleEngine, Transaction};
+use crate::syntax::CRSEntry;
+use http::Request;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Rules without a `phase` action run in phase 2, like in ModSecurity.
+const DEFAULT_PHASE: u8 = 2;
+
+/// The logging phase, which runs even after the transaction has been interrupted.
+const LOGGING_PHASE: u8 = 5;
+
+/// What happened when a transaction was processed.
+#[derive(Debug, Default)]
+pub struct Verdict {
+    /// The rules (and chains) that matched, in the order they were evaluated.
+    pub matches: Vec<RuleMatch>,
+    /// The disruptive action that interrupted the transaction, if any.
+    pub interruption: Option<Disruption>,
+    /// The index in `matches` of the match that interrupted the transaction.
+    pub interrupted_by: Option<usize>,
+    /// Errors raised while evaluating rules. Like ModSecurity, the engine carries on with the
+    /// next rule.
+    pub errors: Vec<Error>,
+}
+
+impl Verdict {
+    /// The IDs of the rules that matched, in order.
+    pub fn matched_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.matches.iter().filter_map(|m| m.metadata.id)
+    }
+}
+
+/// A compiled rule set, along with the position of its markers.
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    rules: Vec<CompiledRule>,
+    /// The index of the first rule after each `SecMarker`.
+    markers: HashMap<String, usize>,
+    actions: ActionConfig,
+    /// The mode used unless a rule changes it with `ctl:ruleEngine`, like `SecRuleEngine`.
+    pub rule_engine: RuleEngine,
+    /// What `block` does, which `SecDefaultAction` decides in ModSecurity. It defaults to
+    /// `pass`, like ModSecurity's own default action.
+    pub block: Disruption,
+    /// The timeout of new persistent collection records, set with `SecCollectionTimeout`. When
+    /// it isn't set, the timeout of the transaction's [`Collections`] is used.
+    ///
+    /// [`Collections`]: super::collections::Collections
+    pub collection_timeout: Option<Duration>,
+}
+
+/// The result of compiling a rule set: the rules that compiled, along with the errors of the
+/// ones that didn't.
+#[derive(Debug)]
+pub struct CompileReport {
+    pub rules: RuleSet,
+    pub errors: Vec<Error>,
+}
+
+impl CompileReport {
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl RuleSet {
+    /// Compiles the rules among `entries`. Rules that fail to compile are left out and reported,
+    /// so that a rule using an unsupported feature doesn't prevent the rest of the rule set from
+    /// loading.
+    pub fn compile<'a>(
+        entries: impl IntoIterator<Item = &'a CRSEntry>,
+        config: &EngineConfig,
+    ) -> CompileReport {
+        let compiled = compile_rules(entries, config);
+        CompileReport {
+            rules: Self {
+                rules: compiled.rules,
+                markers: compiled.markers,
+                actions: config.actions.clone(),
+                rule_engine: RuleEngine::On,
+                block: Disruption::Pass,
+                collection_timeout: compiled.collection_timeout,
+            },
+            errors: compiled.errors,
+        }
+    }
+
+    #[inline]
+    pub fn rules(&self) -> &[CompiledRule] {
+        &self.rules
+    }
+
+    /// The index of the first rule after the target of a `skipAfter`, which is either a marker
+    /// or a rule ID.
+    fn skip_after(&self, target: &str) -> Option<usize> {
+        self.markers.get(target).copied().or_else(|| {
+            let id = target.trim().parse::<u64>().ok()?;
+            let index = self.rules.iter().position(|r| r.metadata.id == Some(id))?;
+            Some(index + 1)
+        })
+    }
+
+    /// Runs the request phases, any response phases, and the logging phase against a
+    /// transaction, then saves the changes to its persistent collections.
+    pub fn process(&self, request: &Request<Vec<u8>>, transaction: &mut Transaction) -> Verdict {
+        let mut verdict = Verdict::default();
+        if let Some(timeout) = self.collection_timeout {
+            transaction.collections.timeout = timeout;
+        }
+        // the phases that still need to run, since `allow` can skip some of them
+        let mut skip_until = 1;
+
+        for phase in 1..=LOGGING_PHASE {
+            let engine = transaction.config.rule_engine.unwrap_or(self.rule_engine);
+            if engine == RuleEngine::Off {
+                break;
+            }
+            let interrupted = verdict.interruption.is_some();
+            if phase < skip_until || (interrupted && phase != LOGGING_PHASE) {
+                continue;
+            }
+
+            let mut index = 0;
+            while index < self.rules.len() {
+                let rule = &self.rules[index];
+                index += 1;
+                let in_phase = rule.metadata.phase.unwrap_or(DEFAULT_PHASE) == phase;
+                let removed = transaction
+                    .config
+                    .is_rule_removed(rule.metadata.id, &rule.metadata.tags);
+                if !in_phase || removed {
+                    continue;
+                }
+
+                let rule_match = match rule.evaluate(request, transaction, &self.actions) {
+                    Ok(Some(rule_match)) => rule_match,
+                    Ok(None) => continue,
+                    Err(error) => {
+                        verdict.errors.push(error);
+                        continue;
+                    }
+                };
+                let disruption = rule_match.disruption.clone();
+                let flow = rule_match.flow.clone();
+                verdict.matches.push(rule_match);
+
+                let detection_only =
+                    transaction.config.rule_engine.unwrap_or(self.rule_engine) != RuleEngine::On;
+                let disruption = match disruption {
+                    Some(Disruption::Block) => Some(self.block.clone()),
+                    disruption => disruption,
+                };
+                match disruption {
+                    None | Some(Disruption::Pass | Disruption::Block | Disruption::Pause(_)) => {}
+                    // DetectionOnly reports matches without acting on them
+                    Some(_) if detection_only => {}
+                    Some(Disruption::Allow(scope)) => {
+                        skip_until = match scope {
+                            AllowScope::Transaction => LOGGING_PHASE,
+                            AllowScope::Phase => phase + 1,
+                            AllowScope::Request => 3,
+                        };
+                        break;
+                    }
+                    Some(disruption) => {
+                        verdict.interruption = Some(disruption);
+                        verdict.interrupted_by = Some(verdict.matches.len() - 1);
+                        break;
+                    }
+                }
+
+                match flow {
+                    // `skip` counts rules in the same phase, skipping chains as a whole
+                    Some(Outcome::Skip(count)) => {
+                        let mut remaining = count;
+                        while remaining > 0 && index < self.rules.len() {
+                            let in_phase =
+                                self.rules[index].metadata.phase.unwrap_or(DEFAULT_PHASE) == phase;
+                            if in_phase {
+                                remaining -= 1;
+                            }
+                            index += 1;
+                        }
+                    }
+                    Some(Outcome::SkipAfter(target)) => {
+                        if let Some(next) = self.skip_after(&target) {
+                            index = index.max(next);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Err(error) = transaction.collections.flush() {
+            verdict.errors.push(error.into());
+        }
+        verdict
+    }
+}
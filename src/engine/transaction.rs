@@ -0,0 +1,310 @@
+//! State of a single transaction that rules read and modify as they're evaluated.
+
+use super::actions::RuleMetadata;
+use super::collections::Collections;
+use super::computed;
+use super::operators::MacroResolver;
+use crate::syntax::InputType;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Number of `TX` variables used for captures (`TX:0` to `TX:9`).
+pub const MAX_CAPTURES: usize = 10;
+
+/// Values of the `SecRuleEngine` setting, which can be changed per transaction with
+/// `ctl:ruleEngine`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RuleEngine {
+    On,
+    Off,
+    DetectionOnly,
+}
+
+/// Configuration changes made by `ctl` actions, which only apply to the current transaction.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionConfig {
+    pub rule_engine: Option<RuleEngine>,
+    pub audit_engine: Option<String>,
+    /// Rule IDs (and ranges of IDs) removed with `ctl:ruleRemoveById`.
+    pub removed_rule_ids: Vec<RangeInclusive<u64>>,
+    /// Rules removed with `ctl:ruleRemoveByTag`, matched against the rule tags.
+    pub removed_rule_tags: Vec<String>,
+    /// The body processor set with `ctl:requestBodyProcessor`, e.g. `XML` or `JSON`.
+    pub request_body_processor: Option<String>,
+    /// Any other `ctl` options, as name and value.
+    pub other: Vec<(String, String)>,
+}
+
+impl TransactionConfig {
+    /// Returns true if the rule was removed with `ctl:ruleRemoveById` or `ctl:ruleRemoveByTag`.
+    pub fn is_rule_removed(&self, id: Option<u64>, tags: &[String]) -> bool {
+        id.is_some_and(|id| {
+            self.removed_rule_ids
+                .iter()
+                .any(|range| range.contains(&id))
+        }) || tags.iter().any(|tag| self.removed_rule_tags.contains(tag))
+    }
+}
+
+/// Data that must not be written to the audit log, as requested by the `sanitise*` actions.
+#[derive(Debug, Clone, Default)]
+pub struct Sanitisation {
+    /// Request parameter names, in lowercase.
+    pub args: HashSet<String>,
+    /// Request header names, in lowercase.
+    pub request_headers: HashSet<String>,
+    /// Response header names, in lowercase.
+    pub response_headers: HashSet<String>,
+    /// Full variable names (e.g. `ARGS:password`) to mask entirely.
+    pub variables: HashSet<String>,
+    /// Full variable names to mask only the matched bytes of, keeping the given number of bytes
+    /// at the start and end of the match visible.
+    pub matched_bytes: HashMap<String, (usize, usize)>,
+}
+
+impl Sanitisation {
+    /// Replaces every character of a value with an asterisk.
+    pub fn mask(value: &str) -> String {
+        "*".repeat(value.chars().count())
+    }
+
+    /// Masks the bytes of a value, keeping `keep_start` bytes at the start and `keep_end` bytes
+    /// at the end visible.
+    pub fn mask_bytes(value: &[u8], keep_start: usize, keep_end: usize) -> Vec<u8> {
+        let len = value.len();
+        value
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if i < keep_start || i + keep_end >= len {
+                    b
+                } else {
+                    b'*'
+                }
+            })
+            .collect()
+    }
+}
+
+/// A variable that matched a rule's operator, as exposed through `MATCHED_VAR` and
+/// `MATCHED_VARS`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatchedVar {
+    /// The full name of the variable, e.g. `ARGS:foo` or `REQUEST_URI`.
+    pub name: String,
+    /// The value that matched, after transformation.
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    /// Identifies the transaction, like the token generated by mod_unique_id.
+    pub unique_id: String,
+    /// When processing of the transaction started.
+    pub started_at: SystemTime,
+    started: Instant,
+    /// The `TX` collection, keyed by lowercase variable name.
+    pub tx: HashMap<String, String>,
+    /// The `ENV` collection, modified with `setenv`.
+    pub env: HashMap<String, String>,
+    /// Set with `setuid`.
+    pub user_id: Option<String>,
+    /// Set with `setsid`.
+    pub session_id: Option<String>,
+    /// Set with `setrsc`.
+    pub resource_id: Option<String>,
+    /// The response status to use for `deny` and `redirect`, set with `status`.
+    pub status: Option<u16>,
+    /// Whether any matching rule asked for the transaction to be written to the audit log.
+    pub audit_log: bool,
+    pub sanitise: Sanitisation,
+    /// Content to insert before the response body, from `prepend`.
+    pub response_prepend: Vec<u8>,
+    /// Content to add after the response body, from `append`.
+    pub response_append: Vec<u8>,
+    /// XML namespace prefixes registered with `xmlns`, mapped to their URIs.
+    pub xml_namespaces: HashMap<String, String>,
+    pub config: TransactionConfig,
+    /// Persistent collections opened with `initcol`, `setsid`, `setuid` or `setrsc`.
+    pub collections: Collections,
+    /// The variable that most recently matched, in any rule.
+    pub matched_var: Option<MatchedVar>,
+    /// The variables that matched the current rule, including the earlier rules of its chain.
+    pub matched_vars: Vec<MatchedVar>,
+    /// The metadata of the rule being evaluated (the chain starter for a chain), exposed as the
+    /// `RULE` collection.
+    pub rule: Option<RuleMetadata>,
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Generates a unique ID from the current time, the process ID and a counter, so that IDs are
+/// unique across concurrent transactions and processes.
+fn generate_unique_id(now: SystemTime) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let micros = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", micros, std::process::id(), count)
+}
+
+impl Transaction {
+    /// Creates a transaction with its own persistent collection storage, so that its
+    /// collections aren't shared with any other transaction.
+    pub fn new() -> Self {
+        Self::with_collections(Collections::default())
+    }
+
+    /// Creates a transaction using the given persistent collections, which are usually backed
+    /// by a store shared by every transaction.
+    pub fn with_collections(collections: Collections) -> Self {
+        let started_at = SystemTime::now();
+        Self {
+            unique_id: generate_unique_id(started_at),
+            started_at,
+            started: Instant::now(),
+            tx: Default::default(),
+            env: Default::default(),
+            user_id: None,
+            session_id: None,
+            resource_id: None,
+            status: None,
+            audit_log: false,
+            sanitise: Default::default(),
+            response_prepend: vec![],
+            response_append: vec![],
+            xml_namespaces: Default::default(),
+            config: Default::default(),
+            collections,
+            matched_var: None,
+            matched_vars: vec![],
+            rule: None,
+        }
+    }
+
+    /// Time elapsed since the transaction started.
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Stores the data captured by an operator in `TX:0` to `TX:9`. Slots without a capture are
+    /// removed, so that stale captures from earlier rules can't be mistaken for new ones.
+    pub fn store_captures(&mut self, captures: &[Vec<u8>]) {
+        for slot in 0..MAX_CAPTURES {
+            match captures.get(slot) {
+                Some(capture) => {
+                    let capture = String::from_utf8_lossy(capture).into_owned();
+                    self.tx.insert(slot.to_string(), capture)
+                }
+                None => self.tx.remove(&slot.to_string()),
+            };
+        }
+    }
+
+    /// Records a variable that matched the current rule.
+    pub fn record_match(&mut self, name: String, value: Vec<u8>) {
+        let matched = MatchedVar { name, value };
+        self.matched_vars.push(matched.clone());
+        self.matched_var = Some(matched);
+    }
+
+    /// Looks up a variable in the `TX` collection, ignoring case.
+    pub fn tx_var(&self, name: &str) -> Option<&str> {
+        self.tx.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    /// Looks up a variable in the `RULE` collection. Like ModSecurity, `msg` and `logdata` are
+    /// the unexpanded action arguments, and `severity` is numeric.
+    fn rule_var(&self, name: &str) -> Option<String> {
+        let rule = self.rule.as_ref()?;
+        match name.to_lowercase().as_str() {
+            "id" => rule.id.map(|id| id.to_string()),
+            "msg" => rule.msg.clone(),
+            "logdata" => rule.logdata.clone(),
+            "rev" => rule.revision.clone(),
+            "ver" => rule.version.clone(),
+            "severity" => rule.severity.map(|severity| severity.to_string()),
+            "accuracy" => rule.accuracy.map(|accuracy| accuracy.to_string()),
+            "maturity" => rule.maturity.map(|maturity| maturity.to_string()),
+            "phase" => rule.phase.map(|phase| phase.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Expands the macros in a string using the transaction's collections.
+    pub fn expand(&self, s: &str) -> String {
+        crate::syntax::MacroString::parse(s).expand(|collection, key| self.resolve(collection, key))
+    }
+}
+
+impl MacroResolver for Transaction {
+    fn resolve(&self, collection: &str, key: Option<&str>) -> Option<String> {
+        let collection = collection.to_lowercase();
+        match (collection.as_str(), key) {
+            ("tx", Some(key)) => self.tx_var(key).map(Into::into),
+            ("env", Some(key)) => self.env.get(key).cloned(),
+            ("rule", Some(key)) => self.rule_var(key),
+            ("userid", None) => self.user_id.clone(),
+            ("sessionid", None) => self.session_id.clone(),
+            ("matched_var", None) => self
+                .matched_var
+                .as_ref()
+                .map(|matched| String::from_utf8_lossy(&matched.value).into_owned()),
+            (collection, Some(key)) if self.collections.is_open(collection) => {
+                self.collections.get(collection, key)
+            }
+            ("matched_var_name", None) => self.matched_var.as_ref().map(|m| m.name.clone()),
+            (name, None) => InputType::from_name(&name.to_uppercase())
+                .and_then(|input| computed::transaction_value(self, input)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::rule::EngineConfig;
+    use crate::engine::ruleset::RuleSet;
+    use crate::syntax::parse_entries;
+
+    #[test]
+    fn rule_macros_expand_from_the_current_rule() {
+        let entries = parse_entries(
+            r#"SecRule ARGS:a "@streq x" "id:10,phase:1,pass,severity:CRITICAL,rev:3,msg:'Bad argument',logdata:'rule %{rule.id}: %{RULE.msg} (%{rule.severity}, rev %{rule.rev})',setvar:tx.last_rule=%{rule.id}"
+SecRule ARGS:b "@streq y" "id:11,phase:1,pass,msg:'Chained',chain"
+    SecRule ARGS:c "@streq %{rule.id}" "setvar:tx.chained=%{rule.msg}"
+"#,
+        )
+        .unwrap();
+        let report = RuleSet::compile(&entries, &EngineConfig::default());
+        assert!(report.is_ok(), "{:?}", report.errors);
+
+        let request = http::Request::get("/?a=x&b=y&c=11").body(vec![]).unwrap();
+        let mut transaction = Transaction::new();
+        let verdict = report.rules.process(&request, &mut transaction);
+        assert!(verdict.errors.is_empty(), "{:?}", verdict.errors);
+        assert_eq!(verdict.matched_ids().collect::<Vec<_>>(), [10, 11]);
+        assert_eq!(
+            verdict.matches[0].logdata.as_deref(),
+            Some("rule 10: Bad argument (2, rev 3)")
+        );
+        assert_eq!(transaction.tx_var("last_rule"), Some("10"));
+        // chained rules see the chain starter's metadata
+        assert_eq!(transaction.tx_var("chained"), Some("Chained"));
+    }
+
+    #[test]
+    fn rule_macros_are_empty_outside_rules() {
+        let transaction = Transaction::new();
+        assert_eq!(transaction.expand("[%{rule.id}]"), "[]");
+    }
+}
@@ -1,78 +1,1064 @@
-use crate::engine::Value;
-
-// pub fn lowercase(var: &Variable) -> Variable {
-//     var.value
-//     todo!()
-// }
-
-// use super::Variable;
-// use std::cell::RefCell;
-// use std::iter::Map;
-//
-//
-// pub struct Transform<'a, I, F> {
-//     iter: RefCell<Map<I, F>>,
-//     cache: RefCell<Vec<Variable<'a>>>,
-// }
-//
-// impl<'a, I, F> Transform<'a, I, F>
-// where
-//     I: Iterator<Item = Variable<'a>>,
-//     F: FnMut(I::Item) -> B,
-// {
-//     #[inline]
-//     pub fn cache_size(&self) -> usize {
-//         // Note: This is safe because we know Vec::len is essentially an atomic operation
-//         // in that it will always return a valid value in this case.
-//         unsafe { self.cache.try_borrow_unguarded() }.unwrap().len()
-//     }
-//
-//     #[inline]
-//     pub fn next(&self) -> Option<Variable<'a>> {
-//         if let Some(next) = self.iter.borrow_mut().next() {
-//             self.cache.borrow_mut().push(next);
-//             Some(next)
-//         } else {
-//             None
-//         }
-//     }
-//
-//     pub fn iter(&self) -> Iter<'a, '_, I, F> {
-//         Iter {
-//             index: 0,
-//             transform: self,
-//         }
-//     }
-// }
-//
-// pub struct Iter<'a, 'b, I, F> {
-//     index: usize,
-//     transform: &'b Transform<'a, I, F>,
-// }
-//
-// impl<'a, 'b, I, F> Iterator for Iter<'a, 'b, I, F>
-// where
-//     I: Iterator<Item = Variable<'a>>,
-//     F: FnMut(I::Item) -> B,
-// {
-//     type Item = Variable<'a>;
-//
-//     fn next(&mut self) -> Option<Self::Item> {
-//         let index = self.index;
-//
-//         if index < self.transform.cache_size() {
-//             // current index is less than the total number of cache entries,
-//             // so we can just return the cached value
-//             let cache = self.transform.cache.borrow();
-//             self.index += 1;
-//             Some(cache[index])
-//         } else {
-//             if let Some(next) = self.transform.next() {
-//                 self.index += 1;
-//                 Some(next)
-//             } else {
-//                 None
-//             }
-//         }
-//     }
-// }
+//! Transformation functions (the `t:` action) and the pipeline they form for each rule.
+//!
+//! Transformations work on raw bytes, since request data isn't necessarily valid UTF-8, and
+//! follow the behaviour of the ModSecurity implementations, including their leniency towards
+//! malformed input (e.g. an invalid `%` escape is left as it is).
+
+use crate::enum_token;
+use sha1::Digest;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown transformation t:{0}")]
+    Unknown(String),
+}
+
+enum_token! {
+    pub enum TransformType {
+        Base64Decode       = "base64Decode",
+        /// Like `base64Decode`, but skips invalid characters instead of stopping at them.
+        Base64DecodeExt    = "base64DecodeExt",
+        Base64Encode       = "base64Encode",
+        /// Normalises a command line: removes `\`, `"`, `'` and `^`, removes spaces before `/`
+        /// and `(`, replaces `,` and `;` with a space, compresses whitespace and lowercases.
+        CmdLine            = "cmdLine",
+        CompressWhitespace = "compressWhitespace",
+        CssDecode          = "cssDecode",
+        /// Decodes ANSI C escape sequences, e.g. `\n` and `\x41`.
+        EscapeSeqDecode    = "escapeSeqDecode",
+        HexDecode          = "hexDecode",
+        HexEncode          = "hexEncode",
+        HtmlEntityDecode   = "htmlEntityDecode",
+        JsDecode           = "jsDecode",
+        /// Replaces the value with its length in bytes.
+        Length             = "length",
+        Lowercase          = "lowercase",
+        Md5                = "md5",
+        /// Removes every transformation before it from the pipeline.
+        None               = "none",
+        NormalisePath      = "normalisePath",
+        NormalizePath      = "normalizePath",
+        /// Like `normalisePath`, but converts backslashes to forward slashes first.
+        NormalisePathWin   = "normalisePathWin",
+        NormalizePathWin   = "normalizePathWin",
+        ParityEven7Bit     = "parityEven7bit",
+        ParityOdd7Bit      = "parityOdd7bit",
+        ParityZero7Bit     = "parityZero7bit",
+        /// Removes `/* */` and `<!-- -->` comments, and anything after `--` or `#`.
+        RemoveComments     = "removeComments",
+        /// Removes the comment characters `/*`, `*/`, `<!--`, `-->`, `--` and `#`.
+        RemoveCommentsChar = "removeCommentsChar",
+        RemoveNulls        = "removeNulls",
+        RemoveWhitespace   = "removeWhitespace",
+        /// Replaces each `/* */` comment with a single space.
+        ReplaceComments    = "replaceComments",
+        ReplaceNulls       = "replaceNulls",
+        Sha1               = "sha1",
+        /// Decodes `0x` prefixed hex sequences, as used in SQL.
+        SqlHexDecode       = "sqlHexDecode",
+        Trim               = "trim",
+        TrimLeft           = "trimLeft",
+        TrimRight          = "trimRight",
+        Uppercase          = "uppercase",
+        UrlDecode          = "urlDecode",
+        /// Like `urlDecode`, but also decodes `%uHHHH` escapes.
+        UrlDecodeUni       = "urlDecodeUni",
+        UrlEncode          = "urlEncode",
+        /// Converts non-ASCII UTF-8 characters to `%uHHHH` escapes.
+        Utf8ToUnicode      = "utf8toUnicode",
+    }
+}
+
+impl TransformType {
+    /// Applies the transformation to a value.
+    pub fn apply(&self, input: &[u8]) -> Vec<u8> {
+        use TransformType::*;
+
+        match self {
+            Base64Decode => base64_decode(input, false),
+            Base64DecodeExt => base64_decode(input, true),
+            Base64Encode => base64_encode(input),
+            CmdLine => cmd_line(input),
+            CompressWhitespace => compress_whitespace(input),
+            CssDecode => css_decode(input),
+            EscapeSeqDecode => escape_seq_decode(input),
+            HexDecode => hex_decode(input),
+            HexEncode => hex_encode(input),
+            HtmlEntityDecode => html_entity_decode(input),
+            JsDecode => js_decode(input),
+            Length => input.len().to_string().into_bytes(),
+            Lowercase => input.to_ascii_lowercase(),
+            NormalisePath | NormalizePath => normalise_path(input),
+            NormalisePathWin | NormalizePathWin => {
+                let input: Vec<u8> = input
+                    .iter()
+                    .map(|&b| if b == b'\\' { b'/' } else { b })
+                    .collect();
+                normalise_path(&input)
+            }
+            ParityEven7Bit => input.iter().map(|&b| with_parity(b, true)).collect(),
+            ParityOdd7Bit => input.iter().map(|&b| with_parity(b, false)).collect(),
+            ParityZero7Bit => input.iter().map(|&b| b & 0x7f).collect(),
+            RemoveComments => remove_comments(input),
+            RemoveCommentsChar => remove_comments_char(input),
+            RemoveNulls => input.iter().copied().filter(|&b| b != 0).collect(),
+            RemoveWhitespace => input
+                .iter()
+                .copied()
+                .filter(|&b| !is_space(b) && b != 0xa0)
+                .collect(),
+            ReplaceComments => replace_comments(input),
+            ReplaceNulls => input
+                .iter()
+                .map(|&b| if b == 0 { b' ' } else { b })
+                .collect(),
+            Md5 => md5::Md5::digest(input).to_vec(),
+            Sha1 => sha1::Sha1::digest(input).to_vec(),
+            SqlHexDecode => sql_hex_decode(input),
+            Trim => trim_end(trim_start(input)).to_vec(),
+            TrimLeft => trim_start(input).to_vec(),
+            TrimRight => trim_end(input).to_vec(),
+            Uppercase => input.to_ascii_uppercase(),
+            UrlDecode => url_decode(input, false),
+            UrlDecodeUni => url_decode(input, true),
+            UrlEncode => url_encode(input),
+            Utf8ToUnicode => utf8_to_unicode(input),
+            // removed from the pipeline when it's built
+            None => input.to_vec(),
+        }
+    }
+}
+
+/// A value produced by the transformation pipeline, to be checked against the operator.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Step {
+    pub value: Vec<u8>,
+    /// The number of transformations applied to produce the value, 0 for the original value.
+    pub index: usize,
+    /// The transformation that produced the value, or `None` for the original value.
+    pub transform: Option<TransformType>,
+}
+
+/// The transformations of a rule, in the order they're applied.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Pipeline {
+    transforms: Vec<TransformType>,
+}
+
+impl Pipeline {
+    /// Adds the transformation named by a `t:` action to the end of the pipeline, or clears the
+    /// pipeline for `t:none`.
+    pub fn push(&mut self, name: &str) -> Result<(), Error> {
+        let transform =
+            TransformType::from_name(name).ok_or_else(|| Error::Unknown(name.to_string()))?;
+        match transform {
+            TransformType::None => self.transforms.clear(),
+            transform => self.transforms.push(transform),
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn transforms(&self) -> &[TransformType] {
+        &self.transforms
+    }
+
+    /// Applies every transformation to a value.
+    pub fn apply(&self, value: &[u8]) -> Vec<u8> {
+        self.transforms
+            .iter()
+            .fold(value.to_vec(), |value, transform| transform.apply(&value))
+    }
+
+    /// The values to check against the operator. Without `multi_match` that's only the fully
+    /// transformed value. With `multi_match` it's the original value followed by the result of
+    /// every transformation that changed the value.
+    pub fn steps(&self, value: &[u8], multi_match: bool) -> Vec<Step> {
+        if !multi_match {
+            return vec![Step {
+                value: self.apply(value),
+                index: self.transforms.len(),
+                transform: self.transforms.last().copied(),
+            }];
+        }
+
+        let mut steps = vec![Step {
+            value: value.to_vec(),
+            index: 0,
+            transform: None,
+        }];
+        let mut current = value.to_vec();
+        for (index, transform) in self.transforms.iter().enumerate() {
+            let next = transform.apply(&current);
+            if next != current {
+                steps.push(Step {
+                    value: next.clone(),
+                    index: index + 1,
+                    transform: Some(*transform),
+                });
+                current = next;
+            }
+        }
+        steps
+    }
+}
+
+/// Whitespace as defined by C's `isspace`.
+#[inline]
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+#[inline]
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Decodes two hex digits at the start of `input`.
+fn hex_byte(input: &[u8]) -> Option<u8> {
+    match input {
+        [high, low, ..] => Some(hex_value(*high)? << 4 | hex_value(*low)?),
+        _ => None,
+    }
+}
+
+/// Reduces a Unicode code point to a single byte the way ModSecurity does: full-width ASCII
+/// (U+FF01 to U+FF5E) becomes the character it represents, anything else keeps its low byte.
+fn unicode_to_byte(code: u32) -> u8 {
+    if (0xff01..=0xff5e).contains(&code) {
+        (code - 0xff00 + 0x20) as u8
+    } else {
+        code as u8
+    }
+}
+
+/// Parses up to `max` hex digits at the start of `input`, returning the value and the number of
+/// digits.
+fn hex_prefix(input: &[u8], max: usize) -> (u32, usize) {
+    input
+        .iter()
+        .take(max)
+        .map_while(|&b| hex_value(b))
+        .fold((0, 0), |(value, len), digit| {
+            (value << 4 | u32::from(digit), len + 1)
+        })
+}
+
+/// Parses up to three octal digits at the start of `input`, returning the value and the number
+/// of digits. Digits that would take the value past 0xff aren't consumed.
+fn octal_prefix(input: &[u8]) -> (u32, usize) {
+    let mut value = 0;
+    let mut len = 0;
+    for &b in input.iter().take(3) {
+        if !(b'0'..=b'7').contains(&b) || value * 8 + u32::from(b - b'0') > 0xff {
+            break;
+        }
+        value = value * 8 + u32::from(b - b'0');
+        len += 1;
+    }
+    (value, len)
+}
+
+fn trim_start(input: &[u8]) -> &[u8] {
+    let start = input
+        .iter()
+        .position(|&b| !is_space(b))
+        .unwrap_or(input.len());
+    &input[start..]
+}
+
+fn trim_end(input: &[u8]) -> &[u8] {
+    let end = input
+        .iter()
+        .rposition(|&b| !is_space(b))
+        .map_or(0, |i| i + 1);
+    &input[..end]
+}
+
+fn with_parity(b: u8, even: bool) -> u8 {
+    let b = b & 0x7f;
+    let odd_bits = b.count_ones() % 2 == 1;
+    if odd_bits == even {
+        b | 0x80
+    } else {
+        b
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                output.push(b'=');
+            }
+        }
+    }
+    output
+}
+
+/// Decodes base64, stopping at the first invalid character (or padding), or skipping invalid
+/// characters if `lenient` is set.
+pub(crate) fn base64_decode(input: &[u8], lenient: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &b in input {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ if lenient && b != b'=' => continue,
+            _ => break,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            output.push((bits >> count) as u8);
+        }
+    }
+    output
+}
+
+fn hex_encode(input: &[u8]) -> Vec<u8> {
+    input
+        .iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+/// Decodes pairs of hex digits, skipping pairs that aren't valid hex.
+fn hex_decode(input: &[u8]) -> Vec<u8> {
+    input.chunks_exact(2).filter_map(hex_byte).collect()
+}
+
+fn sql_hex_decode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let is_prefix = input[i] == b'0' && matches!(input.get(i + 1), Some(b'x' | b'X'));
+        if is_prefix && input.get(i + 2..).and_then(hex_byte).is_some() {
+            i += 2;
+            while let Some(b) = input.get(i..).and_then(hex_byte) {
+                output.push(b);
+                i += 2;
+            }
+        } else {
+            output.push(input[i]);
+            i += 1;
+        }
+    }
+    output
+}
+
+fn url_decode(input: &[u8], unicode: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' if unicode && matches!(input.get(i + 1), Some(b'u' | b'U')) => {
+                match hex_prefix(&input[i + 2..], 4) {
+                    (code, 4) => {
+                        output.push(unicode_to_byte(code));
+                        i += 6;
+                    }
+                    _ => {
+                        output.push(b'%');
+                        i += 1;
+                    }
+                }
+            }
+            b'%' => match input.get(i + 1..).and_then(hex_byte) {
+                Some(b) => {
+                    output.push(b);
+                    i += 3;
+                }
+                None => {
+                    output.push(b'%');
+                    i += 1;
+                }
+            },
+            b'+' => {
+                output.push(b' ');
+                i += 1;
+            }
+            b => {
+                output.push(b);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+fn url_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    for &b in input {
+        match b {
+            b' ' => output.push(b'+'),
+            b'*' | b'0'..=b'9' | b'A'..=b'Z' | b'a'..=b'z' => output.push(b),
+            b => output.extend(format!("%{:02x}", b).into_bytes()),
+        }
+    }
+    output
+}
+
+/// Parses digits like C's `strtol`, which saturates on overflow, keeping the low byte.
+fn parse_number(digits: &[u8], radix: u32) -> u8 {
+    digits
+        .iter()
+        .try_fold(0i64, |value, &d| {
+            let digit = (d as char).to_digit(radix)?;
+            value.checked_mul(radix.into())?.checked_add(digit.into())
+        })
+        .map_or(0xff, |value| value as u8)
+}
+
+fn html_entity_decode(input: &[u8]) -> Vec<u8> {
+    const NAMED: [(&[u8], u8); 5] = [
+        (b"quot", b'"'),
+        (b"amp", b'&'),
+        (b"lt", b'<'),
+        (b"gt", b'>'),
+        (b"nbsp", 0xa0),
+    ];
+
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'&' {
+            output.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        let rest = &input[i + 1..];
+        let decoded = match rest {
+            [b'#', b'x' | b'X', digits @ ..] => {
+                let len = digits.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+                (len > 0).then(|| (parse_number(&digits[..len], 16), 2 + len))
+            }
+            [b'#', digits @ ..] => {
+                let len = digits.iter().take_while(|b| b.is_ascii_digit()).count();
+                (len > 0).then(|| (parse_number(&digits[..len], 10), 1 + len))
+            }
+            _ => {
+                // the whole name has to match, so `&ltx;` is left as it is
+                let len = rest
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphanumeric())
+                    .count();
+                NAMED
+                    .iter()
+                    .find(|(name, _)| rest[..len].eq_ignore_ascii_case(name))
+                    .map(|(_, b)| (*b, len))
+            }
+        };
+
+        match decoded {
+            Some((b, len)) => {
+                output.push(b);
+                i += 1 + len;
+                // the terminating semicolon is optional
+                if input.get(i) == Some(&b';') {
+                    i += 1;
+                }
+            }
+            None => {
+                output.push(b'&');
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+fn js_decode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'\\' || i + 1 == input.len() {
+            output.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        let rest = &input[i + 1..];
+        let (b, len) = match rest[0] {
+            b'u' if hex_prefix(&rest[1..], 4).1 == 4 => {
+                (unicode_to_byte(hex_prefix(&rest[1..], 4).0), 5)
+            }
+            b'x' if hex_byte(&rest[1..]).is_some() => (hex_byte(&rest[1..]).unwrap(), 3),
+            b'0'..=b'7' => {
+                let (code, len) = octal_prefix(rest);
+                (code as u8, len)
+            }
+            b'a' => (0x07, 1),
+            b'b' => (0x08, 1),
+            b'f' => (0x0c, 1),
+            b'n' => (b'\n', 1),
+            b'r' => (b'\r', 1),
+            b't' => (b'\t', 1),
+            b'v' => (0x0b, 1),
+            b => (b, 1),
+        };
+        output.push(b);
+        i += 1 + len;
+    }
+    output
+}
+
+fn css_decode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'\\' {
+            output.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        let rest = &input[i + 1..];
+        match hex_prefix(rest, 6) {
+            (code, len) if len > 0 => {
+                output.push(unicode_to_byte(code));
+                i += 1 + len;
+                // a single whitespace character can terminate the escape
+                if input.get(i).copied().is_some_and(is_space) {
+                    i += 1;
+                }
+            }
+            _ => match rest.first() {
+                // an escaped newline is a line continuation
+                Some(b'\n') => i += 2,
+                Some(&b) => {
+                    output.push(b);
+                    i += 2;
+                }
+                None => i += 1,
+            },
+        }
+    }
+    output
+}
+
+fn escape_seq_decode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'\\' || i + 1 == input.len() {
+            output.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        let rest = &input[i + 1..];
+        let decoded = match rest[0] {
+            b'a' => Some((0x07, 1)),
+            b'b' => Some((0x08, 1)),
+            b'f' => Some((0x0c, 1)),
+            b'n' => Some((b'\n', 1)),
+            b'r' => Some((b'\r', 1)),
+            b't' => Some((b'\t', 1)),
+            b'v' => Some((0x0b, 1)),
+            b @ (b'\\' | b'?' | b'\'' | b'"') => Some((b, 1)),
+            b'x' | b'X' => hex_byte(&rest[1..]).map(|b| (b, 3)),
+            // unlike jsDecode, three digits are used even if the value doesn't fit in a byte
+            b'0'..=b'7' => {
+                let len = rest
+                    .iter()
+                    .take(3)
+                    .take_while(|b| (b'0'..=b'7').contains(b))
+                    .count();
+                Some((parse_number(&rest[..len], 8), len))
+            }
+            _ => None,
+        };
+        match decoded {
+            Some((b, len)) => {
+                output.push(b);
+                i += 1 + len;
+            }
+            // the backslash of an unknown escape is dropped, like in ModSecurity
+            None => {
+                output.push(rest[0]);
+                i += 2;
+            }
+        }
+    }
+    output
+}
+
+fn compress_whitespace(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut in_space = false;
+    for &b in input {
+        if is_space(b) || b == 0xa0 {
+            if !in_space {
+                output.push(b' ');
+            }
+            in_space = true;
+        } else {
+            output.push(b);
+            in_space = false;
+        }
+    }
+    output
+}
+
+fn cmd_line(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut in_space = false;
+    for &b in input {
+        match b {
+            b'\\' | b'"' | b'\'' | b'^' => {}
+            b' ' | b',' | b';' | b'\t' | b'\r' | b'\n' => {
+                if !in_space {
+                    output.push(b' ');
+                    in_space = true;
+                }
+            }
+            b'/' | b'(' => {
+                if in_space {
+                    output.pop();
+                }
+                output.push(b);
+                in_space = false;
+            }
+            b => {
+                output.push(b.to_ascii_lowercase());
+                in_space = false;
+            }
+        }
+    }
+    output
+}
+
+/// Finds the end of a comment that starts at `input[0]`, returning the index after it, or `None`
+/// if `input` doesn't start with a comment.
+fn comment_end(input: &[u8]) -> Option<usize> {
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|w| w == needle)
+    }
+
+    if input.starts_with(b"/*") {
+        Some(find(&input[2..], b"*/").map_or(input.len(), |end| end + 4))
+    } else if input.starts_with(b"<!--") {
+        Some(find(&input[4..], b"-->").map_or(input.len(), |end| end + 7))
+    } else if input.starts_with(b"--") || input.starts_with(b"#") {
+        Some(input.len())
+    } else {
+        None
+    }
+}
+
+fn remove_comments(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match comment_end(&input[i..]) {
+            Some(len) => i += len,
+            None => {
+                output.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+fn replace_comments(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i..].starts_with(b"/*") {
+            output.push(b' ');
+            i += comment_end(&input[i..]).unwrap_or(input.len());
+        } else {
+            output.push(input[i]);
+            i += 1;
+        }
+    }
+    output
+}
+
+fn remove_comments_char(input: &[u8]) -> Vec<u8> {
+    const MARKERS: [&[u8]; 6] = [b"<!--", b"-->", b"/*", b"*/", b"--", b"#"];
+
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match MARKERS.iter().find(|marker| input[i..].starts_with(marker)) {
+            Some(marker) => i += marker.len(),
+            None => {
+                output.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+/// Removes `.` segments and empty segments, and resolves `..` segments, keeping any leading or
+/// trailing slash.
+fn normalise_path(input: &[u8]) -> Vec<u8> {
+    let absolute = input.starts_with(b"/");
+    let trailing = input.ends_with(b"/") || input.ends_with(b"/.") || input.ends_with(b"/..");
+
+    let mut segments: Vec<&[u8]> = vec![];
+    for segment in input.split(|&b| b == b'/') {
+        match segment {
+            b"" | b"." => {}
+            b".." => match segments.last() {
+                Some(last) if *last != b".." => {
+                    segments.pop();
+                }
+                // a relative path can go above its starting point, but the root can't
+                _ if !absolute => segments.push(segment),
+                _ => {}
+            },
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut output = Vec::with_capacity(input.len());
+    if absolute {
+        output.push(b'/');
+    }
+    output.extend(segments.join(&b'/'));
+    if trailing && !segments.is_empty() {
+        output.push(b'/');
+    }
+    output
+}
+
+fn utf8_to_unicode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    for chunk in input.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_ascii() {
+                output.push(c as u8);
+            } else {
+                output.extend(format!("%u{:04x}", c as u32).into_bytes());
+            }
+        }
+        output.extend(chunk.invalid());
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransformType::*;
+
+    #[track_caller]
+    fn check(transform: TransformType, cases: &[(&[u8], &[u8])]) {
+        for (input, expected) in cases {
+            assert_eq!(
+                transform.apply(input),
+                *expected,
+                "t:{} of {:?}",
+                transform.name(),
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn base64() {
+        check(
+            Base64Encode,
+            &[
+                (b"", b""),
+                (b"f", b"Zg=="),
+                (b"fo", b"Zm8="),
+                (b"foo", b"Zm9v"),
+            ],
+        );
+        check(
+            Base64Decode,
+            &[
+                (b"Zm9vYmFy", b"foobar"),
+                (b"Zm9vYg==", b"foob"),
+                (b"Zm9v YmFy", b"foo"),
+                (b"", b""),
+            ],
+        );
+        check(
+            Base64DecodeExt,
+            &[(b"Zm9v YmFy", b"foobar"), (b"Zm9v.Y\nmFy", b"foobar")],
+        );
+    }
+
+    #[test]
+    fn hex() {
+        check(HexEncode, &[(b"a\x00\xff", b"6100ff")]);
+        check(
+            HexDecode,
+            &[
+                (b"6100FF", b"a\x00\xff"),
+                (b"61zz62", b"ab"),
+                (b"616", b"a"),
+            ],
+        );
+        check(
+            SqlHexDecode,
+            &[
+                (b"0x414243", b"ABC"),
+                (b"select 0x61,0X62", b"select a,b"),
+                (b"0x", b"0x"),
+                (b"0xzz", b"0xzz"),
+                (b"0x4142z", b"ABz"),
+            ],
+        );
+    }
+
+    #[test]
+    fn url() {
+        check(
+            UrlDecode,
+            &[
+                (b"a%20b+c", b"a b c"),
+                (b"%41%4a%4A", b"AJJ"),
+                (b"100%", b"100%"),
+                (b"%zz%4", b"%zz%4"),
+                (b"%u0041", b"%u0041"),
+            ],
+        );
+        check(
+            UrlDecodeUni,
+            &[
+                (b"%u0041%20", b"A "),
+                (b"%uff1c", b"<"),
+                (b"%U263a", b":"),
+                (b"%u12", b"%u12"),
+            ],
+        );
+        check(UrlEncode, &[(b"a b*c/d\xff", b"a+b*c%2fd%ff")]);
+        check(
+            Utf8ToUnicode,
+            &[("aé€".as_bytes(), b"a%u00e9%u20ac"), (b"a\xffb", b"a\xffb")],
+        );
+    }
+
+    #[test]
+    fn html_entity_decode() {
+        check(
+            HtmlEntityDecode,
+            &[
+                (b"&lt;script&gt;", b"<script>"),
+                (b"&QUOT;&amp&nbsp;", b"\"&\xa0"),
+                (b"&#65;&#x42;&#X43", b"ABC"),
+                (b"&#x4142;", b"B"),
+                (b"&#99999999999999999999;", b"\xff"),
+                (b"&ltx; &foo; &#; &#x; &", b"&ltx; &foo; &#; &#x; &"),
+                (b"a&#65b", b"aAb"),
+            ],
+        );
+    }
+
+    #[test]
+    fn js_decode() {
+        check(
+            JsDecode,
+            &[
+                (b"\\x41\\u0042\\103", b"ABC"),
+                (b"\\uff1cscript\\uFF1E", b"<script>"),
+                (b"\\u12345", b"45"),
+                (b"\\477", b"'7"),
+                (b"\\n\\t\\'\\\"\\\\", b"\n\t'\"\\"),
+                (b"\\xzz\\u12", b"xzzu12"),
+                (b"trailing\\", b"trailing\\"),
+            ],
+        );
+    }
+
+    #[test]
+    fn css_decode() {
+        check(
+            CssDecode,
+            &[
+                (b"\\41\\42 C", b"ABC"),
+                (b"\\000041", b"A"),
+                (b"\\ff1c", b"<"),
+                (b"\\1ff1c", b"\x1c"),
+                (b"\\a", b"\n"),
+                (b"\\z\\\"", b"z\""),
+                (b"a\\\nb", b"ab"),
+                (b"end\\", b"end"),
+            ],
+        );
+    }
+
+    #[test]
+    fn escape_seq_decode() {
+        check(
+            EscapeSeqDecode,
+            &[
+                (b"\\a\\b\\f\\n\\r\\t\\v", b"\x07\x08\x0c\n\r\t\x0b"),
+                (b"\\\\\\?\\'\\\"", b"\\?'\""),
+                (b"\\x41\\X42\\103", b"ABC"),
+                (b"\\477", b"?"),
+                (b"\\z\\xzz", b"zxzz"),
+                (b"trailing\\", b"trailing\\"),
+            ],
+        );
+    }
+
+    #[test]
+    fn cmd_line() {
+        check(
+            CmdLine,
+            &[
+                (
+                    b"C:\\WINDOWS\\system32\\CMD.exe /c dir",
+                    b"c:windowssystem32cmd.exe/c dir",
+                ),
+                (b"w\"h'o^a\\mi", b"whoami"),
+                (b"cat  ,;\t/etc/passwd", b"cat/etc/passwd"),
+                (b"echo ( x ) ;; ls", b"echo( x ) ls"),
+                (b"  ls", b" ls"),
+            ],
+        );
+    }
+
+    #[test]
+    fn whitespace() {
+        check(
+            CompressWhitespace,
+            &[(b"a \t\r\n\x0b\x0c\xa0b  c", b"a b c")],
+        );
+        check(RemoveWhitespace, &[(b" a\tb\nc\xa0d ", b"abcd")]);
+        check(Trim, &[(b" \t a b \n", b"a b"), (b"   ", b"")]);
+        check(TrimLeft, &[(b" \t a b \n", b"a b \n")]);
+        check(TrimRight, &[(b" \t a b \n", b" \t a b")]);
+    }
+
+    #[test]
+    fn nulls() {
+        check(RemoveNulls, &[(b"a\x00b\x00", b"ab")]);
+        check(ReplaceNulls, &[(b"a\x00b\x00", b"a b ")]);
+    }
+
+    #[test]
+    fn case_and_length() {
+        check(Lowercase, &[(b"AbC\xc9", b"abc\xc9")]);
+        check(Uppercase, &[(b"aBc\xe9", b"ABC\xe9")]);
+        check(Length, &[(b"", b"0"), ("é".as_bytes(), b"2")]);
+    }
+
+    #[test]
+    fn hashes() {
+        check(
+            Md5,
+            &[(
+                b"abc",
+                b"\x90\x01\x50\x98\x3c\xd2\x4f\xb0\xd6\x96\x3f\x7d\x28\xe1\x7f\x72",
+            )],
+        );
+        check(
+            Sha1,
+            &[(
+                b"abc",
+                b"\xa9\x99\x3e\x36\x47\x06\x81\x6a\xba\x3e\x25\x71\x78\x50\xc2\x6c\x9c\xd0\xd8\x9d",
+            )],
+        );
+    }
+
+    #[test]
+    fn parity() {
+        check(ParityEven7Bit, &[(b"ABC", b"AB\xc3"), (b"\xc1", b"A")]);
+        check(ParityOdd7Bit, &[(b"ABC", b"\xc1\xc2C")]);
+        check(ParityZero7Bit, &[(b"\xc1\xc2", b"AB")]);
+    }
+
+    #[test]
+    fn comments() {
+        check(
+            RemoveComments,
+            &[
+                (b"un/**/ion sel/*x*/ect", b"union select"),
+                (b"a<!-- x -->b", b"ab"),
+                (b"1 or 1=1-- x", b"1 or 1=1"),
+                (b"1#x", b"1"),
+                (b"a/* unterminated", b"a"),
+            ],
+        );
+        check(
+            RemoveCommentsChar,
+            &[(b"un/**/ion <!--a--> #b c--", b"union a b c")],
+        );
+        check(
+            ReplaceComments,
+            &[
+                (b"un/**/ion/*x*/select", b"un ion select"),
+                (b"a/* unterminated", b"a "),
+                (b"a--b#c", b"a--b#c"),
+            ],
+        );
+    }
+
+    #[test]
+    fn paths() {
+        check(
+            NormalisePath,
+            &[
+                (b"/a/./b/../c", b"/a/c"),
+                (b"/a//b/", b"/a/b/"),
+                (b"/../../etc/passwd", b"/etc/passwd"),
+                (b"../a/../../b", b"../../b"),
+                (b"/a/b/..", b"/a/"),
+                (b"/", b"/"),
+            ],
+        );
+        check(
+            NormalisePathWin,
+            &[(b"C:\\a\\..\\b\\.\\c", b"C:/b/c"), (b"\\..\\x", b"/x")],
+        );
+        assert_eq!(NormalizePath.apply(b"/a/../b"), b"/b");
+        assert_eq!(NormalizePathWin.apply(b"\\a\\..\\b"), b"/b");
+    }
+
+    #[test]
+    fn pipeline_applies_transforms_in_order() {
+        let mut pipeline = Pipeline::default();
+        for name in ["urlDecode", "lowercase", "none", "lowercase", "urlDecode"] {
+            pipeline.push(name).unwrap();
+        }
+        assert_eq!(pipeline.transforms(), [Lowercase, UrlDecode]);
+        assert_eq!(pipeline.apply(b"%41B"), b"Ab");
+        assert!(matches!(pipeline.push("nope"), Err(Error::Unknown(_))));
+    }
+
+    #[test]
+    fn multi_match_steps_skip_unchanged_values() {
+        let mut pipeline = Pipeline::default();
+        for name in ["lowercase", "urlDecode", "removeWhitespace"] {
+            pipeline.push(name).unwrap();
+        }
+
+        let steps = pipeline.steps(b"a%20b", true);
+        let values: Vec<_> = steps
+            .iter()
+            .map(|s| (s.value.as_slice(), s.index))
+            .collect();
+        assert_eq!(values, [(&b"a%20b"[..], 0), (b"a b", 2), (b"ab", 3)]);
+        assert_eq!(steps[1].transform, Some(UrlDecode));
+
+        let steps = pipeline.steps(b"a%20b", false);
+        assert_eq!(steps.len(), 1);
+        assert_eq!((steps[0].value.as_slice(), steps[0].index), (&b"ab"[..], 3));
+    }
+}
@@ -1,15 +1,19 @@
 use super::SourceType;
 use crate::engine::RequestExt;
 use http::Request;
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ValueData<'a> {
-    Named { name: &'a [u8], value: &'a [u8] },
-    Value(&'a [u8]),
+    Named {
+        name: Cow<'a, [u8]>,
+        value: Cow<'a, [u8]>,
+    },
+    Value(Cow<'a, [u8]>),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Value<'a> {
     source: SourceType,
     data: ValueData<'a>,
@@ -20,7 +24,7 @@ impl<'a> Value<'a> {
     pub fn new(source: SourceType, value: &'a [u8]) -> Self {
         Self {
             source,
-            data: ValueData::Value(value),
+            data: ValueData::Value(Cow::Borrowed(value)),
         }
     }
 
@@ -28,7 +32,34 @@ impl<'a> Value<'a> {
     pub fn new_named(source: SourceType, name: &'a [u8], value: &'a [u8]) -> Self {
         Self {
             source,
-            data: ValueData::Named { name, value },
+            data: ValueData::Named {
+                name: Cow::Borrowed(name),
+                value: Cow::Borrowed(value),
+            },
+        }
+    }
+
+    /// Creates a value that isn't borrowed from the request, e.g. a count or a computed size.
+    #[inline]
+    pub fn owned(source: SourceType, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            source,
+            data: ValueData::Value(Cow::Owned(value.into())),
+        }
+    }
+
+    #[inline]
+    pub fn owned_named(
+        source: SourceType,
+        name: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self {
+            source,
+            data: ValueData::Named {
+                name: Cow::Owned(name.into()),
+                value: Cow::Owned(value.into()),
+            },
         }
     }
 
@@ -48,16 +79,16 @@ impl<'a> Value<'a> {
     }
 
     #[inline]
-    pub fn value(&self) -> &'a [u8] {
-        match self.data {
+    pub fn value(&self) -> &[u8] {
+        match &self.data {
             ValueData::Value(value) => value,
             ValueData::Named { value, .. } => value,
         }
     }
 
     #[inline]
-    pub fn name(&self) -> Option<&'a [u8]> {
-        match self.data {
+    pub fn name(&self) -> Option<&[u8]> {
+        match &self.data {
             ValueData::Value(_) => None,
             ValueData::Named { name, .. } => Some(name),
         }
@@ -67,7 +98,10 @@ impl<'a> Value<'a> {
     pub fn into_name(self, source: SourceType) -> Option<Value<'a>> {
         match self.data {
             ValueData::Value(_) => None,
-            ValueData::Named { name, .. } => Some(Value::new(source, name)),
+            ValueData::Named { name, .. } => Some(Value {
+                source,
+                data: ValueData::Value(name),
+            }),
         }
     }
 }
@@ -75,7 +109,7 @@ impl<'a> Value<'a> {
 impl<'a> Display for Value<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut debug = f.debug_tuple(self.source.name());
-        match self.data {
+        match &self.data {
             ValueData::Named { name, value } => debug
                 .field(&String::from_utf8_lossy(name))
                 .field(&String::from_utf8_lossy(value))
@@ -0,0 +1,613 @@
+//! Structural XML validation for the `@validateDTD` and `@validateSchema` operators.
+//!
+//! Full DTD/XSD validation needs libxml2, so this implements the subset that matters for
+//! rejecting unexpected documents: the root element and every element in the document must be
+//! declared, elements may only contain the child elements their declaration mentions (the order
+//! and number of occurrences aren't checked), attributes must be declared, and required
+//! attributes must be present. Datatypes and content model ordering are not validated.
+//!
+//! Elements declared locally in an XML Schema are only valid in their parent, so elements with
+//! the same name can have different declarations in different parents.
+
+use roxmltree::{Document, Node, NodeId, ParsingOptions};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read {path}, {source}")]
+    IoError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("invalid schema {path}, {reason}")]
+    InvalidSchema { path: String, reason: String },
+}
+
+/// What an element is allowed to contain.
+#[derive(Debug, Clone, Default)]
+struct ElementDecl {
+    /// Allowed child elements, with the index of their declaration if it's local to this
+    /// element, or `None` if it's global. `None` if any globally declared element is allowed.
+    children: Option<HashMap<String, Option<usize>>>,
+    /// Whether non-whitespace text content is allowed.
+    text: bool,
+    attributes: HashSet<String>,
+    required_attributes: HashSet<String>,
+}
+
+/// A set of element declarations loaded from a DTD or XML Schema.
+#[derive(Debug, Clone, Default)]
+pub struct XmlValidator {
+    /// Every element declaration, global or local.
+    declarations: Vec<ElementDecl>,
+    /// The global element declarations, by name.
+    elements: HashMap<String, usize>,
+    /// Elements allowed as the document root, `None` if any declared element is allowed.
+    roots: Option<HashSet<String>>,
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    std::fs::read_to_string(path).map_err(|source| Error::IoError {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+/// Splits a DTD declaration body into tokens, keeping parenthesized groups and quoted strings
+/// together.
+fn dtd_tokens(body: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in body.char_indices() {
+        match (c, quote) {
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                start.get_or_insert(i);
+            }
+            ('(', None) => {
+                depth += 1;
+                start.get_or_insert(i);
+            }
+            (')', None) => depth -= 1,
+            (c, None) if c.is_whitespace() && depth == 0 => {
+                if let Some(s) = start.take() {
+                    tokens.push(&body[s..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&body[s..]);
+    }
+    tokens
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Parses a document, allowing a DTD, since documents checked by `@validateDTD` usually
+/// declare one.
+fn parse(xml: &str) -> Result<Document<'_>, roxmltree::Error> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    Document::parse_with_options(xml, options)
+}
+
+impl XmlValidator {
+    /// Adds an empty declaration, returning its index.
+    fn declare(&mut self) -> usize {
+        self.declarations.push(ElementDecl::default());
+        self.declarations.len() - 1
+    }
+
+    /// The global declaration of an element.
+    fn global(&self, name: &str) -> Option<&ElementDecl> {
+        self.elements
+            .get(name)
+            .map(|&index| &self.declarations[index])
+    }
+
+    /// Loads element and attribute declarations from a DTD file.
+    pub fn from_dtd(path: &Path) -> Result<Self, Error> {
+        Self::parse_dtd(&read(path)?).map_err(|reason| Error::InvalidSchema {
+            path: path.display().to_string(),
+            reason,
+        })
+    }
+
+    fn parse_dtd(dtd: &str) -> Result<Self, String> {
+        let mut validator = Self::default();
+        let mut attlists = vec![];
+
+        let mut rest = dtd;
+        while let Some(start) = rest.find("<!") {
+            rest = &rest[start + 2..];
+            if let Some(comment) = rest.strip_prefix("--") {
+                rest = comment.split_once("-->").map(|(_, r)| r).unwrap_or("");
+                continue;
+            }
+            let end = rest
+                .find('>')
+                .ok_or_else(|| "unterminated declaration".to_string())?;
+            let declaration = &rest[..end];
+            rest = &rest[end + 1..];
+
+            if let Some(body) = declaration.strip_prefix("ELEMENT") {
+                let tokens = dtd_tokens(body);
+                let (name, model) = match tokens.as_slice() {
+                    [name, model @ ..] if !model.is_empty() => (*name, model.join(" ")),
+                    _ => return Err(format!("invalid element declaration {}", body.trim())),
+                };
+                let decl = match model.as_str() {
+                    "EMPTY" => ElementDecl {
+                        children: Some(HashMap::new()),
+                        ..Default::default()
+                    },
+                    "ANY" => ElementDecl {
+                        children: None,
+                        text: true,
+                        ..Default::default()
+                    },
+                    model => ElementDecl {
+                        children: Some(
+                            model
+                                .split(|c| !is_name_char(c))
+                                .filter(|n| !n.is_empty())
+                                .map(|n| (n.into(), None))
+                                .collect(),
+                        ),
+                        text: model.contains("#PCDATA"),
+                        ..Default::default()
+                    },
+                };
+                let index = validator.declare();
+                validator.declarations[index] = decl;
+                validator.elements.insert(name.into(), index);
+            } else if let Some(body) = declaration.strip_prefix("ATTLIST") {
+                attlists.push(body);
+            }
+        }
+
+        for body in attlists {
+            let tokens = dtd_tokens(body);
+            let (element, mut attributes) = match tokens.split_first() {
+                Some((element, attributes)) => (*element, attributes),
+                None => return Err("empty attribute list declaration".into()),
+            };
+            let index = match validator.elements.get(element) {
+                Some(&index) => index,
+                None => {
+                    let index = validator.declare();
+                    validator.elements.insert(element.to_string(), index);
+                    index
+                }
+            };
+            let decl = &mut validator.declarations[index];
+
+            // each attribute is `name type default`, where default may be `#FIXED "value"`
+            while let [name, _type, default, tail @ ..] = attributes {
+                decl.attributes.insert(name.to_string());
+                if *default == "#REQUIRED" {
+                    decl.required_attributes.insert(name.to_string());
+                }
+                attributes = match (*default, tail) {
+                    ("#FIXED", [_value, rest @ ..]) => rest,
+                    _ => tail,
+                };
+            }
+        }
+
+        if validator.elements.is_empty() {
+            return Err("no element declarations".into());
+        }
+        Ok(validator)
+    }
+
+    /// Loads element and attribute declarations from an XML Schema (XSD) file.
+    pub fn from_schema(path: &Path) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::InvalidSchema {
+            path: path.display().to_string(),
+            reason,
+        };
+        let content = read(path)?;
+        let document = parse(&content).map_err(|e| invalid(e.to_string()))?;
+        Self::parse_schema(&document).map_err(invalid)
+    }
+
+    fn parse_schema(document: &Document) -> Result<Self, String> {
+        let schema = document.root_element();
+        if schema.tag_name().name() != "schema" {
+            return Err("root element is not a schema".into());
+        }
+
+        let is_xs = |node: &Node, name: &str| node.is_element() && node.tag_name().name() == name;
+
+        // named complex types, so element declarations can refer to them
+        let types: HashMap<&str, Node> = schema
+            .children()
+            .filter(|n| is_xs(n, "complexType"))
+            .filter_map(|n| Some((n.attribute("name")?, n)))
+            .collect();
+
+        let mut validator = Self {
+            roots: Some(HashSet::new()),
+            ..Default::default()
+        };
+
+        // the declaration of each element node, global or local, and those still to be read
+        let mut declared: HashMap<NodeId, usize> = HashMap::new();
+        let mut pending = vec![];
+        for element in schema.children().filter(|n| is_xs(n, "element")) {
+            if let Some(name) = element.attribute("name") {
+                let index = validator.declare();
+                declared.insert(element.id(), index);
+                validator.elements.insert(name.into(), index);
+                validator.roots.as_mut().unwrap().insert(name.into());
+                pending.push(element);
+            }
+        }
+
+        while let Some(element) = pending.pop() {
+            // the content model is either inline or a reference to a named complex type
+            let mut bodies = vec![element];
+            if let Some(ty) = element.attribute("type") {
+                if let Some(ty) = types.get(local_name(ty)) {
+                    bodies.push(*ty);
+                }
+            }
+
+            let mut decl = ElementDecl::default();
+            let mut children = HashMap::new();
+            let mut any = false;
+            let mut complex = false;
+            // walk the content model without descending into nested element declarations
+            let mut stack: Vec<Node> = bodies.iter().flat_map(|b| b.children()).collect();
+            while let Some(node) = stack.pop() {
+                if !node.is_element() {
+                    continue;
+                }
+                match node.tag_name().name() {
+                    "element" => {
+                        if let Some(child) = node.attribute("name") {
+                            // a local declaration, which is read once even if its type is
+                            // used by several elements, or by itself
+                            let index = match declared.get(&node.id()) {
+                                Some(&index) => index,
+                                None => {
+                                    let index = validator.declare();
+                                    declared.insert(node.id(), index);
+                                    pending.push(node);
+                                    index
+                                }
+                            };
+                            children.insert(local_name(child).to_string(), Some(index));
+                        } else if let Some(child) = node.attribute("ref") {
+                            children.insert(local_name(child).to_string(), None);
+                        }
+                    }
+                    "attribute" => {
+                        if let Some(attr) = node.attribute("name").or(node.attribute("ref")) {
+                            decl.attributes.insert(local_name(attr).into());
+                            if node.attribute("use") == Some("required") {
+                                decl.required_attributes.insert(local_name(attr).into());
+                            }
+                        }
+                    }
+                    "any" => any = true,
+                    "anyAttribute" => {}
+                    other => {
+                        if other == "complexType" {
+                            complex = true;
+                            decl.text |= node.attribute("mixed") == Some("true");
+                        }
+                        decl.text |= other == "simpleContent";
+                        stack.extend(node.children());
+                    }
+                }
+            }
+
+            decl.children = if any { None } else { Some(children) };
+            // elements without a complex type have simple (text only) content
+            decl.text |= any
+                || (!complex
+                    && element
+                        .attribute("type")
+                        .is_none_or(|ty| !types.contains_key(local_name(ty))));
+            validator.declarations[declared[&element.id()]] = decl;
+        }
+
+        if validator.elements.is_empty() {
+            return Err("no element declarations".into());
+        }
+        Ok(validator)
+    }
+
+    /// Validates a document, returning a description of the first problem found.
+    pub fn validate(&self, xml: &[u8]) -> Result<(), String> {
+        let xml = std::str::from_utf8(xml).map_err(|e| e.to_string())?;
+        let document = parse(xml).map_err(|e| e.to_string())?;
+        let root = document.root_element();
+        let root_name = root.tag_name().name();
+
+        if let Some(roots) = &self.roots {
+            if !roots.contains(root_name) {
+                return Err(format!("root element {} is not allowed", root_name));
+            }
+        }
+
+        let undeclared = |name: &str| format!("element {} is not declared", name);
+        let root_decl = self
+            .global(root_name)
+            .ok_or_else(|| undeclared(root_name))?;
+        // each element is checked against the declaration that applies in its parent
+        let mut stack = vec![(root, root_decl)];
+        while let Some((node, decl)) = stack.pop() {
+            let name = node.tag_name().name();
+            for child in node.children() {
+                if child.is_element() {
+                    let child_name = child.tag_name().name();
+                    let child_decl = match decl.children.as_ref().map(|c| c.get(child_name)) {
+                        Some(None) => {
+                            return Err(format!(
+                                "element {} is not allowed in {}",
+                                child_name, name
+                            ))
+                        }
+                        Some(Some(&Some(index))) => Some(&self.declarations[index]),
+                        Some(Some(None)) | None => self.global(child_name),
+                    };
+                    let child_decl = child_decl.ok_or_else(|| undeclared(child_name))?;
+                    stack.push((child, child_decl));
+                } else if child.is_text()
+                    && !decl.text
+                    && !child.text().unwrap_or_default().trim().is_empty()
+                {
+                    return Err(format!("element {} may not contain text", name));
+                }
+            }
+
+            for attribute in node.attributes() {
+                let attr = attribute.name();
+                // namespace declarations and schema instance attributes are always allowed
+                let special = attr == "xmlns"
+                    || attribute.namespace() == Some("http://www.w3.org/2001/XMLSchema-instance");
+                if !special && !decl.attributes.contains(attr) {
+                    return Err(format!("attribute {} is not declared for {}", attr, name));
+                }
+            }
+            for required in &decl.required_attributes {
+                if node.attribute(required.as_str()).is_none() {
+                    return Err(format!(
+                        "required attribute {} missing from {}",
+                        required, name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DTD: &str = r#"<!-- a purchase order -->
+<!ELEMENT order (customer, item+, note?)>
+<!ATTLIST order
+    id ID #REQUIRED
+    currency CDATA #FIXED "EUR"
+    priority (low|high) "low">
+<!ELEMENT customer (#PCDATA)>
+<!ELEMENT item (name, quantity)>
+<!ATTLIST item sku CDATA #REQUIRED>
+<!ELEMENT name (#PCDATA)>
+<!ELEMENT quantity (#PCDATA)>
+<!ELEMENT note ANY>
+"#;
+
+    const XSD: &str = r#"<?xml version="1.0"?>
+<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+  <xs:element name="order">
+    <xs:complexType>
+      <xs:sequence>
+        <xs:element name="customer">
+          <xs:complexType>
+            <xs:sequence>
+              <xs:element name="name">
+                <xs:complexType>
+                  <xs:attribute name="first" type="xs:string" use="required"/>
+                  <xs:attribute name="last" type="xs:string"/>
+                </xs:complexType>
+              </xs:element>
+            </xs:sequence>
+          </xs:complexType>
+        </xs:element>
+        <xs:element name="item" type="itemType" maxOccurs="unbounded"/>
+        <xs:element ref="note" minOccurs="0"/>
+      </xs:sequence>
+      <xs:attribute name="id" type="xs:string" use="required"/>
+    </xs:complexType>
+  </xs:element>
+  <xs:element name="note" type="xs:string"/>
+  <xs:complexType name="itemType">
+    <xs:sequence>
+      <xs:element name="name" type="xs:string"/>
+      <xs:element name="parts" minOccurs="0">
+        <xs:complexType>
+          <xs:sequence>
+            <xs:element name="item" type="itemType" maxOccurs="unbounded"/>
+          </xs:sequence>
+        </xs:complexType>
+      </xs:element>
+    </xs:sequence>
+  </xs:complexType>
+</xs:schema>
+"#;
+
+    fn schema() -> XmlValidator {
+        XmlValidator::parse_schema(&parse(XSD).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn validates_against_a_dtd() {
+        let validator = XmlValidator::parse_dtd(DTD).unwrap();
+        let valid = r#"<?xml version="1.0"?>
+<!DOCTYPE order SYSTEM "order.dtd">
+<order id="o1" priority="high">
+  <customer>Alice</customer>
+  <item sku="a1"><name>Pen</name><quantity>2</quantity></item>
+  <note><name>anything declared</name></note>
+</order>"#;
+        assert_eq!(validator.validate(valid.as_bytes()), Ok(()));
+
+        for (xml, error) in [
+            ("<item/>", "required attribute sku missing from item"),
+            (
+                "<order><customer/></order>",
+                "required attribute id missing",
+            ),
+            (
+                "<order id='1'><name/></order>",
+                "element name is not allowed in order",
+            ),
+            (
+                "<order id='1' extra='x'/>",
+                "attribute extra is not declared for order",
+            ),
+            (
+                "<order id='1'>text</order>",
+                "element order may not contain text",
+            ),
+            (
+                "<order id='1'><note><b/></note></order>",
+                "element b is not declared",
+            ),
+            ("<invoice/>", "element invoice is not declared"),
+            ("<order id='1'>", "never closed"),
+        ] {
+            let result = validator.validate(xml.as_bytes());
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{}: {:?}",
+                xml,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn validates_documents_with_an_internal_dtd() {
+        let validator = XmlValidator::parse_dtd(DTD).unwrap();
+        let xml = r#"<!DOCTYPE order [<!ENTITY who "Alice">]>
+<order id="o1"><customer>&who;</customer></order>"#;
+        assert_eq!(validator.validate(xml.as_bytes()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_dtds() {
+        assert!(XmlValidator::parse_dtd("<!-- nothing -->").is_err());
+        assert!(XmlValidator::parse_dtd("<!ELEMENT order").is_err());
+        assert!(XmlValidator::parse_dtd("<!ELEMENT order>").is_err());
+    }
+
+    #[test]
+    fn validates_against_a_schema() {
+        let valid = r#"<?xml version="1.0"?>
+<order id="o1" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"
+       xsi:noNamespaceSchemaLocation="order.xsd">
+  <customer><name first="Alice"/></customer>
+  <item>
+    <name>Kit</name>
+    <parts><item><name>Pen</name></item></parts>
+  </item>
+  <note>Deliver after 5pm</note>
+</order>"#;
+        assert_eq!(schema().validate(valid.as_bytes()), Ok(()));
+
+        for (xml, error) in [
+            (
+                "<item><name>Pen</name></item>",
+                "root element item is not allowed",
+            ),
+            (
+                "<order><customer/></order>",
+                "required attribute id missing",
+            ),
+            (
+                "<order id='1'><note><b/></note></order>",
+                "element b is not allowed in note",
+            ),
+            (
+                "<order id='1'><customer/><parts/></order>",
+                "element parts is not allowed in order",
+            ),
+            (
+                "<order id='1'>text</order>",
+                "element order may not contain text",
+            ),
+        ] {
+            let result = schema().validate(xml.as_bytes());
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{}: {:?}",
+                xml,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn scopes_local_declarations_by_their_parent() {
+        // the customer's name has a required attribute and no text, the item's name is text
+        let customer_text =
+            "<order id='1'><customer><name first='A'>Alice</name></customer></order>";
+        assert_eq!(
+            schema().validate(customer_text.as_bytes()),
+            Err("element name may not contain text".into())
+        );
+        let item_without_first = "<order id='1'><item><name>Pen</name></item></order>";
+        assert_eq!(schema().validate(item_without_first.as_bytes()), Ok(()));
+        let item_attribute = "<order id='1'><item><name first='A'>Pen</name></item></order>";
+        assert_eq!(
+            schema().validate(item_attribute.as_bytes()),
+            Err("attribute first is not declared for name".into())
+        );
+
+        // local declarations aren't global: a name can't be the root or appear elsewhere
+        let root = schema().validate(b"<name first='A'/>");
+        assert_eq!(root, Err("root element name is not allowed".into()));
+
+        // the recursive item type applies to nested items too
+        let nested = "<order id='1'><item><name>Kit</name><parts><item><name>Pen</name>\
+            <parts><item><oops/></item></parts></item></parts></item></order>";
+        assert_eq!(
+            schema().validate(nested.as_bytes()),
+            Err("element oops is not allowed in item".into())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let not_a_schema = parse("<root/>").unwrap();
+        assert!(XmlValidator::parse_schema(&not_a_schema).is_err());
+        let empty = parse(r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema"/>"#).unwrap();
+        assert!(XmlValidator::parse_schema(&empty).is_err());
+    }
+}
@@ -0,0 +1,559 @@
+//! A cookie jar for the stages of a test, so that cookies set by a stage with `save_cookie` are
+//! sent by the stages after it, as described in [RFC 6265](https://httpwg.org/specs/rfc6265.html).
+
+use http::header::{COOKIE, HOST, SET_COOKIE};
+use http::{HeaderValue, Request, Response};
+use std::cmp::Reverse;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A cookie from a `Set-Cookie` header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    /// The domain the cookie is also sent to subdomains of, without a leading dot.
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    /// When the cookie expires, from `Max-Age` if present, or from `Expires` otherwise.
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+/// Finds the month from the first three letters of its name.
+fn parse_month(token: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let prefix = token.get(..3)?.to_ascii_lowercase();
+    MONTHS
+        .iter()
+        .position(|&m| m == prefix)
+        .map(|i| i as u64 + 1)
+}
+
+fn parse_time(token: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = token.splitn(3, ':').map(|part| {
+        let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+        matches!(digits.len(), 1 | 2).then(|| digits.parse().ok())?
+    });
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+/// Days since the epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).saturating_sub(719_468)
+}
+
+/// Parses a cookie date with the algorithm of RFC 6265 section 5.1.1, which accepts the date
+/// formats used in practice, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_date(date: &str) -> Option<SystemTime> {
+    let is_delimiter = |c: char| matches!(c, '\t' | ' '..='/' | ';'..='@' | '['..='`' | '{'..='~');
+    let (mut time, mut day, mut month, mut year) = (None, None, None, None);
+    for token in date.split(is_delimiter).filter(|token| !token.is_empty()) {
+        let digits = token.chars().take_while(char::is_ascii_digit).count();
+        if time.is_none() {
+            if let Some(parsed) = parse_time(token) {
+                time = Some(parsed);
+                continue;
+            }
+        }
+        if day.is_none() && matches!(digits, 1 | 2) {
+            day = token[..digits].parse().ok();
+        } else if month.is_none() && parse_month(token).is_some() {
+            month = parse_month(token);
+        } else if year.is_none() && matches!(digits, 2..=4) {
+            year = token[..digits].parse::<u64>().ok();
+        }
+    }
+
+    let year = match year? {
+        year @ 70..=99 => year + 1900,
+        year @ 0..=69 => year + 2000,
+        year => year,
+    };
+    let (hour, minute, second) = time?;
+    let (day, month) = (day?, month?);
+    if year < 1601 || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+impl SetCookie {
+    /// Parses a `Set-Cookie` header value, ignoring unknown or invalid attributes. Returns
+    /// `None` if there's no `name=value` pair, in which case the header must be ignored.
+    pub fn parse(header: &str, now: SystemTime) -> Option<Self> {
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Self {
+            name: name.into(),
+            value: value.trim().into(),
+            domain: None,
+            path: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "expires" => cookie.expires = parse_date(value).or(cookie.expires),
+                "max-age" => {
+                    max_age = match value.parse::<i64>() {
+                        // a zero or negative max age expires the cookie immediately
+                        Ok(seconds) if seconds <= 0 => Some(UNIX_EPOCH),
+                        Ok(seconds) => Some(now + Duration::from_secs(seconds.unsigned_abs())),
+                        Err(_) => max_age,
+                    }
+                }
+                "domain" if !value.is_empty() => {
+                    cookie.domain = Some(value.trim_start_matches('.').to_ascii_lowercase())
+                }
+                "path" if value.starts_with('/') => cookie.path = Some(value.into()),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+        cookie.expires = max_age.or(cookie.expires);
+        Some(cookie)
+    }
+
+    #[inline]
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// A cookie stored in the jar, along with where it can be sent.
+#[derive(Debug, Clone)]
+struct StoredCookie {
+    cookie: SetCookie,
+    domain: String,
+    /// Whether the cookie is only sent to the exact domain that set it.
+    host_only: bool,
+    path: String,
+}
+
+/// The default path of a cookie, the directory of the request path.
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(0) | None => "/".into(),
+        Some(end) => request_path[..end].into(),
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || request_path
+            .strip_prefix(cookie_path)
+            .is_some_and(|rest| cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+/// The host a request is sent to, from its `Host` header or its URI, without the port.
+pub fn request_host<T>(request: &Request<T>) -> String {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
+/// The cookies received by the stages of a test.
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<StoredCookie>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// Stores a cookie set in the response to a request for `host` and `request_path`,
+    /// replacing any cookie with the same name, domain and path. Cookies for another domain are
+    /// rejected, and expired cookies remove the cookie they replace.
+    pub fn store(&mut self, cookie: SetCookie, host: &str, request_path: &str, now: SystemTime) {
+        let (domain, host_only) = match &cookie.domain {
+            Some(domain) if domain_matches(host, domain) => (domain.clone(), false),
+            Some(_) => return,
+            None => (host.to_ascii_lowercase(), true),
+        };
+        let path = cookie
+            .path
+            .clone()
+            .unwrap_or_else(|| default_path(request_path));
+
+        self.cookies.retain(|stored| {
+            !(stored.cookie.name == cookie.name && stored.domain == domain && stored.path == path)
+        });
+        if !cookie.is_expired(now) {
+            self.cookies.push(StoredCookie {
+                cookie,
+                domain,
+                host_only,
+                path,
+            });
+        }
+    }
+
+    /// Stores every cookie set by a response.
+    pub fn store_response<T>(
+        &mut self,
+        response: &Response<T>,
+        host: &str,
+        request_path: &str,
+        now: SystemTime,
+    ) {
+        for header in response.headers().get_all(SET_COOKIE) {
+            let header = String::from_utf8_lossy(header.as_bytes());
+            if let Some(cookie) = SetCookie::parse(&header, now) {
+                self.store(cookie, host, request_path, now);
+            }
+        }
+    }
+
+    /// The `name=value` pairs of the unexpired cookies to send with a request, with longer
+    /// paths first, as user agents send them.
+    pub fn cookies_for<T>(&self, request: &Request<T>, now: SystemTime) -> Vec<String> {
+        let host = request_host(request);
+        let secure = request.uri().scheme_str() == Some("https");
+        let mut cookies: Vec<&StoredCookie> = self
+            .cookies
+            .iter()
+            .filter(|stored| match stored.host_only {
+                true => host == stored.domain,
+                false => domain_matches(&host, &stored.domain),
+            })
+            .filter(|stored| path_matches(request.uri().path(), &stored.path))
+            .filter(|stored| secure || !stored.cookie.secure)
+            .filter(|stored| !stored.cookie.is_expired(now))
+            .collect();
+        cookies.sort_by_key(|stored| Reverse(stored.path.len()));
+        cookies
+            .into_iter()
+            .map(|stored| format!("{}={}", stored.cookie.name, stored.cookie.value))
+            .collect()
+    }
+
+    /// Adds the jar's cookies to a request, in a single `Cookie` header. Cookies that are set
+    /// explicitly in the request's own `Cookie` headers are kept, and take precedence over
+    /// cookies with the same name from the jar.
+    pub fn add_to<T>(&self, request: &mut Request<T>, now: SystemTime) {
+        let cookies = self.cookies_for(request, now);
+        if cookies.is_empty() {
+            return;
+        }
+
+        let explicit: Vec<String> = request
+            .headers()
+            .get_all(COOKIE)
+            .iter()
+            .flat_map(|header| {
+                let header = String::from_utf8_lossy(header.as_bytes()).into_owned();
+                header
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|pair| !pair.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let name = |pair: &str| {
+            pair.split('=')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        let explicit_names: Vec<String> = explicit.iter().map(|pair| name(pair)).collect();
+
+        let mut pairs = explicit;
+        pairs.extend(
+            cookies
+                .into_iter()
+                .filter(|pair| !explicit_names.contains(&name(pair))),
+        );
+        if let Ok(value) = HeaderValue::from_str(&pairs.join("; ")) {
+            request.headers_mut().insert(COOKIE, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sun, 06 Nov 1994 08:49:37 GMT
+    const NOV_1994: u64 = 784_111_777;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn request(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    fn jar_with(header: &str, host: &str, request_path: &str) -> CookieJar {
+        let mut jar = CookieJar::new();
+        let cookie = SetCookie::parse(header, at(NOV_1994)).unwrap();
+        jar.store(cookie, host, request_path, at(NOV_1994));
+        jar
+    }
+
+    #[test]
+    fn parses_dates() {
+        for date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "06 Nov 1994 08:49:37",
+        ] {
+            assert_eq!(parse_date(date), Some(at(NOV_1994)), "{}", date);
+        }
+    }
+
+    #[test]
+    fn parses_two_digit_years() {
+        assert_eq!(parse_date("Thu, 01-Jan-70 00:00:00 GMT"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_date("Sat, 01-Jan-00 00:00:00 GMT"),
+            Some(at(946_684_800))
+        );
+        assert_eq!(
+            parse_date("Sat, 31-Dec-69 23:59:59 GMT"),
+            Some(at(3_155_759_999))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994",
+            "Sun, 06 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:49:37 GMT",
+            "Sun, 06 Nov 1600 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn parses_set_cookie() {
+        let cookie = SetCookie::parse(
+            " id = a3fWa ; Domain=.Example.com; Path=/docs; Secure; HttpOnly; Unknown=1",
+            at(NOV_1994),
+        )
+        .unwrap();
+        assert_eq!(
+            cookie,
+            SetCookie {
+                name: "id".into(),
+                value: "a3fWa".into(),
+                domain: Some("example.com".into()),
+                path: Some("/docs".into()),
+                expires: None,
+                secure: true,
+                http_only: true,
+            }
+        );
+        assert_eq!(SetCookie::parse("no-pair", at(NOV_1994)), None);
+        assert_eq!(SetCookie::parse("=value", at(NOV_1994)), None);
+    }
+
+    #[test]
+    fn max_age_takes_precedence_over_expires() {
+        let now = at(NOV_1994);
+        let in_a_minute = Some(at(NOV_1994 + 60));
+        for header in [
+            "a=b; Max-Age=60; Expires=Thu, 01 Jan 2037 00:00:00 GMT",
+            "a=b; Expires=Thu, 01 Jan 2037 00:00:00 GMT; Max-Age=60",
+        ] {
+            let cookie = SetCookie::parse(header, now).unwrap();
+            assert_eq!(cookie.expires, in_a_minute, "{}", header);
+        }
+
+        let cookie = SetCookie::parse("a=b; Max-Age=0; Expires=Thu, 01 Jan 2037 00:00:00 GMT", now);
+        assert!(cookie.unwrap().is_expired(now));
+        let cookie = SetCookie::parse(
+            "a=b; Max-Age=soon; Expires=Sun, 06 Nov 1994 08:50:37 GMT",
+            now,
+        );
+        assert_eq!(cookie.unwrap().expires, in_a_minute);
+    }
+
+    #[test]
+    fn expired_cookies_remove_stored_cookies() {
+        let mut jar = jar_with("a=b", "example.com", "/");
+        let now = at(NOV_1994);
+        jar.store(
+            SetCookie::parse("a=c; Max-Age=-1", now).unwrap(),
+            "example.com",
+            "/",
+            now,
+        );
+        assert!(jar.is_empty());
+    }
+
+    #[test]
+    fn matches_domains() {
+        let jar = jar_with("a=b; Domain=.example.com", "www.example.com", "/");
+        let now = at(NOV_1994);
+        assert_eq!(
+            jar.cookies_for(&request("http://example.com/"), now),
+            ["a=b"]
+        );
+        assert_eq!(
+            jar.cookies_for(&request("http://api.example.com/"), now),
+            ["a=b"]
+        );
+        assert!(jar
+            .cookies_for(&request("http://badexample.com/"), now)
+            .is_empty());
+        assert!(jar
+            .cookies_for(&request("http://example.org/"), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn host_only_cookies_are_not_sent_to_subdomains() {
+        let jar = jar_with("a=b", "example.com", "/");
+        let now = at(NOV_1994);
+        assert_eq!(
+            jar.cookies_for(&request("http://EXAMPLE.com:8080/"), now),
+            ["a=b"]
+        );
+        assert!(jar
+            .cookies_for(&request("http://www.example.com/"), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_cookies_for_other_domains() {
+        assert!(jar_with("a=b; Domain=example.org", "example.com", "/").is_empty());
+        assert!(jar_with("a=b; Domain=www.example.com", "example.com", "/").is_empty());
+    }
+
+    #[test]
+    fn matches_paths() {
+        assert_eq!(default_path(""), "/");
+        assert_eq!(default_path("/login"), "/");
+        assert_eq!(default_path("/docs/login"), "/docs");
+
+        let jar = jar_with("a=b", "example.com", "/docs/login");
+        let now = at(NOV_1994);
+        assert_eq!(
+            jar.cookies_for(&request("http://example.com/docs"), now),
+            ["a=b"]
+        );
+        assert_eq!(
+            jar.cookies_for(&request("http://example.com/docs/x"), now),
+            ["a=b"]
+        );
+        assert!(jar
+            .cookies_for(&request("http://example.com/docsx"), now)
+            .is_empty());
+        assert!(jar
+            .cookies_for(&request("http://example.com/"), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn sends_longer_paths_first() {
+        let now = at(NOV_1994);
+        let mut jar = jar_with("a=root; Path=/", "example.com", "/");
+        jar.store(
+            SetCookie::parse("a=docs; Path=/docs", now).unwrap(),
+            "example.com",
+            "/",
+            now,
+        );
+        assert_eq!(
+            jar.cookies_for(&request("http://example.com/docs/x"), now),
+            ["a=docs", "a=root"]
+        );
+    }
+
+    #[test]
+    fn sends_secure_cookies_over_https_only() {
+        let jar = jar_with("a=b; Secure", "example.com", "/");
+        let now = at(NOV_1994);
+        assert!(jar
+            .cookies_for(&request("http://example.com/"), now)
+            .is_empty());
+        assert_eq!(
+            jar.cookies_for(&request("https://example.com/"), now),
+            ["a=b"]
+        );
+    }
+
+    #[test]
+    fn explicit_cookie_headers_take_precedence() {
+        let now = at(NOV_1994);
+        let mut jar = jar_with("session=jar", "example.com", "/");
+        jar.store(
+            SetCookie::parse("theme=dark", now).unwrap(),
+            "example.com",
+            "/",
+            now,
+        );
+
+        let mut request = Request::get("http://example.com/")
+            .header(COOKIE, "session=explicit; lang=en")
+            .header(COOKIE, "other=1")
+            .body(())
+            .unwrap();
+        jar.add_to(&mut request, now);
+        let cookies: Vec<_> = request.headers().get_all(COOKIE).iter().collect();
+        assert_eq!(cookies, ["session=explicit; lang=en; other=1; theme=dark"]);
+    }
+
+    #[test]
+    fn leaves_requests_without_matching_cookies_alone() {
+        let jar = jar_with("a=b", "example.com", "/");
+        let mut request = Request::get("http://example.org/")
+            .header(COOKIE, "x=1")
+            .header(COOKIE, "y=2")
+            .body(())
+            .unwrap();
+        jar.add_to(&mut request, at(NOV_1994));
+        assert_eq!(request.headers().get_all(COOKIE).iter().count(), 2);
+    }
+}
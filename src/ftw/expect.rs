@@ -0,0 +1,437 @@
+//! Checks the outcome of a stage against its expected [`Output`].
+
+use super::{Error, Output, OutputStatus};
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+
+/// Longest excerpt of a response or log shown in a failure.
+const MAX_EXCERPT: usize = 200;
+
+/// What happened when a stage's request was sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Outcome<'a> {
+    /// The response status and body, or the error that prevented a response.
+    pub response: Result<(u16, &'a [u8]), &'a str>,
+    /// The WAF log lines produced by the request, if the log is available.
+    pub log: Option<&'a str>,
+}
+
+/// An expected output field that didn't match.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mismatch {
+    /// The name of the field, as in the test file.
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:\n  - expected: {}\n  + actual:   {}",
+            self.field, self.expected, self.actual
+        )
+    }
+}
+
+/// The result of checking an outcome against the expectations.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Assessment {
+    pub mismatches: Vec<Mismatch>,
+    /// Fields that couldn't be checked, e.g. `log_contains` when the log isn't available.
+    pub unchecked: Vec<&'static str>,
+}
+
+impl Assessment {
+    #[inline]
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty() && self.unchecked.is_empty()
+    }
+}
+
+impl Display for Assessment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, mismatch) in self.mismatches.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+/// The expected output of a stage, with its regexes compiled.
+#[derive(Debug, Clone)]
+pub struct Expectations {
+    /// Any of these statuses is accepted, and any status is accepted if it's empty.
+    status: Vec<u32>,
+    response_contains: Option<Regex>,
+    /// Regexes that must match the log, with the fields they're from.
+    log_contains: Vec<(&'static str, Regex)>,
+    /// Regexes that mustn't match the log, with the fields they're from.
+    no_log_contains: Vec<(&'static str, Regex)>,
+    /// The only rules allowed in the log, for isolated stages.
+    isolated: Option<Vec<u32>>,
+    expect_error: bool,
+}
+
+fn compile(field: &'static str, pattern: &Option<String>) -> Result<Option<Regex>, Error> {
+    pattern
+        .as_deref()
+        .map(|pattern| Regex::new(pattern).map_err(|source| Error::InvalidRegex { field, source }))
+        .transpose()
+}
+
+/// Matches the log lines of a rule, e.g. `[id "920100"]`.
+fn rule_regex(id: u32) -> Regex {
+    Regex::new(&format!(r#"\[id "{}"\]"#, id)).unwrap()
+}
+
+/// The ids of the rules in the log, in order and without duplicates.
+fn logged_rules(log: &str) -> Vec<u32> {
+    lazy_static::lazy_static! {
+        static ref RULE_ID: Regex = Regex::new(r#"\[id "(\d+)"\]"#).unwrap();
+    }
+
+    let mut ids = vec![];
+    for id in RULE_ID.captures_iter(log).filter_map(|c| c[1].parse().ok()) {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Shortens text to show in a failure, keeping it on one line.
+fn excerpt(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let mut excerpt: String = text.chars().take(MAX_EXCERPT).collect();
+    if excerpt.len() < text.len() {
+        excerpt.push_str("...");
+    }
+    format!("{:?}", excerpt)
+}
+
+impl Expectations {
+    pub fn compile(output: &Output) -> Result<Self, Error> {
+        let log = output.log.clone().unwrap_or_default();
+        let mut log_contains = vec![];
+        for (field, pattern) in [
+            ("log_contains", &output.log_contains),
+            ("log.match_regex", &log.match_regex),
+        ] {
+            log_contains.extend(compile(field, pattern)?.map(|regex| (field, regex)));
+        }
+        let mut no_log_contains = vec![];
+        for (field, pattern) in [
+            ("no_log_contains", &output.no_log_contains),
+            ("log.no_match_regex", &log.no_match_regex),
+        ] {
+            no_log_contains.extend(compile(field, pattern)?.map(|regex| (field, regex)));
+        }
+        log_contains.extend(
+            log.expect_ids
+                .iter()
+                .map(|&id| ("log.expect_ids", rule_regex(id))),
+        );
+        no_log_contains.extend(
+            log.no_expect_ids
+                .iter()
+                .map(|&id| ("log.no_expect_ids", rule_regex(id))),
+        );
+
+        Ok(Self {
+            status: match &output.status {
+                Some(OutputStatus::Status(status)) => vec![*status],
+                Some(OutputStatus::Any(statuses)) => statuses.clone(),
+                None => vec![],
+            },
+            response_contains: compile("response_contains", &output.response_contains)?,
+            log_contains,
+            no_log_contains,
+            isolated: output.isolated.then_some(log.expect_ids),
+            expect_error: output.expect_error,
+        })
+    }
+
+    /// Returns true if checking the expectations needs the WAF log.
+    #[inline]
+    pub fn needs_log(&self) -> bool {
+        !self.log_contains.is_empty() || !self.no_log_contains.is_empty() || self.isolated.is_some()
+    }
+
+    pub fn check(&self, outcome: &Outcome) -> Assessment {
+        let mut assessment = Assessment::default();
+        let mut mismatch = |field, expected: String, actual: String| {
+            assessment.mismatches.push(Mismatch {
+                field,
+                expected,
+                actual,
+            })
+        };
+
+        match (outcome.response, self.expect_error) {
+            // the response can't be checked when there isn't one, but the log still can
+            (Err(_), true) => {}
+            (Err(error), false) => mismatch("expect_error", "a response".into(), error.into()),
+            (Ok((status, _)), true) => mismatch(
+                "expect_error",
+                "an error".into(),
+                format!("a response with status {}", status),
+            ),
+            (Ok((status, body)), false) => {
+                if !self.status.is_empty() && !self.status.contains(&u32::from(status)) {
+                    let expected = match self.status.as_slice() {
+                        [expected] => expected.to_string(),
+                        expected => format!("one of {:?}", expected),
+                    };
+                    mismatch("status", expected, status.to_string());
+                }
+                if let Some(regex) = &self.response_contains {
+                    if !regex.is_match(&String::from_utf8_lossy(body)) {
+                        mismatch("response_contains", format!("/{}/", regex), excerpt(body));
+                    }
+                }
+            }
+        }
+
+        match outcome.log {
+            Some(log) => {
+                for (field, regex) in &self.log_contains {
+                    if !regex.is_match(log) {
+                        mismatch(field, format!("/{}/", regex), excerpt(log.as_bytes()));
+                    }
+                }
+                for (field, regex) in &self.no_log_contains {
+                    if let Some(found) = regex.find(log) {
+                        let line = log[..found.start()].rfind('\n').map_or(0, |i| i + 1);
+                        let end = log[found.end()..]
+                            .find('\n')
+                            .map_or(log.len(), |i| found.end() + i);
+                        mismatch(
+                            field,
+                            format!("no match for /{}/", regex),
+                            excerpt(&log.as_bytes()[line..end]),
+                        );
+                    }
+                }
+                if let Some(expected) = &self.isolated {
+                    let logged = logged_rules(log);
+                    if logged.iter().any(|id| !expected.contains(id)) {
+                        mismatch(
+                            "isolated",
+                            format!("only rules {:?}", expected),
+                            format!("rules {:?}", logged),
+                        );
+                    }
+                }
+            }
+            None => {
+                let fields = self.log_contains.iter().chain(&self.no_log_contains);
+                for (field, _) in fields {
+                    if !assessment.unchecked.contains(field) {
+                        assessment.unchecked.push(field);
+                    }
+                }
+                if self.isolated.is_some() {
+                    assessment.unchecked.push("isolated");
+                }
+            }
+        }
+        assessment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expectations(output: &str) -> Expectations {
+        let output: Output = serde_yaml::from_str(output).unwrap();
+        Expectations::compile(&output).unwrap()
+    }
+
+    fn response(status: u16, body: &str) -> Outcome<'_> {
+        Outcome {
+            response: Ok((status, body.as_bytes())),
+            log: None,
+        }
+    }
+
+    fn fields(assessment: &Assessment) -> Vec<&'static str> {
+        assessment.mismatches.iter().map(|m| m.field).collect()
+    }
+
+    #[test]
+    fn checks_a_single_status() {
+        let expectations = expectations("status: 403");
+        assert!(expectations.check(&response(403, "")).passed());
+
+        let assessment = expectations.check(&response(200, ""));
+        assert_eq!(
+            assessment.mismatches,
+            [Mismatch {
+                field: "status",
+                expected: "403".into(),
+                actual: "200".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn checks_a_status_list() {
+        let expectations = expectations("status: [200, 404]");
+        assert!(expectations.check(&response(200, "")).passed());
+        assert!(expectations.check(&response(404, "")).passed());
+
+        let assessment = expectations.check(&response(403, ""));
+        assert_eq!(assessment.mismatches[0].expected, "one of [200, 404]");
+        assert_eq!(assessment.mismatches[0].actual, "403");
+    }
+
+    #[test]
+    fn accepts_any_status_without_one() {
+        let expectations = expectations("response_contains: denied");
+        assert!(expectations.check(&response(403, "access denied")).passed());
+
+        let assessment = expectations.check(&response(200, "welcome"));
+        assert_eq!(fields(&assessment), ["response_contains"]);
+        assert_eq!(assessment.mismatches[0].expected, "/denied/");
+        assert_eq!(assessment.mismatches[0].actual, "\"welcome\"");
+    }
+
+    #[test]
+    fn expects_an_error() {
+        let expectations = expectations("expect_error: true\nstatus: 200");
+        let error = Outcome {
+            response: Err("connection reset"),
+            log: None,
+        };
+        assert!(expectations.check(&error).passed());
+
+        // the status isn't checked when an error was expected
+        let assessment = expectations.check(&response(200, ""));
+        assert_eq!(
+            assessment.mismatches,
+            [Mismatch {
+                field: "expect_error",
+                expected: "an error".into(),
+                actual: "a response with status 200".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn expects_a_response() {
+        let expectations = expectations("status: 200");
+        let error = Outcome {
+            response: Err("connection reset"),
+            log: None,
+        };
+        let assessment = expectations.check(&error);
+        assert_eq!(
+            assessment.mismatches,
+            [Mismatch {
+                field: "expect_error",
+                expected: "a response".into(),
+                actual: "connection reset".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_log_fields_as_unchecked_without_the_log() {
+        let expectations = expectations(
+            r#"
+status: 200
+log_contains: id "1"
+no_log_contains: id "2"
+log:
+  expect_ids: [3]
+  no_expect_ids: [4]
+isolated: true
+"#,
+        );
+        assert!(expectations.needs_log());
+
+        let assessment = expectations.check(&response(200, ""));
+        assert!(assessment.mismatches.is_empty());
+        assert!(!assessment.passed());
+        assert_eq!(
+            assessment.unchecked,
+            [
+                "log_contains",
+                "log.expect_ids",
+                "no_log_contains",
+                "log.no_expect_ids",
+                "isolated"
+            ]
+        );
+
+        // the log is still checked when there's no response
+        let outcome = Outcome {
+            response: Err("timed out"),
+            log: Some(r#"[id "3"]"#),
+        };
+        let assessment = expectations.check(&outcome);
+        assert!(assessment.unchecked.is_empty());
+        assert_eq!(fields(&assessment), ["expect_error", "log_contains"]);
+    }
+
+    #[test]
+    fn checks_the_log() {
+        let expectations = expectations(
+            r#"
+log:
+  expect_ids: [1]
+  no_expect_ids: [2]
+"#,
+        );
+        let outcome = Outcome {
+            response: Ok((200, b"")),
+            log: Some("[id \"1\"] first\n[id \"2\"] second\n[id \"3\"] third"),
+        };
+        let assessment = expectations.check(&outcome);
+        assert_eq!(
+            assessment.mismatches,
+            [Mismatch {
+                field: "log.no_expect_ids",
+                expected: r#"no match for /\[id "2"\]/"#.into(),
+                actual: r#""[id \"2\"] second""#.into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn isolated_stages_only_allow_the_expected_rules() {
+        let expectations = expectations("isolated: true\nlog:\n  expect_ids: [1]");
+        let outcome = |log| Outcome {
+            response: Ok((200, b"")),
+            log: Some(log),
+        };
+        assert!(expectations
+            .check(&outcome(r#"[id "1"] [id "1"]"#))
+            .passed());
+
+        let assessment = expectations.check(&outcome(r#"[id "1"] [id "5"]"#));
+        assert_eq!(fields(&assessment), ["isolated"]);
+        assert_eq!(assessment.mismatches[0].actual, "rules [1, 5]");
+    }
+
+    #[test]
+    fn formats_assessments() {
+        let expectations = expectations("status: 403\nresponse_contains: denied");
+        let assessment = expectations.check(&response(200, &"x".repeat(300)));
+        let excerpt = format!("\"{}...\"", "x".repeat(MAX_EXCERPT));
+        assert_eq!(
+            assessment.to_string(),
+            format!(
+                "status:\n  - expected: 403\n  + actual:   200\nresponse_contains:\n  - expected: /denied/\n  + actual:   {}",
+                excerpt
+            )
+        );
+        assert_eq!(Assessment::default().to_string(), "");
+    }
+}
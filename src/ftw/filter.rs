@@ -0,0 +1,657 @@
+//! Selects which FTW tests to run, and applies go-ftw style overrides to the ones that run.
+//!
+//! Tests that aren't selected aren't dropped: they're reported as skipped, with the reason they
+//! were skipped, so that a run still accounts for every test.
+
+use super::runner::{Status, TestResult};
+use super::{Data, Error, File, HttpVersion, Input, Test};
+use crate::syntax::{Action, ActionType, CRSEntry};
+use glob::Pattern;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The input fields that are forced for every stage, as in go-ftw's `testoverride.input`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputOverride {
+    pub dest_addr: Option<String>,
+    pub port: Option<u32>,
+    pub protocol: Option<String>,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub version: Option<HttpVersion>,
+    /// Headers that are set in every stage, replacing headers with the same name in any case.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub data: Option<Data>,
+    pub save_cookie: Option<bool>,
+    pub stop_magic: Option<bool>,
+    pub autocomplete_headers: Option<bool>,
+    pub encoded_request: Option<String>,
+    pub raw_request: Option<String>,
+    /// Replaces an empty `Host` header with the stage's `dest_addr`.
+    #[serde(default)]
+    pub override_empty_host_header: bool,
+}
+
+impl InputOverride {
+    pub fn apply(&self, input: &mut Input) {
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+
+        set(&mut input.dest_addr, &self.dest_addr);
+        set(&mut input.port, &self.port);
+        set(&mut input.protocol, &self.protocol);
+        set(&mut input.method, &self.method);
+        set(&mut input.uri, &self.uri);
+        set(&mut input.version, &self.version);
+        set(&mut input.save_cookie, &self.save_cookie);
+        set(&mut input.stop_magic, &self.stop_magic);
+        set(&mut input.data, &self.data);
+        if self.autocomplete_headers.is_some() {
+            input.autocomplete_headers = self.autocomplete_headers;
+        }
+        if self.encoded_request.is_some() {
+            input.encoded_request = self.encoded_request.clone();
+        }
+        if self.raw_request.is_some() {
+            input.raw_request = self.raw_request.clone();
+        }
+
+        for (name, value) in &self.headers {
+            input
+                .headers
+                .retain(|header, _| !header.eq_ignore_ascii_case(name));
+            input.headers.insert(name.clone(), value.clone());
+        }
+        if self.override_empty_host_header {
+            for (header, value) in input.headers.iter_mut() {
+                if header.eq_ignore_ascii_case("Host") && value.trim().is_empty() {
+                    *value = input.dest_addr.clone();
+                }
+            }
+        }
+    }
+}
+
+/// go-ftw's `testoverride` section, whose lists map regexes of test titles to the reason a test
+/// is listed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOverrides {
+    #[serde(default)]
+    input: InputOverride,
+    #[serde(default)]
+    ignore: BTreeMap<String, String>,
+    #[serde(default)]
+    forcepass: BTreeMap<String, String>,
+    #[serde(default)]
+    forcefail: BTreeMap<String, String>,
+}
+
+/// A go-ftw config file, of which only the overrides are used.
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    testoverride: RawOverrides,
+}
+
+/// Overrides of the tests' inputs and results, as in go-ftw's `testoverride`.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub input: InputOverride,
+    /// Tests that are skipped, with the reason.
+    pub ignore: Vec<(Regex, String)>,
+    /// Tests that pass whatever their result, with the reason.
+    pub forcepass: Vec<(Regex, String)>,
+    /// Tests that fail whatever their result, with the reason.
+    pub forcefail: Vec<(Regex, String)>,
+}
+
+fn compile_titles(
+    field: &'static str,
+    titles: BTreeMap<String, String>,
+) -> Result<Vec<(Regex, String)>, Error> {
+    titles
+        .into_iter()
+        .map(|(title, reason)| match Regex::new(&title) {
+            Ok(regex) => Ok((regex, reason)),
+            Err(source) => Err(Error::InvalidRegex { field, source }),
+        })
+        .collect()
+}
+
+/// The reason of the first entry whose regex matches the title.
+fn find_title<'a>(titles: &'a [(Regex, String)], title: &str) -> Option<&'a str> {
+    titles
+        .iter()
+        .find(|(regex, _)| regex.is_match(title))
+        .map(|(_, reason)| reason.as_str())
+}
+
+impl TryFrom<RawOverrides> for Overrides {
+    type Error = Error;
+
+    fn try_from(raw: RawOverrides) -> Result<Self, Self::Error> {
+        Ok(Self {
+            input: raw.input,
+            ignore: compile_titles("testoverride.ignore", raw.ignore)?,
+            forcepass: compile_titles("testoverride.forcepass", raw.forcepass)?,
+            forcefail: compile_titles("testoverride.forcefail", raw.forcefail)?,
+        })
+    }
+}
+
+impl FromStr for Overrides {
+    type Err = Error;
+
+    /// Reads the `testoverride` section of a go-ftw config file, ignoring its other settings.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Option<ConfigFile> = serde_yaml::from_str(s)?;
+        config.unwrap_or_default().testoverride.try_into()
+    }
+}
+
+impl Overrides {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+/// Which tests to run. A test runs if it's selected by every filter that's set, and isn't
+/// excluded by any of them.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only tests with a title matching this run.
+    pub include: Option<Regex>,
+    /// Tests with a title matching this are skipped.
+    pub exclude: Option<Regex>,
+    /// Only tests for a rule matching one of these run, e.g. `942*`.
+    pub rule_ids: Vec<Pattern>,
+    /// Tests for a rule matching one of these are skipped.
+    pub exclude_rule_ids: Vec<Pattern>,
+    /// Only tests in a file matching one of these run.
+    pub files: Vec<Pattern>,
+    /// Only tests for a rule with one of these tags run.
+    pub tags: Vec<String>,
+    /// Tests for a rule with one of these tags are skipped.
+    pub exclude_tags: Vec<String>,
+    /// The tags of the rules, by id, which `tags` and `exclude_tags` are checked against, see
+    /// [`rule_tags`].
+    pub rule_tags: HashMap<u32, Vec<String>>,
+    pub overrides: Overrides,
+}
+
+/// The tags of the rules among `entries`, by id, from their `tag` actions. Chained rules don't
+/// have tags of their own, so only the chain starter's are found.
+pub fn rule_tags<'a>(entries: impl IntoIterator<Item = &'a CRSEntry>) -> HashMap<u32, Vec<String>> {
+    let mut tags = HashMap::new();
+    for entry in entries {
+        let actions = match entry {
+            CRSEntry::SecRule { actions, .. } | CRSEntry::SecAction { actions, .. } => actions,
+            CRSEntry::SecMarker { .. }
+            | CRSEntry::SecComponentSignature { .. }
+            | CRSEntry::SecCollectionTimeout { .. } => continue,
+        };
+        let id = actions
+            .iter()
+            .find(|action| action.action == ActionType::Id)
+            .and_then(Action::argument)
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            let rule_tags = actions
+                .iter()
+                .filter(|action| action.action == ActionType::Tag)
+                .filter_map(Action::argument)
+                .map(String::from)
+                .collect();
+            tags.insert(id, rule_tags);
+        }
+    }
+    tags
+}
+
+/// A result forced by the overrides, with the reason.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Forced {
+    Pass(String),
+    Fail(String),
+}
+
+/// A test to run, with the overrides applied, or a test that's skipped.
+#[derive(Debug, Clone)]
+pub struct Selected {
+    pub test: Test,
+    /// Why the test doesn't run, if it's skipped.
+    pub skipped: Option<String>,
+    /// The result forced by `forcepass` or `forcefail`.
+    pub forced: Option<Forced>,
+}
+
+impl Selected {
+    /// Applies the forced result, if any, to the result of running the test, keeping the
+    /// reason and the status it replaced in [`TestResult::forced`].
+    pub fn finish(&self, mut result: TestResult) -> TestResult {
+        match (&self.forced, &result.status) {
+            (Some(Forced::Pass(reason)), Status::Failed(_)) => {
+                result.forced = Some(format!("forced to pass, {} ({})", reason, result.status));
+                result.status = Status::Passed;
+            }
+            (Some(Forced::Fail(reason)), Status::Passed) => {
+                result.forced = Some(format!("forced to fail, {} ({})", reason, result.status));
+                result.status = Status::Failed(format!("forced to fail, {}", reason));
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// The result of a skipped test.
+    pub fn skipped_result(&self) -> Option<TestResult> {
+        self.skipped
+            .as_ref()
+            .map(|reason| TestResult::skipped(&self.test, reason.clone()))
+    }
+}
+
+impl Filter {
+    /// Why a test of the file at `path` is skipped, or `None` if it runs.
+    pub fn skip_reason(&self, path: &Path, file: &File, test: &Test) -> Option<String> {
+        let title = test.title();
+        let rule_id = test.rule_id.map(|id| id.to_string());
+        let matches_rule = |patterns: &[Pattern]| {
+            rule_id
+                .as_ref()
+                .is_some_and(|id| patterns.iter().any(|pattern| pattern.matches(id)))
+        };
+        let tags = || {
+            test.rule_id
+                .and_then(|id| self.rule_tags.get(&id))
+                .into_iter()
+                .flatten()
+        };
+
+        if !file.meta.enabled {
+            return Some("the test file is disabled".into());
+        }
+        if !self.files.is_empty() && !self.files.iter().any(|p| p.matches_path(path)) {
+            return Some(format!("{} isn't selected", path.display()));
+        }
+        if let Some(include) = self.include.as_ref().filter(|r| !r.is_match(&title)) {
+            return Some(format!("the title doesn't match /{}/", include));
+        }
+        if let Some(exclude) = self.exclude.as_ref().filter(|r| r.is_match(&title)) {
+            return Some(format!("the title matches /{}/", exclude));
+        }
+        if !self.rule_ids.is_empty() && !matches_rule(&self.rule_ids) {
+            return Some(match &rule_id {
+                Some(id) => format!("rule {} isn't selected", id),
+                None => "the test isn't for a rule".into(),
+            });
+        }
+        if matches_rule(&self.exclude_rule_ids) {
+            return Some(format!("rule {} is excluded", rule_id.unwrap_or_default()));
+        }
+        if !self.tags.is_empty() && !tags().any(|tag| self.tags.contains(tag)) {
+            return Some(match &rule_id {
+                Some(id) => format!("rule {} has none of the selected tags", id),
+                None => "the test isn't for a rule".into(),
+            });
+        }
+        if let Some(tag) = tags().find(|tag| self.exclude_tags.contains(tag)) {
+            return Some(format!("tag {} is excluded", tag));
+        }
+        find_title(&self.overrides.ignore, &title).map(|reason| format!("ignored, {}", reason))
+    }
+
+    /// Every test of the file at `path`, with the overrides applied to the stages of the tests
+    /// that run.
+    pub fn select(&self, path: &Path, file: &File) -> Vec<Selected> {
+        file.tests
+            .iter()
+            .map(|test| {
+                let title = test.title();
+                let skipped = self.skip_reason(path, file, test);
+                let mut test = test.clone();
+                if skipped.is_none() {
+                    for wrapper in &mut test.stages {
+                        self.overrides.input.apply(&mut wrapper.stage.input);
+                    }
+                }
+                let forced = match find_title(&self.overrides.forcefail, &title) {
+                    Some(reason) => Some(Forced::Fail(reason.into())),
+                    None => find_title(&self.overrides.forcepass, &title)
+                        .map(|reason| Forced::Pass(reason.into())),
+                };
+                Selected {
+                    test,
+                    skipped,
+                    forced,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parse_entries;
+
+    const FILE: &str = r#"
+meta:
+  author: a
+rule_id: 942100
+tests:
+  - test_id: 1
+    stages:
+      - input:
+          headers:
+            host: ""
+            User-Agent: curl
+        output:
+          status: 403
+  - test_id: 2
+    stages:
+      - input:
+          uri: /other
+"#;
+
+    fn file() -> File {
+        File::from_str(FILE).unwrap()
+    }
+
+    fn skip_reasons(filter: &Filter, path: &str, file: &File) -> Vec<Option<String>> {
+        file.tests
+            .iter()
+            .map(|test| filter.skip_reason(Path::new(path), file, test))
+            .collect()
+    }
+
+    fn titles(entries: &[(&str, &str)]) -> Vec<(Regex, String)> {
+        entries
+            .iter()
+            .map(|&(title, reason)| (Regex::new(title).unwrap(), reason.into()))
+            .collect()
+    }
+
+    #[test]
+    fn runs_every_test_by_default() {
+        let reasons = skip_reasons(&Filter::default(), "942100.yaml", &file());
+        assert_eq!(reasons, [None, None]);
+    }
+
+    #[test]
+    fn skips_disabled_files() {
+        let mut file = file();
+        file.meta.enabled = false;
+        let reasons = skip_reasons(&Filter::default(), "942100.yaml", &file);
+        assert_eq!(reasons[0].as_deref(), Some("the test file is disabled"));
+    }
+
+    #[test]
+    fn selects_by_title() {
+        let filter = Filter {
+            include: Some(Regex::new("-1$").unwrap()),
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(reasons[0], None);
+        assert_eq!(reasons[1].as_deref(), Some("the title doesn't match /-1$/"));
+
+        let filter = Filter {
+            exclude: Some(Regex::new("-1$").unwrap()),
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(reasons[0].as_deref(), Some("the title matches /-1$/"));
+        assert_eq!(reasons[1], None);
+    }
+
+    #[test]
+    fn selects_by_rule_id() {
+        let filter = Filter {
+            rule_ids: vec![Pattern::new("942*").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(skip_reasons(&filter, "942100.yaml", &file()), [None, None]);
+
+        let filter = Filter {
+            rule_ids: vec![Pattern::new("941*").unwrap()],
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(reasons[0].as_deref(), Some("rule 942100 isn't selected"));
+
+        let filter = Filter {
+            exclude_rule_ids: vec![Pattern::new("9421??").unwrap()],
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(reasons[0].as_deref(), Some("rule 942100 is excluded"));
+    }
+
+    #[test]
+    fn selects_by_file() {
+        let filter = Filter {
+            files: vec![Pattern::new("tests/*/942*.yaml").unwrap()],
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "tests/sqli/942100.yaml", &file());
+        assert_eq!(reasons, [None, None]);
+        let reasons = skip_reasons(&filter, "tests/xss/941100.yaml", &file());
+        assert_eq!(
+            reasons[0].as_deref(),
+            Some("tests/xss/941100.yaml isn't selected")
+        );
+    }
+
+    #[test]
+    fn selects_by_the_tags_of_the_rule() {
+        let entries = parse_entries(
+            r#"SecRule ARGS "@detectSQLi" "id:942100,phase:2,deny,tag:'attack-sqli',tag:'paranoia-level/1',chain"
+SecRule ARGS "@rx a" "t:none"
+SecAction "id:900000,phase:1,nolog,pass"
+SecMarker "END"
+"#,
+        )
+        .unwrap();
+        let rule_tags = rule_tags(&entries);
+        assert_eq!(rule_tags.len(), 2);
+        assert_eq!(rule_tags[&942100], ["attack-sqli", "paranoia-level/1"]);
+        assert!(rule_tags[&900000].is_empty());
+
+        let filter = Filter {
+            tags: vec!["attack-sqli".into()],
+            rule_tags: rule_tags.clone(),
+            ..Default::default()
+        };
+        assert_eq!(skip_reasons(&filter, "942100.yaml", &file()), [None, None]);
+
+        let filter = Filter {
+            tags: vec!["attack-xss".into()],
+            rule_tags: rule_tags.clone(),
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(
+            reasons[0].as_deref(),
+            Some("rule 942100 has none of the selected tags")
+        );
+
+        let filter = Filter {
+            exclude_tags: vec!["paranoia-level/1".into()],
+            rule_tags,
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(
+            reasons[0].as_deref(),
+            Some("tag paranoia-level/1 is excluded")
+        );
+    }
+
+    #[test]
+    fn skips_ignored_tests() {
+        let filter = Filter {
+            overrides: Overrides {
+                ignore: titles(&[("^942100-2$", "known false negative")]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let reasons = skip_reasons(&filter, "942100.yaml", &file());
+        assert_eq!(reasons[0], None);
+        assert_eq!(reasons[1].as_deref(), Some("ignored, known false negative"));
+    }
+
+    #[test]
+    fn replaces_headers_in_any_case() {
+        let mut input = file().tests[0].stages[0].stage.input.clone();
+        let overrides = InputOverride {
+            dest_addr: Some("waf.example".into()),
+            port: Some(8080),
+            headers: HashMap::from([("user-agent".into(), "go-ftw".into())]),
+            ..Default::default()
+        };
+        overrides.apply(&mut input);
+        assert_eq!(input.dest_addr, "waf.example");
+        assert_eq!(input.port, 8080);
+        assert_eq!(
+            input.headers,
+            HashMap::from([
+                ("host".into(), "".into()),
+                ("user-agent".into(), "go-ftw".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn overrides_empty_host_headers() {
+        let mut input = file().tests[0].stages[0].stage.input.clone();
+        let overrides = InputOverride {
+            dest_addr: Some("waf.example".into()),
+            override_empty_host_header: true,
+            ..Default::default()
+        };
+        overrides.apply(&mut input);
+        assert_eq!(input.headers["host"], "waf.example");
+
+        // hosts that aren't empty are kept
+        let mut input = file().tests[0].stages[0].stage.input.clone();
+        input.headers.insert("host".into(), "localhost".into());
+        overrides.apply(&mut input);
+        assert_eq!(input.headers["host"], "localhost");
+    }
+
+    #[test]
+    fn reads_go_ftw_overrides() {
+        let overrides: Overrides = r#"
+logfile: /var/log/modsec_audit.log
+testoverride:
+  input:
+    dest_addr: 127.0.0.1
+    port: 8080
+  ignore:
+    "^942100-": known issue
+  forcepass:
+    "-1$": flaky
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(overrides.input.dest_addr.as_deref(), Some("127.0.0.1"));
+        assert_eq!(overrides.input.port, Some(8080));
+        assert_eq!(
+            find_title(&overrides.ignore, "942100-2"),
+            Some("known issue")
+        );
+        assert_eq!(find_title(&overrides.forcepass, "942100-1"), Some("flaky"));
+        assert_eq!(find_title(&overrides.forcefail, "942100-1"), None);
+
+        let error = "testoverride:\n  ignore:\n    \"(\": x\n".parse::<Overrides>();
+        assert!(matches!(
+            error,
+            Err(Error::InvalidRegex {
+                field: "testoverride.ignore",
+                ..
+            })
+        ));
+        assert!("".parse::<Overrides>().is_ok());
+    }
+
+    #[test]
+    fn applies_overrides_to_selected_tests_only() {
+        let filter = Filter {
+            exclude: Some(Regex::new("-2$").unwrap()),
+            overrides: Overrides {
+                input: InputOverride {
+                    uri: Some("/overridden".into()),
+                    ..Default::default()
+                },
+                forcepass: titles(&[("-1$", "flaky")]),
+                forcefail: titles(&[("-2$", "not fixed yet")]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let selected = filter.select(Path::new("942100.yaml"), &file());
+        assert_eq!(selected.len(), 2);
+
+        assert_eq!(selected[0].skipped, None);
+        assert_eq!(selected[0].forced, Some(Forced::Pass("flaky".into())));
+        assert_eq!(selected[0].test.stages[0].stage.input.uri, "/overridden");
+
+        assert_eq!(
+            selected[1].skipped.as_deref(),
+            Some("the title matches /-2$/")
+        );
+        assert_eq!(selected[1].test.stages[0].stage.input.uri, "/other");
+        let result = selected[1].skipped_result().unwrap();
+        assert_eq!(
+            result.status,
+            Status::Skipped("the title matches /-2$/".into())
+        );
+    }
+
+    #[test]
+    fn keeps_the_reason_of_forced_results() {
+        let test = &file().tests[0];
+        let selected = |forced| Selected {
+            test: test.clone(),
+            skipped: None,
+            forced: Some(forced),
+        };
+        let failed = TestResult {
+            status: Status::Failed("stage 1: status 200".into()),
+            ..TestResult::new(test)
+        };
+
+        let result = selected(Forced::Pass("flaky".into())).finish(failed.clone());
+        assert_eq!(result.status, Status::Passed);
+        assert_eq!(
+            result.forced.as_deref(),
+            Some("forced to pass, flaky (failed: stage 1: status 200)")
+        );
+
+        let result = selected(Forced::Fail("not fixed".into())).finish(TestResult::new(test));
+        assert_eq!(
+            result.status,
+            Status::Failed("forced to fail, not fixed".into())
+        );
+        assert_eq!(
+            result.forced.as_deref(),
+            Some("forced to fail, not fixed (passed)")
+        );
+
+        // results that already agree aren't changed
+        let result = selected(Forced::Fail("not fixed".into())).finish(failed);
+        assert_eq!(result.status, Status::Failed("stage 1: status 200".into()));
+        assert_eq!(result.forced, None);
+    }
+}
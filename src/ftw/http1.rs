@@ -0,0 +1,417 @@
+//! A lenient HTTP/1.x parser for the raw requests of FTW tests, and for the responses to them.
+//!
+//! Raw requests are often deliberately malformed, so the parser accepts whatever a forgiving
+//! server would: bare LF line endings, missing versions, folded headers, and bytes that aren't
+//! allowed in URIs, which are percent-encoded so that the request can still be represented.
+//! Header lines that can't be represented, e.g. a header name with spaces, a header value with
+//! control characters, or a line without a colon, are kept as they are in [`RawHeaders`], so
+//! that the engine can still inspect them.
+
+use crate::engine::RawHeaders;
+use http::header::{HeaderName, HeaderValue};
+use http::{Method, Request, Response, Uri, Version};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the request line is empty")]
+    EmptyRequestLine,
+    #[error("invalid method {0:?}")]
+    InvalidMethod(String),
+    #[error("invalid request target {0:?}")]
+    InvalidTarget(String),
+    #[error(transparent)]
+    HttpError(#[from] http::Error),
+}
+
+/// Splits off the next line, accepting both CRLF and bare LF line endings.
+fn next_line(input: &[u8]) -> (&[u8], &[u8]) {
+    match input.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &input[..end];
+            (line.strip_suffix(b"\r").unwrap_or(line), &input[end + 1..])
+        }
+        None => (input, &[]),
+    }
+}
+
+/// Splits a message into its start line, header lines, and body. A message without a blank
+/// line after the headers has an empty body.
+fn split_message(input: &[u8]) -> (&[u8], Vec<&[u8]>, &[u8]) {
+    let (start, mut rest) = next_line(input);
+    let mut headers = vec![];
+    while !rest.is_empty() {
+        let (line, remaining) = next_line(rest);
+        rest = remaining;
+        if line.is_empty() {
+            break;
+        }
+        headers.push(line);
+    }
+    (start, headers, rest)
+}
+
+/// Parses header lines, unfolding continuation lines. Headers that can't be represented are
+/// returned separately, with their raw name and value, since leaving them out or altering them
+/// would change what the WAF sees. A line that isn't a header is kept as a name without a value.
+fn parse_headers(lines: &[&[u8]]) -> (Vec<(HeaderName, HeaderValue)>, RawHeaders) {
+    // the name, value, and whether the line was a header at all
+    let mut raw: Vec<(&[u8], Vec<u8>, bool)> = vec![];
+    for &line in lines {
+        let folded = line.starts_with(b" ") || line.starts_with(b"\t");
+        match (folded, raw.last_mut(), line.iter().position(|&b| b == b':')) {
+            (true, Some((_, value, true)), _) => {
+                value.push(b' ');
+                value.extend_from_slice(line.trim_ascii());
+            }
+            (false, _, Some(colon)) => raw.push((
+                &line[..colon],
+                line[colon + 1..].trim_ascii().to_vec(),
+                true,
+            )),
+            _ => raw.push((line, vec![], false)),
+        }
+    }
+
+    let mut headers = vec![];
+    let mut unrepresentable = RawHeaders::default();
+    for (name, value, is_header) in raw {
+        match (
+            HeaderName::from_bytes(name),
+            HeaderValue::from_bytes(&value),
+        ) {
+            (Ok(name), Ok(value)) if is_header => headers.push((name, value)),
+            _ => unrepresentable.0.push((name.to_vec(), value)),
+        }
+    }
+    (headers, unrepresentable)
+}
+
+/// Parses a request target, percent-encoding any bytes that aren't allowed in a URI.
+fn parse_target(target: &[u8]) -> Result<Uri, Error> {
+    if let Ok(uri) = Uri::try_from(target) {
+        return Ok(uri);
+    }
+    let mut escaped = String::with_capacity(target.len() * 3);
+    for &b in target {
+        match b {
+            b'"' | b'<' | b'>' | b'\\' | b'^' | b'`' | b'{' | b'|' | b'}' => {
+                escaped.push_str(&format!("%{:02X}", b))
+            }
+            0x21..=0x7e => escaped.push(char::from(b)),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    Uri::try_from(escaped.as_str())
+        .map_err(|_| Error::InvalidTarget(String::from_utf8_lossy(target).into_owned()))
+}
+
+fn parse_version(version: Option<&[u8]>) -> Version {
+    match version {
+        Some(b"HTTP/0.9") => Version::HTTP_09,
+        Some(b"HTTP/1.0") | None => Version::HTTP_10,
+        Some(b"HTTP/2") | Some(b"HTTP/2.0") => Version::HTTP_2,
+        Some(b"HTTP/3") | Some(b"HTTP/3.0") => Version::HTTP_3,
+        // unknown versions are handled like HTTP/1.1, as most servers do
+        _ => Version::HTTP_11,
+    }
+}
+
+fn find_header<'a>(headers: &'a [(HeaderName, HeaderValue)], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .rev()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_bytes())
+}
+
+fn is_chunked(headers: &[(HeaderName, HeaderValue)]) -> bool {
+    find_header(headers, "transfer-encoding").is_some_and(|value| {
+        String::from_utf8_lossy(value)
+            .to_ascii_lowercase()
+            .contains("chunked")
+    })
+}
+
+fn content_length(headers: &[(HeaderName, HeaderValue)]) -> Option<usize> {
+    std::str::from_utf8(find_header(headers, "content-length")?)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Decodes a chunked body, returning the body and whether the last chunk was reached.
+fn decode_chunked(mut input: &[u8]) -> (Vec<u8>, bool) {
+    let mut body = vec![];
+    loop {
+        let (line, rest) = next_line(input);
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = match usize::from_str_radix(String::from_utf8_lossy(size).trim(), 16) {
+            Ok(size) => size,
+            Err(_) => return (body, false),
+        };
+        if size == 0 {
+            return (body, true);
+        }
+        let chunk = &rest[..size.min(rest.len())];
+        body.extend_from_slice(chunk);
+        if chunk.len() < size {
+            return (body, false);
+        }
+        input = next_line(&rest[size..]).1;
+    }
+}
+
+/// Parses a raw request. The body is delimited by `Transfer-Encoding: chunked` or
+/// `Content-Length` if present, and is the rest of the input otherwise.
+pub fn parse_request(input: &[u8]) -> Result<Request<Vec<u8>>, Error> {
+    let (start, header_lines, rest) = split_message(input);
+    let start = start.trim_ascii();
+    let (method, line) = match start.iter().position(u8::is_ascii_whitespace) {
+        Some(end) => (&start[..end], start[end..].trim_ascii_start()),
+        None => (start, &[][..]),
+    };
+    if method.is_empty() {
+        return Err(Error::EmptyRequestLine);
+    }
+    let method = Method::from_bytes(method)
+        .map_err(|_| Error::InvalidMethod(String::from_utf8_lossy(method).into_owned()))?;
+    // the target may contain whitespace, so only a last word that looks like one is the version
+    let (target, version) = match line.iter().rposition(u8::is_ascii_whitespace) {
+        Some(end) if line[end + 1..].starts_with(b"HTTP/") => {
+            (line[..end].trim_ascii_end(), Some(&line[end + 1..]))
+        }
+        _ if line.starts_with(b"HTTP/") => (&[][..], Some(line)),
+        _ => (line, None),
+    };
+    let uri = parse_target(if target.is_empty() { b"/" } else { target })?;
+    let version = parse_version(version);
+
+    let (headers, raw_headers) = parse_headers(&header_lines);
+    let body = if is_chunked(&headers) {
+        decode_chunked(rest).0
+    } else {
+        match content_length(&headers) {
+            Some(length) => rest[..length.min(rest.len())].to_vec(),
+            None => rest.to_vec(),
+        }
+    };
+
+    let mut builder = Request::builder().method(method).uri(uri).version(version);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    if !raw_headers.0.is_empty() {
+        builder = builder.extension(raw_headers);
+    }
+    Ok(builder.body(body)?)
+}
+
+/// Writes a request as it would be sent over HTTP/1.x, with the given version in the request
+/// line, which doesn't need to be a valid version. A `Content-Length` header is added if the
+/// request has a body without one.
+pub fn serialize_request<T: AsRef<[u8]>>(request: &Request<T>, version: &str) -> Vec<u8> {
+    let target = request.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let mut raw = format!("{} {} {}\r\n", request.method(), target, version).into_bytes();
+    for (name, value) in request.headers() {
+        raw.extend_from_slice(name.as_str().as_bytes());
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(value.as_bytes());
+        raw.extend_from_slice(b"\r\n");
+    }
+    let body = request.body().as_ref();
+    let has_length = request.headers().contains_key(http::header::CONTENT_LENGTH)
+        || request
+            .headers()
+            .contains_key(http::header::TRANSFER_ENCODING);
+    if !body.is_empty() && !has_length {
+        raw.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+    }
+    raw.extend_from_slice(b"\r\n");
+    raw.extend_from_slice(body);
+    raw
+}
+
+/// Parses a response received so far, returning it once it's complete. With `eof` set, the
+/// connection has been closed, so whatever has been received is the response.
+pub fn parse_response(input: &[u8], eof: bool) -> Option<Response<Vec<u8>>> {
+    let headers_received =
+        input.windows(2).any(|w| w == b"\n\n") || input.windows(4).any(|w| w == b"\r\n\r\n");
+    if !headers_received && !eof {
+        return None;
+    }
+
+    let (start, header_lines, rest) = split_message(input);
+    let status = start
+        .split(|&b| b == b' ')
+        .find(|part| !part.is_empty() && !part.starts_with(b"HTTP/"))?;
+    let status: u16 = std::str::from_utf8(status).ok()?.parse().ok()?;
+
+    // only the status and body of a response are checked, so it's parsed leniently
+    let (headers, _) = parse_headers(&header_lines);
+    let body = if matches!(status, 100..=199 | 204 | 304) {
+        vec![]
+    } else if is_chunked(&headers) {
+        match decode_chunked(rest) {
+            (body, complete) if complete || eof => body,
+            _ => return None,
+        }
+    } else {
+        match content_length(&headers) {
+            Some(length) if rest.len() >= length => rest[..length].to_vec(),
+            _ if eof => rest.to_vec(),
+            _ => return None,
+        }
+    };
+
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a [u8]> {
+        headers.get(name).map(HeaderValue::as_bytes)
+    }
+
+    #[test]
+    fn parses_requests_with_bare_lf() {
+        let request = parse_request(b"POST /a?b=c HTTP/1.1\nHost: localhost\n\nbody").unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), "/a?b=c");
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(header(request.headers(), "host"), Some(&b"localhost"[..]));
+        assert_eq!(request.body(), b"body");
+    }
+
+    #[test]
+    fn parses_requests_without_a_version() {
+        let request = parse_request(b"GET /index.html\r\n\r\n").unwrap();
+        assert_eq!(request.uri(), "/index.html");
+        assert_eq!(request.version(), Version::HTTP_10);
+
+        let request = parse_request(b"GET HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.uri(), "/");
+        assert_eq!(request.version(), Version::HTTP_11);
+
+        let request = parse_request(b"GET /a b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.uri(), "/a%20b");
+    }
+
+    #[test]
+    fn unfolds_folded_headers() {
+        let raw = b"GET / HTTP/1.1\r\nX-Folded: a\r\n  b\r\n\tc\r\nHost: localhost\r\n\r\n";
+        let request = parse_request(raw).unwrap();
+        assert_eq!(header(request.headers(), "x-folded"), Some(&b"a b c"[..]));
+        assert_eq!(header(request.headers(), "host"), Some(&b"localhost"[..]));
+    }
+
+    #[test]
+    fn keeps_headers_that_cant_be_represented() {
+        let raw = b"GET / HTTP/1.1\r\nHost: localhost\r\nBad Name: x\r\nX-Test: a\x01b\r\n c\r\nnot-a-header\r\n\r\n";
+        let request = parse_request(raw).unwrap();
+        assert_eq!(request.headers().len(), 1);
+        assert_eq!(header(request.headers(), "host"), Some(&b"localhost"[..]));
+        let raw_headers = request.extensions().get::<RawHeaders>().unwrap();
+        let expected: [(&[u8], &[u8]); 3] = [
+            (b"Bad Name", b"x"),
+            (b"X-Test", b"a\x01b c"),
+            (b"not-a-header", b""),
+        ];
+        assert_eq!(
+            raw_headers.0,
+            expected.map(|(name, value)| (name.to_vec(), value.to_vec()))
+        );
+
+        let request = parse_request(b"GET / HTTP/1.1\r\n folded: x\r\n\r\n").unwrap();
+        let raw_headers = request.extensions().get::<RawHeaders>().unwrap();
+        assert_eq!(raw_headers.0, [(b" folded: x".to_vec(), vec![])]);
+
+        let request = parse_request(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(request.extensions().get::<RawHeaders>().is_none());
+    }
+
+    #[test]
+    fn rejects_empty_request_lines() {
+        assert!(matches!(
+            parse_request(b"\r\n"),
+            Err(Error::EmptyRequestLine)
+        ));
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert_eq!(parse_request(raw).unwrap().body(), b"abcde");
+
+        assert_eq!(
+            decode_chunked(b"3\r\nabc\r\n0\r\n\r\n"),
+            (b"abc".to_vec(), true)
+        );
+        assert_eq!(decode_chunked(b"3\r\nabc\r\n"), (b"abc".to_vec(), false));
+        assert_eq!(decode_chunked(b"5\r\nab"), (b"ab".to_vec(), false));
+        assert_eq!(decode_chunked(b"zz\r\nabc"), (vec![], false));
+    }
+
+    #[test]
+    fn keeps_what_was_sent_of_truncated_bodies() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab";
+        assert_eq!(parse_request(raw).unwrap().body(), b"ab");
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        let request = parse_request(raw).unwrap();
+        assert_eq!(request.body(), b"abc");
+        assert_eq!(
+            header(request.headers(), "content-length"),
+            Some(&b"10"[..])
+        );
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nabc";
+        assert_eq!(parse_request(raw).unwrap().body(), b"ab");
+    }
+
+    #[test]
+    fn serializes_requests() {
+        let request = Request::post("/a?b")
+            .header("host", "x")
+            .body(b"abc")
+            .unwrap();
+        assert_eq!(
+            serialize_request(&request, "HTTP/1.1"),
+            b"POST /a?b HTTP/1.1\r\nhost: x\r\ncontent-length: 3\r\n\r\nabc"
+        );
+    }
+
+    #[test]
+    fn waits_for_complete_responses() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nab";
+        assert!(parse_response(raw, false).is_none());
+        assert_eq!(parse_response(raw, true).unwrap().body(), b"ab");
+
+        let raw = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 2\r\n\r\nno";
+        let response = parse_response(raw, false).unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.body(), b"no");
+
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n";
+        assert!(parse_response(raw, false).is_none());
+        assert_eq!(parse_response(raw, true).unwrap().body(), b"ab");
+
+        assert!(parse_response(b"HTTP/1.1 204 No Content\r\n", false).is_none());
+        let raw = b"HTTP/1.1 204 No Content\r\n\r\n";
+        assert!(parse_response(raw, false).unwrap().body().is_empty());
+    }
+
+    #[test]
+    fn parses_responses_leniently() {
+        let raw = b"HTTP/1.1 200 OK\nBad Header: x\nX-Ok: y\n\nbody";
+        let response = parse_response(raw, true).unwrap();
+        assert_eq!(header(response.headers(), "x-ok"), Some(&b"y"[..]));
+        assert_eq!(response.body(), b"body");
+    }
+}
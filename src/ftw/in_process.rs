@@ -0,0 +1,312 @@
+//! Runs FTW tests against the crate's own engine, without a WAF or any network access.
+//!
+//! Each stage's request is processed by a [`RuleSet`] in a new transaction, and the rules that
+//! matched are written to a log in ModSecurity's format, so `log_contains` and
+//! `no_log_contains` work the same as against a real WAF:
+//!
+//! ```text
+//! ModSecurity: Warning. [id "942100"] [msg "SQL Injection Attack Detected via libinjection"] [severity "CRITICAL"] [tag "attack-sqli"]
+//! ```
+//!
+//! There's no backend to set cookies, so with `save_cookie` the jar of a test is seeded from the
+//! `Set-Cookie` headers of the stage's input, as well as any the simulated response has. Like
+//! over HTTP, the jar's cookies are added to the requests of the later stages.
+
+use super::cookies::{CookieJar, SetCookie};
+use super::filter::Selected;
+use super::runner::{
+    compile_expectations, origin, stage_status, Response, StageResult, Status, TestResult,
+};
+use super::{File, Stage, Test};
+use crate::engine::actions::Disruption;
+use crate::engine::collections::Collections;
+use crate::engine::rule::RuleMatch;
+use crate::engine::ruleset::{RuleSet, Verdict};
+use crate::engine::transaction::Transaction;
+use http::header::SET_COOKIE;
+use std::fmt::Write;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The status of a response when the disruptive action doesn't set one, like ModSecurity.
+const DENY_STATUS: u16 = 403;
+const REDIRECT_STATUS: u16 = 302;
+/// The status of a request that the rules let through to the (absent) backend.
+const ALLOWED_STATUS: u16 = 200;
+
+/// Syslog severity names, as ModSecurity logs them.
+const SEVERITIES: [&str; 8] = [
+    "EMERGENCY",
+    "ALERT",
+    "CRITICAL",
+    "ERROR",
+    "WARNING",
+    "NOTICE",
+    "INFO",
+    "DEBUG",
+];
+
+/// Formats a match the way ModSecurity logs it.
+pub fn log_line(rule_match: &RuleMatch, interrupted: bool) -> String {
+    let metadata = &rule_match.metadata;
+    let mut line = match interrupted {
+        true => "ModSecurity: Access denied.".to_string(),
+        false => "ModSecurity: Warning.".to_string(),
+    };
+    let mut field = |name: &str, value: &str| {
+        let _ = write!(line, " [{} {:?}]", name, value);
+    };
+    if let Some(id) = metadata.id {
+        field("id", &id.to_string());
+    }
+    if let Some(revision) = &metadata.revision {
+        field("rev", revision);
+    }
+    if let Some(msg) = &rule_match.msg {
+        field("msg", msg);
+    }
+    if let Some(logdata) = &rule_match.logdata {
+        field("data", logdata);
+    }
+    if let Some(severity) = metadata.severity {
+        field(
+            "severity",
+            SEVERITIES.get(usize::from(severity)).unwrap_or(&""),
+        );
+    }
+    if let Some(version) = &metadata.version {
+        field("ver", version);
+    }
+    for tag in &metadata.tags {
+        field("tag", tag);
+    }
+    line
+}
+
+/// The log lines for every match of a processed transaction, except those of `nolog` rules,
+/// along with any rule errors.
+pub fn log_lines(verdict: &Verdict) -> String {
+    let mut lines = vec![];
+    for (i, rule_match) in verdict.matches.iter().enumerate() {
+        let interrupted = verdict.interrupted_by == Some(i);
+        if !rule_match.metadata.nolog {
+            lines.push(log_line(rule_match, interrupted));
+        }
+    }
+    for error in &verdict.errors {
+        lines.push(format!("ModSecurity: Rule error. {}", error));
+    }
+    lines.join("\n")
+}
+
+/// Evaluates the requests of FTW tests with a rule set, and checks the simulated response and
+/// log against the expected output.
+#[derive(Debug, Clone)]
+pub struct InProcessRunner {
+    rules: RuleSet,
+    /// Every transaction gets a copy, so persistent collections are shared between stages.
+    collections: Collections,
+}
+
+impl InProcessRunner {
+    pub fn new(rules: RuleSet) -> Self {
+        Self::with_collections(rules, Collections::default())
+    }
+
+    /// Uses the given persistent collections, which must not have any collection open.
+    pub fn with_collections(rules: RuleSet, collections: Collections) -> Self {
+        Self { rules, collections }
+    }
+
+    #[inline]
+    pub fn rules(&self) -> &RuleSet {
+        &self.rules
+    }
+
+    /// Runs every test of a file, in order.
+    pub fn run_file(&self, file: &File) -> Vec<TestResult> {
+        file.tests
+            .iter()
+            .map(|test| match file.meta.enabled {
+                true => self.run_test(test),
+                false => TestResult::disabled(test),
+            })
+            .collect()
+    }
+
+    /// Runs the tests chosen by a [`Filter`](super::Filter), in order, reporting the others as
+    /// skipped.
+    pub fn run_selected(&self, tests: &[Selected]) -> Vec<TestResult> {
+        tests
+            .iter()
+            .map(|selected| match selected.skipped_result() {
+                Some(result) => result,
+                None => selected.finish(self.run_test(&selected.test)),
+            })
+            .collect()
+    }
+
+    /// Runs the stages of a test in order, stopping at the first stage that fails.
+    pub fn run_test(&self, test: &Test) -> TestResult {
+        let mut result = TestResult::new(test);
+        let mut jar = CookieJar::new();
+        for wrapper in &test.stages {
+            if !result.push_stage(self.run_stage(&wrapper.stage, &mut jar)) {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Processes the request of a stage with the cookies in the jar, and checks the outcome. If
+    /// the stage has `save_cookie` set, the cookies it sets are stored in the jar.
+    pub fn run_stage(&self, stage: &Stage, jar: &mut CookieJar) -> StageResult {
+        let expectations = match compile_expectations(stage) {
+            Ok(expectations) => expectations,
+            Err(status) => {
+                return StageResult {
+                    status,
+                    duration: Duration::ZERO,
+                }
+            }
+        };
+
+        // the engine would see an altered request, so the stage fails rather than passing or
+        // failing for the wrong reason
+        let mut request = match stage.input.request() {
+            Ok(request) => request,
+            Err(error) => {
+                return StageResult {
+                    status: Status::Failed(format!(
+                        "can't represent the request in-process, {}",
+                        error
+                    )),
+                    duration: Duration::ZERO,
+                }
+            }
+        };
+
+        // raw requests are processed as they are, without the jar's cookies
+        if stage.input.raw_bytes().is_none() {
+            jar.add_to(&mut request, SystemTime::now());
+        }
+        let (host, path) = origin(&request);
+
+        let started = Instant::now();
+        let (response, log) = self.process(request);
+        let duration = started.elapsed();
+
+        if stage.input.save_cookie {
+            let now = SystemTime::now();
+            let set_cookies = stage
+                .input
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(SET_COOKIE.as_str()));
+            for (_, header) in set_cookies {
+                if let Some(cookie) = SetCookie::parse(header, now) {
+                    jar.store(cookie, &host, &path, now);
+                }
+            }
+            if let Ok(response) = &response {
+                jar.store_response(response, &host, &path, now);
+            }
+        }
+        StageResult {
+            status: stage_status(expectations.as_ref(), &response, log.as_deref()),
+            duration,
+        }
+    }
+
+    /// Processes the request of a stage, returning the simulated response and log.
+    fn process(&self, request: http::Request<Vec<u8>>) -> (Response, Option<String>) {
+        let mut transaction = Transaction::with_collections(self.collections.clone());
+        let verdict = self.rules.process(&request, &mut transaction);
+        let log = Some(log_lines(&verdict));
+
+        let status = match &verdict.interruption {
+            None | Some(Disruption::Proxy(_)) => ALLOWED_STATUS,
+            Some(Disruption::Drop) => return (Err("the connection was dropped".into()), log),
+            Some(Disruption::Redirect(_)) => transaction.status.unwrap_or(REDIRECT_STATUS),
+            Some(_) => transaction.status.unwrap_or(DENY_STATUS),
+        };
+        let response = http::Response::builder()
+            .status(status)
+            .body(vec![])
+            .map_err(|error| format!("invalid response, {}", error));
+        (response, log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::rule::EngineConfig;
+    use crate::syntax::parse_entries;
+
+    fn runner() -> InProcessRunner {
+        let entries = parse_entries(
+            r#"SecRule REQUEST_COOKIES:session "@streq abc" "id:1,phase:1,deny,status:403,log"
+"#,
+        )
+        .unwrap();
+        let report = RuleSet::compile(&entries, &EngineConfig::default());
+        assert!(report.is_ok(), "{:?}", report.errors);
+        InProcessRunner::new(report.rules)
+    }
+
+    fn test(save_cookie: bool, second_status: u16) -> File {
+        File::from_str(&format!(
+            r#"
+meta:
+  enabled: true
+tests:
+  - test_title: cookies
+    stages:
+      - stage:
+          input:
+            headers:
+              Host: localhost
+              Set-Cookie: session=abc; Path=/
+            save_cookie: {save_cookie}
+          output:
+            status: 200
+      - stage:
+          input:
+            headers:
+              Host: localhost
+            uri: /account
+          output:
+            status: {second_status}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn saved_cookies_are_sent_by_later_stages() {
+        let results = runner().run_file(&test(true, 403));
+        assert_eq!(results[0].status, Status::Passed, "{:?}", results[0]);
+    }
+
+    #[test]
+    fn cookies_are_only_saved_with_save_cookie() {
+        let results = runner().run_file(&test(false, 200));
+        assert_eq!(results[0].status, Status::Passed, "{:?}", results[0]);
+    }
+
+    #[test]
+    fn each_test_has_its_own_jar() {
+        let runner = runner();
+        let mut file = test(true, 403);
+        let mut second = test(false, 200).tests.remove(0);
+        // the second test doesn't save the cookie, and mustn't see the first test's
+        second.stages.remove(0);
+        file.tests.push(second);
+        let results = runner.run_file(&file);
+        assert!(
+            results.iter().all(|r| r.status.is_passed()),
+            "{:?}",
+            results
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Isolates the WAF log lines produced by a single stage, using go-ftw's marker requests.
+//!
+//! Before and after each stage, the runner sends a request with a unique value in the marker
+//! header. The WAF must be configured to log those requests, e.g. with
+//!
+//! ```text
+//! SecRule REQUEST_HEADERS:X-CRS-Test "@rx ^.*$" \
+//!     "id:999999,phase:1,pass,t:none,log,msg:'%{MATCHED_VAR}'"
+//! ```
+//!
+//! so the lines logged for the stage are the ones between the start and end markers.
+
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Where the WAF log is and how stages are marked in it.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// The WAF's error log, which must be readable by the runner.
+    pub path: PathBuf,
+    /// The header that marker requests carry their marker in.
+    pub marker_header: String,
+    /// How long to wait for the end marker to be written to the log.
+    pub timeout: Duration,
+    /// How often to read the log while waiting for the end marker.
+    pub poll_interval: Duration,
+}
+
+impl LogConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            marker_header: "X-CRS-Test".into(),
+            timeout: Duration::from_secs(5),
+            poll_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Generates a marker that's unique across stages, runners and processes.
+pub fn new_marker() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", nanos, std::process::id(), count)
+}
+
+/// Returns the lines strictly between the last line containing `start` and the first line after
+/// it containing `end`, or `None` if the end marker hasn't been logged yet. If the start marker
+/// is missing, e.g. because the log was rotated, the lines from the beginning are used.
+pub fn lines_between(log: &str, start: &str, end: &str) -> Option<String> {
+    let lines: Vec<&str> = log.lines().collect();
+    let start_index = lines
+        .iter()
+        .rposition(|line| line.contains(start))
+        .map_or(0, |i| i + 1);
+    let end_index = start_index
+        + lines[start_index..]
+            .iter()
+            .position(|line| line.contains(end))?;
+    Some(lines[start_index..end_index].join("\n"))
+}
+
+/// The WAF log, read from a position recorded before a stage started.
+#[derive(Debug, Clone)]
+pub struct WafLog {
+    config: LogConfig,
+}
+
+impl WafLog {
+    pub fn new(config: LogConfig) -> Self {
+        Self { config }
+    }
+
+    #[inline]
+    pub fn config(&self) -> &LogConfig {
+        &self.config
+    }
+
+    /// The current end of the log, to read new lines from later.
+    pub async fn offset(&self) -> io::Result<u64> {
+        match fs::metadata(&self.config.path).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reads the log from `offset`, starting over if the log has been truncated since.
+    async fn read_from(&self, offset: u64) -> io::Result<String> {
+        let mut file = fs::File::open(&self.config.path).await?;
+        let offset = match file.metadata().await?.len() {
+            len if len < offset => 0,
+            _ => offset,
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = vec![];
+        file.read_to_end(&mut data).await?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Waits for the end marker to be logged, then returns the lines logged between the
+    /// markers.
+    pub async fn wait_for_lines(
+        &self,
+        offset: u64,
+        start: &str,
+        end: &str,
+    ) -> Result<String, String> {
+        let started = Instant::now();
+        loop {
+            let log = self.read_from(offset).await.map_err(|error| {
+                format!("failed to read {}, {}", self.config.path.display(), error)
+            })?;
+            if let Some(lines) = lines_between(&log, start, end) {
+                return Ok(lines);
+            }
+            if started.elapsed() >= self.config.timeout {
+                return Err(format!(
+                    "end marker {} wasn't logged within {:?}, is the WAF logging {} requests?",
+                    end, self.config.timeout, self.config.marker_header
+                ));
+            }
+            tokio::time::sleep(self.config.poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_the_lines_between_the_markers() {
+        let log = "before\nstart-1\none\ntwo\nend-1\nafter\n";
+        assert_eq!(
+            lines_between(log, "start-1", "end-1").as_deref(),
+            Some("one\ntwo")
+        );
+        let log = "start-1\nend-1\n";
+        assert_eq!(lines_between(log, "start-1", "end-1").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn uses_the_lines_from_the_beginning_without_a_start_marker() {
+        let log = "one\ntwo\nend-1\nafter\n";
+        assert_eq!(
+            lines_between(log, "start-1", "end-1").as_deref(),
+            Some("one\ntwo")
+        );
+    }
+
+    #[test]
+    fn waits_for_the_end_marker() {
+        assert_eq!(lines_between("start-1\none\n", "start-1", "end-1"), None);
+        assert_eq!(lines_between("", "start-1", "end-1"), None);
+        // an end marker before the start marker is from somewhere else
+        assert_eq!(
+            lines_between("end-1\nstart-1\none", "start-1", "end-1"),
+            None
+        );
+    }
+
+    #[test]
+    fn handles_repeated_markers() {
+        // a marker request can be logged by several rules, or by the access and error logs
+        let log = "start-1\nstart-1 again\none\nend-1\nend-1 again\nafter\n";
+        assert_eq!(
+            lines_between(log, "start-1", "end-1").as_deref(),
+            Some("one")
+        );
+    }
+}
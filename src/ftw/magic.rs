@@ -0,0 +1,207 @@
+//! The defaults that FTW fills in for a stage's input unless `stop_magic` is set, following
+//! go-ftw: default headers, a `Content-Length` matching the data, and url-encoding of data
+//! that isn't already encoded.
+
+use std::collections::HashMap;
+
+pub const DEFAULT_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+pub const DEFAULT_USER_AGENT: &str = "OWASP CRS test agent";
+pub const DEFAULT_ACCEPT: &str = "*/*";
+
+/// Looks up a header by name, ignoring case.
+pub fn header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Adds a header unless there's already one with the same name, in any case.
+pub fn add_default_header(headers: &mut HashMap<String, String>, name: &str, value: &str) {
+    if header(headers, name).is_none() {
+        headers.insert(name.into(), value.into());
+    }
+}
+
+/// Escapes text to be used in a query string, like Go's `url.QueryEscape`.
+pub fn query_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() * 3);
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(char::from(b))
+            }
+            b' ' => escaped.push('+'),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+/// Unescapes a query string, like Go's `url.QueryUnescape`, returning `None` if there's an
+/// invalid escape.
+pub fn query_unescape(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                unescaped.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                unescaped.push(b' ');
+                i += 1;
+            }
+            b => {
+                unescaped.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&unescaped).into_owned())
+}
+
+/// Url-encodes form data, like go-ftw. Data is left as it is unless it's a single parameter
+/// that isn't encoded yet: data that's already encoded, has several parameters, or can't be
+/// parsed as a query string (e.g. because it contains `;`) is sent as it was written.
+pub fn encode_data(content_type: Option<&str>, data: &str) -> String {
+    let is_form = content_type.is_some_and(|ct| ct.eq_ignore_ascii_case(DEFAULT_CONTENT_TYPE));
+    let is_encoded = query_unescape(data).as_deref() != Some(data);
+    if !is_form || is_encoded || data.is_empty() || data.contains(['&', ';']) {
+        return data.into();
+    }
+    match data.split_once('=') {
+        Some((name, value)) => format!("{}={}", query_escape(name), query_escape(value)),
+        None => query_escape(data),
+    }
+}
+
+/// Replaces bare LFs with CRLFs, since multipart bodies must use CRLF line endings.
+pub fn crlf_line_endings(data: &str) -> String {
+    let mut fixed = String::with_capacity(data.len());
+    let mut previous = None;
+    for c in data.chars() {
+        if c == '\n' && previous != Some('\r') {
+            fixed.push('\r');
+        }
+        fixed.push(c);
+        previous = Some(c);
+    }
+    fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ftw::File;
+
+    fn input(yaml: &str) -> crate::ftw::Input {
+        let file = format!(
+            "meta:\n  author: test\ntests:\n  - test_title: magic\n    stages:\n      - stage:\n          input:\n{}",
+            yaml
+        );
+        let mut file = File::from_str(&file).unwrap();
+        let mut input = file.tests.remove(0).stages.remove(0).stage.input;
+        input.do_magic();
+        input
+    }
+
+    #[test]
+    fn encodes_post_data() {
+        let cases = [
+            ("", ""),
+            ("hello=world", "hello=world"),
+            ("foo bar", "foo+bar"),
+            ("name=panda&food=bamboo", "name=panda&food=bamboo"),
+            ("foo=%3D", "foo=%3D"),
+            ("foo=bar+baz", "foo=bar+baz"),
+            ("a=b=c", "a=b%3Dc"),
+            (
+                "param=<script>alert(1)</script>",
+                "param=%3Cscript%3Ealert%281%29%3C%2Fscript%3E",
+            ),
+            ("param=alert(1);", "param=alert(1);"),
+            ("bad=%zz", "bad=%zz"),
+        ];
+        for (original, encoded) in cases {
+            assert_eq!(
+                encode_data(Some(DEFAULT_CONTENT_TYPE), original),
+                encoded,
+                "{}",
+                original
+            );
+        }
+    }
+
+    #[test]
+    fn only_encodes_form_data() {
+        assert_eq!(encode_data(Some("text/plain"), "foo bar"), "foo bar");
+        assert_eq!(encode_data(None, "foo bar"), "foo bar");
+        assert_eq!(
+            encode_data(Some("Application/X-WWW-Form-Urlencoded"), "foo bar"),
+            "foo+bar"
+        );
+    }
+
+    #[test]
+    fn adds_default_headers() {
+        let input = input("            port: 8080\n");
+        assert_eq!(header(&input.headers, "Host"), Some("localhost:8080"));
+        assert_eq!(
+            header(&input.headers, "User-Agent"),
+            Some(DEFAULT_USER_AGENT)
+        );
+        assert_eq!(header(&input.headers, "Accept"), Some(DEFAULT_ACCEPT));
+        assert_eq!(header(&input.headers, "Content-Length"), Some("0"));
+        assert_eq!(header(&input.headers, "Content-Type"), None);
+    }
+
+    #[test]
+    fn keeps_explicit_headers() {
+        let input = input(
+            "            headers:\n              host: example.com\n              user-agent: curl\n              ACCEPT: text/html\n              content-type: text/plain\n            data: foo bar\n",
+        );
+        assert_eq!(input.headers.len(), 5);
+        assert_eq!(header(&input.headers, "Host"), Some("example.com"));
+        assert_eq!(header(&input.headers, "User-Agent"), Some("curl"));
+        assert_eq!(header(&input.headers, "Accept"), Some("text/html"));
+        assert_eq!(header(&input.headers, "Content-Type"), Some("text/plain"));
+        assert_eq!(header(&input.headers, "Content-Length"), Some("7"));
+    }
+
+    #[test]
+    fn sets_content_length_of_encoded_data() {
+        let input = input("            data: foo bar<>\n");
+        assert_eq!(
+            header(&input.headers, "Content-Type"),
+            Some(DEFAULT_CONTENT_TYPE)
+        );
+        let data: Option<String> = input.data.into();
+        assert_eq!(data.as_deref(), Some("foo+bar%3C%3E"));
+        assert_eq!(header(&input.headers, "Content-Length"), Some("13"));
+    }
+
+    #[test]
+    fn fixes_multipart_line_endings() {
+        let input = input(
+            "            headers:\n              Content-Type: multipart/form-data; boundary=x\n            data: \"--x\\nContent-Disposition: form-data; name=a\\r\\n\\r\\n1\\n--x--\"\n",
+        );
+        let data: Option<String> = input.data.into();
+        assert_eq!(
+            data.as_deref(),
+            Some("--x\r\nContent-Disposition: form-data; name=a\r\n\r\n1\r\n--x--")
+        );
+        assert_eq!(header(&input.headers, "Content-Length"), Some("55"));
+    }
+
+    #[test]
+    fn stop_magic_changes_nothing() {
+        let input = input("            stop_magic: true\n            data: 'foo bar\\r\\n'\n");
+        assert!(input.headers.is_empty());
+        let data: Option<String> = input.data.into();
+        assert_eq!(data.as_deref(), Some("foo bar\\r\\n"));
+    }
+}
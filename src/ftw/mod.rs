@@ -1,10 +1,23 @@
+use crate::engine::transforms::base64_decode;
 use bytes::{BufMut, Bytes, BytesMut};
-use http::header::{HeaderName, HeaderValue};
 use hyper::{Body, Request, Uri};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+pub mod cookies;
+pub mod expect;
+pub mod filter;
+pub mod http1;
+pub mod in_process;
+pub mod log;
+pub mod magic;
+pub mod runner;
+
+pub use filter::{Filter, Overrides};
+pub use in_process::InProcessRunner;
+pub use runner::{Runner, RunnerConfig};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -14,6 +27,17 @@ pub enum Error {
     YamlError(#[from] serde_yaml::Error),
     #[error(transparent)]
     HttpError(#[from] http::Error),
+    #[error("invalid port {0}")]
+    InvalidPort(u32),
+    #[error("failed to load certificates from {}, {reason}", path.display())]
+    CertificateError { path: PathBuf, reason: String },
+    #[error("invalid raw request, {0}")]
+    RawRequestError(#[from] http1::Error),
+    #[error("invalid {field} regex, {source}")]
+    InvalidRegex {
+        field: &'static str,
+        source: regex::Error,
+    },
 }
 
 mod defaults {
@@ -46,6 +70,34 @@ mod defaults {
     pub fn uri() -> String {
         "/".into()
     }
+
+    pub fn is_addr(addr: &str) -> bool {
+        addr == self::addr()
+    }
+
+    pub fn is_port(port: &u32) -> bool {
+        *port == self::port()
+    }
+
+    pub fn is_method(method: &str) -> bool {
+        method == self::method()
+    }
+
+    pub fn is_protocol(protocol: &str) -> bool {
+        protocol == self::protocol()
+    }
+
+    pub fn is_uri(uri: &str) -> bool {
+        uri == self::uri()
+    }
+}
+
+/// A host as it's written in a URI, in brackets if it's an IPv6 address.
+pub(crate) fn uri_host(host: &str) -> String {
+    match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{}]", host),
+        false => host.into(),
+    }
 }
 
 #[inline]
@@ -58,16 +110,50 @@ fn is_true(b: &bool) -> bool {
     *b == true
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The HTTP version of a request. Versions other than those that can be sent with an HTTP
+/// client are kept as they are, so they can be sent in a raw request line.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum HttpVersion {
-    #[serde(rename = "HTTP/1.0")]
     Http1_0,
-    #[serde(rename = "HTTP/1.1")]
     Http1_1,
-    #[serde(rename = "HTTP/2", alias = "HTTP/2.0")]
+    /// Sent with prior knowledge over `http`, or negotiated with ALPN over `https`.
     Http2_0,
-    #[serde(other)]
-    Unknown,
+    Unknown(String),
+}
+
+impl From<String> for HttpVersion {
+    fn from(version: String) -> Self {
+        match version.as_str() {
+            "HTTP/1.0" => Self::Http1_0,
+            "HTTP/1.1" => Self::Http1_1,
+            "HTTP/2" | "HTTP/2.0" => Self::Http2_0,
+            _ => Self::Unknown(version),
+        }
+    }
+}
+
+impl From<HttpVersion> for String {
+    fn from(version: HttpVersion) -> Self {
+        match version {
+            HttpVersion::Http1_0 => "HTTP/1.0".into(),
+            HttpVersion::Http1_1 => "HTTP/1.1".into(),
+            HttpVersion::Http2_0 => "HTTP/2".into(),
+            HttpVersion::Unknown(version) => version,
+        }
+    }
+}
+
+impl HttpVersion {
+    /// The version as understood by `http`, or `None` for unknown versions.
+    pub fn as_http(&self) -> Option<http::Version> {
+        match self {
+            Self::Http1_0 => Some(http::Version::HTTP_10),
+            Self::Http1_1 => Some(http::Version::HTTP_11),
+            Self::Http2_0 => Some(http::Version::HTTP_2),
+            Self::Unknown(_) => None,
+        }
+    }
 }
 
 impl Default for HttpVersion {
@@ -76,7 +162,14 @@ impl Default for HttpVersion {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl HttpVersion {
+    #[inline]
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Meta {
     #[serde(default, skip_serializing_if = "String::is_empty")]
@@ -89,12 +182,17 @@ pub struct Meta {
     pub enabled: bool,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[serde(from = "InputData")]
 #[serde(into = "Option<String>")]
 pub struct Data(Option<String>);
 
 impl Data {
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        self.0.is_none()
+    }
+
     pub fn replace_escaped_crlf(&mut self) {
         lazy_static::lazy_static! {
             static ref ESCAPED_CRLF: Regex = Regex::new(r"\\r\\n").unwrap();
@@ -111,9 +209,9 @@ impl Data {
 #[serde(untagged, deny_unknown_fields)]
 pub enum InputData {
     None,
-    // Note: literals \r and \n will be replaced be replaced with CRLF when stop_magic is on. Note: if
-    // urlencoded content-type header is provided and parameters aren't in name=value form, data will
-    // be made empty, unless stop_magic is on.
+    // Note: literals \r and \n will be replaced with CRLF unless stop_magic is on. Note: if the
+    // content type is urlencoded and the data isn't encoded yet, it will be url-encoded, unless
+    // stop_magic is on.
     Text(String),
     Lines(Vec<String>),
 }
@@ -135,26 +233,32 @@ impl From<Data> for Option<String> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Input {
     /// <IP or DNS> def = localhost
-    #[serde(default = "defaults::addr")]
+    #[serde(default = "defaults::addr", skip_serializing_if = "defaults::is_addr")]
     pub dest_addr: String,
-    #[serde(default = "defaults::port")]
+    #[serde(default = "defaults::port", skip_serializing_if = "defaults::is_port")]
     pub port: u32,
-    #[serde(default = "defaults::method")]
+    #[serde(
+        default = "defaults::method",
+        skip_serializing_if = "defaults::is_method"
+    )]
     pub method: String,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub headers: HashMap<String, String>,
     /// http or https
-    #[serde(default = "defaults::protocol")]
+    #[serde(
+        default = "defaults::protocol",
+        skip_serializing_if = "defaults::is_protocol"
+    )]
     pub protocol: String,
-    #[serde(default = "defaults::uri")]
+    #[serde(default = "defaults::uri", skip_serializing_if = "defaults::is_uri")]
     pub uri: String,
-    #[serde(default = "Default::default")]
+    #[serde(default, skip_serializing_if = "HttpVersion::is_default")]
     pub version: HttpVersion,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Data::is_none")]
     pub data: Data,
     /// If there are multiple stages and save cookie is set, it will automatically be provided in
     /// the next stage if the site in question provides the Set-Cookie response header.
@@ -165,6 +269,10 @@ pub struct Input {
     /// encoding, etc. When stop_magic is on, the framework will not do anything automagically.
     #[serde(default, skip_serializing_if = "is_false")]
     pub stop_magic: bool,
+    /// go-ftw's replacement for `stop_magic`: when false, the framework doesn't do anything
+    /// automagically. Default = true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autocomplete_headers: Option<bool>,
     /// Description: This argument will take a base64 encoded string that will be decoded and sent
     /// through as the request. It will override all other settings
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -176,28 +284,44 @@ pub struct Input {
 }
 
 impl Input {
+    /// Whether the framework may fill in defaults, which `stop_magic` or
+    /// `autocomplete_headers: false` turn off.
+    #[inline]
+    pub fn is_magic(&self) -> bool {
+        !self.stop_magic && self.autocomplete_headers != Some(false)
+    }
+
+    /// Fills in the defaults that FTW adds unless `stop_magic` is set, see [`magic`]. This is
+    /// done when the request is built, so that the input can be written back as it was read.
     pub fn do_magic(&mut self) {
         // not actually magic, just something we need to do
         self.replace_invalid_query_string_chars();
+        if !self.is_magic() {
+            return;
+        }
 
-        // FTW does some things automatically if stop_magic == false, so we do them here.
-        if !self.stop_magic {
-            // replace "\\r\\n" with actual CRLFs
-            self.data.replace_escaped_crlf();
-
-            // default content type to "application/x-www-form-urlencoded" if we have any data
-            if let Some(text) = &self.data.0 {
-                if !text.is_empty()
-                    && !self.headers.contains_key("Content-Type")
-                    && !self.headers.contains_key("content-type")
-                {
-                    self.headers.insert(
-                        "Content-Type".into(),
-                        "application/x-www-form-urlencoded".into(),
-                    );
-                }
+        // replace "\\r\\n" with actual CRLFs
+        self.data.replace_escaped_crlf();
+
+        if let Some(text) = self.data.0.as_mut().filter(|text| !text.is_empty()) {
+            magic::add_default_header(
+                &mut self.headers,
+                "Content-Type",
+                magic::DEFAULT_CONTENT_TYPE,
+            );
+            let content_type = magic::header(&self.headers, "Content-Type");
+            *text = magic::encode_data(content_type, text);
+            if content_type.is_some_and(|ct| ct.starts_with("multipart/form-data")) {
+                *text = magic::crlf_line_endings(text);
             }
         }
+
+        let length = self.data.0.as_ref().map_or(0, String::len);
+        magic::add_default_header(&mut self.headers, "Content-Length", &length.to_string());
+        let host = self.host_header();
+        magic::add_default_header(&mut self.headers, "Host", &host);
+        magic::add_default_header(&mut self.headers, "User-Agent", magic::DEFAULT_USER_AGENT);
+        magic::add_default_header(&mut self.headers, "Accept", magic::DEFAULT_ACCEPT);
     }
 
     fn replace_invalid_query_string_chars(&mut self) {
@@ -224,41 +348,96 @@ impl Input {
         self.uri = String::from_utf8(uri_new.to_vec()).unwrap();
     }
 
-    pub fn uri(&self) -> Result<Uri, http::Error> {
-        Uri::builder()
+    /// The port, which must be a valid TCP port.
+    pub fn port(&self) -> Result<u16, Error> {
+        u16::try_from(self.port).map_err(|_| Error::InvalidPort(self.port))
+    }
+
+    #[inline]
+    fn host(&self) -> String {
+        uri_host(&self.dest_addr)
+    }
+
+    /// The value of the `Host` header that a client would send to the destination, which only
+    /// includes the port if it isn't the protocol's default.
+    pub fn host_header(&self) -> String {
+        match (self.protocol.as_str(), self.port) {
+            ("http", 80) | ("https", 443) => self.host(),
+            (_, port) => format!("{}:{}", self.host(), port),
+        }
+    }
+
+    pub fn uri(&self) -> Result<Uri, Error> {
+        Ok(Uri::builder()
             .scheme(self.protocol.as_str())
-            .authority(format!("localhost:{}", self.port))
+            .authority(format!("{}:{}", self.host(), self.port()?))
             .path_and_query(self.uri.as_str())
-            .build()
+            .build()?)
     }
 
-    pub fn request(&self) -> Result<Request<Vec<u8>>, http::Error> {
+    /// The bytes of `encoded_request` or `raw_request`, which are sent as they are in place of a
+    /// request built from the other fields. `encoded_request` takes precedence.
+    pub fn raw_bytes(&self) -> Option<Vec<u8>> {
+        match (&self.encoded_request, &self.raw_request) {
+            (Some(encoded), _) => Some(base64_decode(encoded.as_bytes(), true)),
+            (None, Some(raw)) => Some(raw.clone().into_bytes()),
+            (None, None) => None,
+        }
+    }
+
+    /// The request of the stage, with the defaults filled in by [`Input::do_magic`]. A raw
+    /// request is parsed leniently, so that malformed requests can still be evaluated.
+    pub fn request(&self) -> Result<Request<Vec<u8>>, Error> {
+        if let Some(raw) = self.raw_bytes() {
+            return Ok(http1::parse_request(&raw)?);
+        }
+        let mut input = self.clone();
+        input.do_magic();
+        input.build_request()
+    }
+
+    fn build_request(&self) -> Result<Request<Vec<u8>>, Error> {
+        // unknown versions are only sent as they are in raw requests
+        let version = self.version.as_http().unwrap_or(http::Version::HTTP_11);
         let mut builder = Request::builder()
             .method(self.method.as_str())
-            .uri(self.uri()?);
+            .uri(self.uri()?)
+            .version(version);
 
+        // invalid headers are reported when the body is set
         for (header, value) in &self.headers {
-            let name = HeaderName::from_bytes(header.as_bytes())?;
-            let value: HeaderValue = value.parse()?;
-            builder = builder.header(name, value);
+            builder = builder.header(header.as_str(), value.as_str());
         }
 
-        if let Some(body) = self.data.0.as_ref() {
-            builder.body(body.clone().into_bytes())
-        } else {
-            builder.body(Default::default())
-        }
+        let body = self.data.0.as_ref().map(|body| body.clone().into_bytes());
+        Ok(builder.body(body.unwrap_or_default())?)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged, deny_unknown_fields)]
 pub enum OutputStatus {
     Status(u32),
     Any(Vec<u32>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The rules that go-ftw expects in the log, by id or by regex.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogOutput {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expect_ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_expect_ids: Vec<u32>,
+    /// Regex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_regex: Option<String>,
+    /// Regex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_match_regex: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Output {
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -274,9 +453,24 @@ pub struct Output {
     pub no_log_contains: Option<String>,
     #[serde(default, skip_serializing_if = "is_false")]
     pub expect_error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<LogOutput>,
+    /// Whether the stage is run again if it fails, for stages that depend on timing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_once: Option<bool>,
+    /// Whether the rules in `log.expect_ids` must be the only rules in the log.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub isolated: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Output {
+    #[inline]
+    pub fn is_retried(&self) -> bool {
+        self.retry_once == Some(true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Stage {
     pub input: Input,
@@ -284,25 +478,100 @@ pub struct Stage {
     pub output: Option<Output>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
+/// A stage of a test. Legacy FTW files wrap each stage in a `stage` key, while go-ftw files
+/// list the stages directly, and the stage is written back the way it was read.
+#[derive(Debug, Clone, PartialEq)]
 pub struct StageWrapper {
     pub stage: Stage,
+    wrapped: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl StageWrapper {
+    /// Wraps a stage, which is written in a `stage` key if `wrapped` is set.
+    pub fn new(stage: Stage, wrapped: bool) -> Self {
+        Self { stage, wrapped }
+    }
+
+    #[inline]
+    pub fn is_wrapped(&self) -> bool {
+        self.wrapped
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WrappedStage<S> {
+    stage: S,
+}
+
+impl Serialize for StageWrapper {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.wrapped {
+            true => WrappedStage { stage: &self.stage }.serialize(serializer),
+            false => self.stage.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StageWrapper {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+
+        // decided by the key rather than with an untagged enum, to keep the errors of the stage
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        let wrapped = value.get("stage").is_some();
+        let stage = match wrapped {
+            true => serde_yaml::from_value::<WrappedStage<Stage>>(value).map(|w| w.stage),
+            false => serde_yaml::from_value(value),
+        };
+        stage
+            .map(|stage| Self { stage, wrapped })
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Test {
+    /// The title of a legacy FTW test. go-ftw tests have a `test_id` instead.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub test_title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_id: Option<u32>,
+    /// The rule the test is for, from the file's `rule_id`, or from the title of a legacy test
+    /// such as `920100-1`.
+    #[serde(skip)]
+    pub rule_id: Option<u32>,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub desc: String,
     pub stages: Vec<StageWrapper>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Test {
+    /// The title of the test, which go-ftw derives from the rule and test ids, e.g. `920100-1`.
+    pub fn title(&self) -> String {
+        match (&self.test_title, self.rule_id, self.test_id) {
+            (title, _, _) if !title.is_empty() => title.clone(),
+            (_, Some(rule_id), Some(test_id)) => format!("{}-{}", rule_id, test_id),
+            (_, None, Some(test_id)) => test_id.to_string(),
+            (_, _, None) => String::new(),
+        }
+    }
+}
+
+/// The rule id that a legacy test title starts with, as in `920100-1`.
+fn title_rule_id(title: &str) -> Option<u32> {
+    let (rule_id, _) = title.split_once('-')?;
+    rule_id.parse().ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct File {
     pub meta: Meta,
+    /// The rule that the tests of a go-ftw file are for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<u32>,
     pub tests: Vec<Test>,
     // disallow construction outside of this module?
     #[serde(default, skip)]
@@ -310,14 +579,27 @@ pub struct File {
 }
 
 impl File {
+    /// Reads a legacy FTW or go-ftw file.
     pub fn from_str(s: &str) -> Result<Self, Error> {
-        serde_yaml::from_str(s)
-            .map_err(Into::into)
-            .map(|mut file: Self| {
-                // FIXME: some sort of initialization magic
-                file.inputs_mut().for_each(Input::do_magic);
-                file
-            })
+        let mut file: Self = serde_yaml::from_str(s)?;
+        for test in &mut file.tests {
+            test.rule_id = file.rule_id.or_else(|| title_rule_id(&test.test_title));
+        }
+        Ok(file)
+    }
+
+    /// Writes the file back in the schema it was read in.
+    pub fn to_yaml(&self) -> Result<String, Error> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Whether the file uses the go-ftw schema rather than the legacy FTW one.
+    pub fn is_go_ftw(&self) -> bool {
+        self.rule_id.is_some()
+            || self
+                .tests
+                .iter()
+                .any(|test| test.test_id.is_some() || test.stages.iter().any(|s| !s.wrapped))
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Error> {
@@ -344,3 +626,122 @@ impl File {
         self.stages_mut().map(|s| &mut s.input)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads a file, writes it back, and checks that reading it again gives the same file.
+    fn round_trip(yaml: &str) -> File {
+        let file = File::from_str(yaml).unwrap();
+        let written = file.to_yaml().unwrap();
+        let reloaded = File::from_str(&written).unwrap();
+        assert_eq!(reloaded, file, "written as:\n{}", written);
+        reloaded
+    }
+
+    #[test]
+    fn round_trips_legacy_files() {
+        let file = round_trip(
+            r#"
+meta:
+  author: csanders-git
+  description: Tests of the body
+  enabled: true
+  name: 920100.yaml
+tests:
+  - test_title: 920100-1
+    desc: A multiline body
+    stages:
+      - stage:
+          input:
+            dest_addr: 127.0.0.1
+            port: 80
+            method: POST
+            headers:
+              User-Agent: OWASP CRS test agent
+              Host: localhost
+            uri: /?a=b c
+            version: HTTP/1.0
+            data:
+              - first=1
+              - second=2
+            save_cookie: true
+          output:
+            status: [200, 403]
+            log_contains: id "920100"
+  - test_title: 920100-2
+    stages:
+      - stage:
+          input:
+            stop_magic: true
+            raw_request: "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"
+          output:
+            no_log_contains: id "920100"
+            expect_error: true
+"#,
+        );
+        assert!(!file.is_go_ftw());
+        assert!(file.tests.iter().all(|test| test.stages[0].is_wrapped()));
+        assert_eq!(file.tests[0].rule_id, Some(920100));
+        assert_eq!(
+            file.tests[0].stages[0].stage.input.data,
+            Data(Some("first=1\r\nsecond=2".into()))
+        );
+    }
+
+    #[test]
+    fn round_trips_go_ftw_files() {
+        let file = round_trip(
+            r#"
+meta:
+  author: fzipi
+  enabled: false
+rule_id: 920100
+tests:
+  - test_id: 1
+    desc: An encoded request
+    stages:
+      - input:
+          dest_addr: localhost
+          protocol: https
+          port: 443
+          autocomplete_headers: false
+          encoded_request: R0VUIC8gSFRUUC8xLjENCkhvc3Q6IGxvY2FsaG9zdA0KDQo=
+        output:
+          log:
+            expect_ids: [920100, 920101]
+            no_expect_ids: [920102]
+          isolated: true
+          retry_once: true
+  - test_id: 2
+    stages:
+      - input:
+          uri: /get
+          version: HTTP/2
+        output:
+          status: 200
+          response_contains: hello
+          log:
+            match_regex: id[:\s"]*920100
+"#,
+        );
+        assert!(file.is_go_ftw());
+        assert!(!file.meta.enabled);
+        assert!(file.tests.iter().all(|test| !test.stages[0].is_wrapped()));
+        assert_eq!(file.tests[0].title(), "920100-1");
+
+        let stage = &file.tests[0].stages[0].stage;
+        assert_eq!(
+            stage.input.raw_bytes().unwrap(),
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+        let output = stage.output.as_ref().unwrap();
+        assert_eq!(output.log.as_ref().unwrap().expect_ids, [920100, 920101]);
+        assert!(output.isolated);
+        assert_eq!(
+            file.tests[1].stages[0].stage.input.version,
+            HttpVersion::Http2_0
+        );
+    }
+}
@@ -0,0 +1,876 @@
+//! Runs FTW tests by sending each stage's request to a WAF and checking the response against the
+//! stage's expected output.
+
+use super::cookies::{request_host, CookieJar};
+use super::expect::{Expectations, Outcome};
+use super::filter::Selected;
+use super::log::{new_marker, LogConfig, WafLog};
+use super::{http1, uri_host, Error, File, HttpVersion, Input, Output, Stage, Test};
+use http::header::HOST;
+use http::HeaderValue;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use tokio_rustls::TlsConnector;
+
+/// Where and how the runner sends requests.
+#[derive(Debug, Clone)]
+pub struct RunnerConfig {
+    /// The host that requests are sent to in place of each stage's `dest_addr`, e.g. to test a
+    /// WAF that the tests' destination doesn't resolve to. The `Host` header and the TLS server
+    /// name are still the stage's destination.
+    pub host: Option<String>,
+    /// The port that requests are sent to in place of each stage's `port`.
+    pub port: Option<u16>,
+    /// How long to wait for each response.
+    pub timeout: Duration,
+    /// The WAF log, used to check `log_contains`, `no_log_contains` and `log`. Without it,
+    /// stages that check the log are skipped.
+    pub log: Option<LogConfig>,
+    /// PEM files of certificates to trust for `https`, in addition to the public web roots,
+    /// e.g. the CA of a local test certificate.
+    pub ca_certificates: Vec<PathBuf>,
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: None,
+            timeout: Duration::from_secs(10),
+            log: None,
+            ca_certificates: vec![],
+        }
+    }
+}
+
+/// The result of running a stage, or a whole test.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Status {
+    Passed,
+    /// The response didn't match the expected output, for the given reason.
+    Failed(String),
+    /// The expected output couldn't be checked, for the given reason.
+    Skipped(String),
+}
+
+impl Status {
+    #[inline]
+    pub fn is_passed(&self) -> bool {
+        *self == Self::Passed
+    }
+
+    #[inline]
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+
+    #[inline]
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, Self::Skipped(_))
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Passed => write!(f, "passed"),
+            Status::Failed(reason) => write!(f, "failed: {}", reason),
+            Status::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StageResult {
+    pub status: Status,
+    /// Time taken to send the request and receive the response.
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub title: String,
+    /// The overall result: failed if any stage failed, skipped if any stage was skipped, and
+    /// passed otherwise.
+    pub status: Status,
+    /// Results of the stages that ran. Stages after a failed stage don't run, since later
+    /// stages usually depend on earlier ones.
+    pub stages: Vec<StageResult>,
+    /// Why the overrides changed the status, if they did, and what the status was before.
+    pub forced: Option<String>,
+}
+
+impl TestResult {
+    pub(crate) fn new(test: &Test) -> Self {
+        Self {
+            title: test.title(),
+            status: Status::Passed,
+            stages: vec![],
+            forced: None,
+        }
+    }
+
+    /// The result of a test that doesn't run, for the given reason.
+    pub(crate) fn skipped(test: &Test, reason: String) -> Self {
+        Self {
+            status: Status::Skipped(reason),
+            ..Self::new(test)
+        }
+    }
+
+    /// The result of a test in a disabled file.
+    pub(crate) fn disabled(test: &Test) -> Self {
+        Self::skipped(test, "the test file is disabled".into())
+    }
+
+    /// Adds the result of the next stage, returning false if the remaining stages shouldn't run.
+    pub(crate) fn push_stage(&mut self, result: StageResult) -> bool {
+        let index = self.stages.len() + 1;
+        let failed = match &result.status {
+            Status::Passed => false,
+            Status::Failed(reason) => {
+                self.status = Status::Failed(format!("stage {}: {}", index, reason));
+                true
+            }
+            Status::Skipped(reason) => {
+                if self.status.is_passed() {
+                    self.status = Status::Skipped(format!("stage {}: {}", index, reason));
+                }
+                false
+            }
+        };
+        self.stages.push(result);
+        !failed
+    }
+}
+
+/// A response, or the error that prevented one from being received.
+pub(crate) type Response = Result<http::Response<Vec<u8>>, String>;
+
+/// Compiles the expected output of a stage, failing the stage if it's invalid.
+pub(crate) fn compile_expectations(stage: &Stage) -> Result<Option<Expectations>, Status> {
+    stage
+        .output
+        .as_ref()
+        .map(Expectations::compile)
+        .transpose()
+        .map_err(|error| Status::Failed(error.to_string()))
+}
+
+/// Checks the response of a stage, and the log lines it produced if available.
+pub(crate) fn stage_status(
+    expectations: Option<&Expectations>,
+    response: &Response,
+    log: Option<&str>,
+) -> Status {
+    let expectations = match expectations {
+        Some(expectations) => expectations,
+        None => {
+            return match response {
+                Ok(_) => Status::Passed,
+                Err(error) => Status::Failed(error.clone()),
+            }
+        }
+    };
+    let outcome = Outcome {
+        response: match response {
+            Ok(response) => Ok((response.status().as_u16(), response.body().as_slice())),
+            Err(error) => Err(error.as_str()),
+        },
+        log,
+    };
+    let assessment = expectations.check(&outcome);
+    if !assessment.mismatches.is_empty() {
+        Status::Failed(assessment.to_string())
+    } else if !assessment.unchecked.is_empty() {
+        Status::Skipped(format!(
+            "{} can't be checked without the WAF log",
+            assessment.unchecked.join(" and ")
+        ))
+    } else {
+        Status::Passed
+    }
+}
+
+/// The host and path a request is sent to, which decide the cookies it can set.
+pub(crate) fn origin<T>(request: &http::Request<T>) -> (String, String) {
+    (request_host(request), request.uri().path().to_string())
+}
+
+/// The TLS configuration for `https`, trusting the public web roots and the configured
+/// certificates.
+fn tls_config(config: &RunnerConfig) -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    for path in &config.ca_certificates {
+        let error = |reason: String| Error::CertificateError {
+            path: path.clone(),
+            reason,
+        };
+        let file = fs::File::open(path).map_err(|e| error(e.to_string()))?;
+        let certificates = rustls_pemfile::certs(&mut io::BufReader::new(file))
+            .map_err(|e| error(e.to_string()))?;
+        if certificates.is_empty() {
+            return Err(error("no PEM certificates found".into()));
+        }
+        for certificate in certificates {
+            roots
+                .add(&Certificate(certificate))
+                .map_err(|e| error(e.to_string()))?;
+        }
+    }
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Connects hyper's requests to the host that the config sends requests to, if any. The URI
+/// keeps the stage's destination, which is the TLS server name and the HTTP/2 authority.
+#[derive(Debug, Clone)]
+struct Connector {
+    http: HttpConnector,
+    host: Option<String>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = <HttpConnector as Service<Uri>>::Response;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = <HttpConnector as Service<Uri>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let uri = match &self.host {
+            Some(host) => {
+                let authority = match uri.port_u16() {
+                    Some(port) => format!("{}:{}", uri_host(host), port),
+                    None => uri_host(host),
+                };
+                let mut parts = uri.clone().into_parts();
+                match authority.parse() {
+                    Ok(authority) => {
+                        parts.authority = Some(authority);
+                        Uri::from_parts(parts).unwrap_or(uri)
+                    }
+                    Err(_) => uri,
+                }
+            }
+            None => uri,
+        };
+        self.http.call(uri)
+    }
+}
+
+/// A connection that a raw request can be sent over, with or without TLS.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Sends the requests of FTW tests to a WAF and checks its responses.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    config: RunnerConfig,
+    client: Client<HttpsConnector<Connector>>,
+    /// Sends HTTP/2 requests, with prior knowledge over `http`.
+    http2_client: Client<HttpsConnector<Connector>>,
+    tls: Arc<ClientConfig>,
+    log: Option<WafLog>,
+}
+
+impl Runner {
+    pub fn new(config: RunnerConfig) -> Result<Self, Error> {
+        let tls = tls_config(&config)?;
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let connector = Connector {
+            http,
+            host: config.host.clone(),
+        };
+        let builder = || {
+            HttpsConnectorBuilder::new()
+                .with_tls_config(tls.clone())
+                .https_or_http()
+        };
+        Ok(Self {
+            client: Client::builder()
+                .build(builder().enable_http1().wrap_connector(connector.clone())),
+            http2_client: Client::builder()
+                .http2_only(true)
+                .build(builder().enable_http2().wrap_connector(connector)),
+            tls: Arc::new(tls),
+            log: config.log.clone().map(WafLog::new),
+            config,
+        })
+    }
+
+    #[inline]
+    pub fn config(&self) -> &RunnerConfig {
+        &self.config
+    }
+
+    /// Runs every test of a file, in order.
+    pub async fn run_file(&self, file: &File) -> Vec<TestResult> {
+        let mut results = vec![];
+        for test in &file.tests {
+            let result = match file.meta.enabled {
+                true => self.run_test(test).await,
+                false => TestResult::disabled(test),
+            };
+            results.push(result);
+        }
+        results
+    }
+
+    /// Runs the tests chosen by a [`Filter`](super::Filter), in order, reporting the others as
+    /// skipped.
+    pub async fn run_selected(&self, tests: &[Selected]) -> Vec<TestResult> {
+        let mut results = vec![];
+        for selected in tests {
+            let result = match selected.skipped_result() {
+                Some(result) => result,
+                None => selected.finish(self.run_test(&selected.test).await),
+            };
+            results.push(result);
+        }
+        results
+    }
+
+    /// Runs the stages of a test in order, stopping at the first stage that fails.
+    pub async fn run_test(&self, test: &Test) -> TestResult {
+        let mut result = TestResult::new(test);
+        let mut jar = CookieJar::new();
+        for wrapper in &test.stages {
+            if !result.push_stage(self.run_stage(&wrapper.stage, &mut jar).await) {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Sends the request of a stage with the cookies in the jar, and checks the response. If
+    /// the stage has `save_cookie` set, the cookies set by the response are stored in the jar,
+    /// and if it has `retry_once` set, it's sent again if it fails.
+    pub async fn run_stage(&self, stage: &Stage, jar: &mut CookieJar) -> StageResult {
+        let result = self.attempt_stage(stage, jar).await;
+        let retried = stage.output.as_ref().is_some_and(Output::is_retried);
+        match result.status.is_failed() && retried {
+            true => self.attempt_stage(stage, jar).await,
+            false => result,
+        }
+    }
+
+    async fn attempt_stage(&self, stage: &Stage, jar: &mut CookieJar) -> StageResult {
+        // the expectations are compiled first, so an invalid test doesn't send any request
+        let expectations = match compile_expectations(stage) {
+            Ok(expectations) => expectations,
+            Err(status) => {
+                return StageResult {
+                    status,
+                    duration: Duration::ZERO,
+                }
+            }
+        };
+
+        // the log is only read for stages that check it, since the markers need extra requests
+        let log = self
+            .log
+            .as_ref()
+            .filter(|_| expectations.as_ref().is_some_and(Expectations::needs_log));
+        let started = Instant::now();
+        let (response, log) = match log {
+            Some(log) => match self.send_marked(stage, jar, log).await {
+                Ok((response, lines)) => (response, Some(lines)),
+                Err(error) => {
+                    return StageResult {
+                        status: Status::Failed(error),
+                        duration: started.elapsed(),
+                    }
+                }
+            },
+            None => (self.send(stage, jar).await, None),
+        };
+        let duration = started.elapsed();
+
+        let status = stage_status(expectations.as_ref(), &response, log.as_deref());
+        StageResult { status, duration }
+    }
+
+    /// Sends a stage's request between a start and an end marker request, returning the
+    /// response and the log lines between the markers.
+    async fn send_marked(
+        &self,
+        stage: &Stage,
+        jar: &mut CookieJar,
+        log: &WafLog,
+    ) -> Result<(Response, String), String> {
+        let offset = log.offset().await.map_err(|error| {
+            format!("failed to read {}, {}", log.config().path.display(), error)
+        })?;
+        let start = new_marker();
+        self.send_marker(log, &start, &stage.input).await?;
+        let response = self.send(stage, jar).await;
+        let end = new_marker();
+        self.send_marker(log, &end, &stage.input).await?;
+        let lines = log.wait_for_lines(offset, &start, &end).await?;
+        Ok((response, lines))
+    }
+
+    /// Sends a marker request to the same destination as a stage.
+    async fn send_marker(&self, log: &WafLog, marker: &str, input: &Input) -> Result<(), String> {
+        let request = hyper::Request::get(self.target_uri(input, "/")?)
+            .header(HOST, input.host_header())
+            .header(log.config().marker_header.as_str(), marker)
+            .body(Body::empty())
+            .map_err(|error| format!("invalid marker request, {}", error))?;
+        self.exchange(&self.client, request)
+            .await
+            .map(|_| ())
+            .map_err(|error| format!("marker request failed, {}", error))
+    }
+
+    /// The host and port that a stage's request is sent to, unless the config overrides them.
+    fn destination(&self, input: &Input) -> Result<(String, u16), String> {
+        let port = match self.config.port {
+            Some(port) => port,
+            None => input.port().map_err(|error| error.to_string())?,
+        };
+        let host = self.config.host.as_ref().unwrap_or(&input.dest_addr);
+        Ok((host.clone(), port))
+    }
+
+    /// The URI of a path at the destination of a stage, with the port that the request is sent
+    /// to. The host is the stage's `dest_addr` even if the config overrides it, since it's the
+    /// TLS server name, and the [`Connector`] connects to the override instead.
+    fn target_uri(&self, input: &Input, path_and_query: &str) -> Result<Uri, String> {
+        let (_, port) = self.destination(input)?;
+        Uri::builder()
+            .scheme(input.protocol.as_str())
+            .authority(format!("{}:{}", uri_host(&input.dest_addr), port))
+            .path_and_query(path_and_query)
+            .build()
+            .map_err(|error| format!("invalid request, {}", error))
+    }
+
+    async fn send(&self, stage: &Stage, jar: &mut CookieJar) -> Response {
+        let input = &stage.input;
+        // raw requests are sent as they are, without the jar's cookies
+        let (response, origin) = match input.raw_bytes() {
+            Some(raw) => {
+                let origin = http1::parse_request(&raw)
+                    .ok()
+                    .map(|request| origin(&request));
+                (self.send_raw(&raw, input).await, origin)
+            }
+            None => {
+                let mut request = input
+                    .request()
+                    .map_err(|error| format!("invalid request, {}", error))?;
+                jar.add_to(&mut request, SystemTime::now());
+                let origin = origin(&request);
+                // unless the magic is off, the Host header is the stage's destination, even if the
+                // request is sent elsewhere, while HTTP/2 uses the URI's authority instead
+                let is_http2 = input.version == HttpVersion::Http2_0;
+                if input.is_magic() && !is_http2 && !request.headers().contains_key(HOST) {
+                    if let Ok(host) = HeaderValue::from_str(&input.host_header()) {
+                        request.headers_mut().insert(HOST, host);
+                    }
+                }
+
+                let response = match &input.version {
+                    HttpVersion::Unknown(version) => {
+                        let raw = http1::serialize_request(&request, version);
+                        self.send_raw(&raw, input).await
+                    }
+                    // hyper adds a Host header to requests without one, so they're sent as
+                    // they are over the socket
+                    version if !is_http2 && !request.headers().contains_key(HOST) => {
+                        let raw =
+                            http1::serialize_request(&request, &String::from(version.clone()));
+                        self.send_raw(&raw, input).await
+                    }
+                    version => {
+                        let (mut parts, body) = request.into_parts();
+                        let path_and_query =
+                            parts.uri.path_and_query().map_or("/", |pq| pq.as_str());
+                        parts.uri = self.target_uri(input, path_and_query)?;
+                        let request = hyper::Request::from_parts(parts, Body::from(body));
+                        let client = match version {
+                            HttpVersion::Http2_0 => &self.http2_client,
+                            _ => &self.client,
+                        };
+                        self.exchange(client, request).await
+                    }
+                };
+                (response, Some(origin))
+            }
+        };
+
+        if let (true, Ok(response), Some((host, path))) = (input.save_cookie, &response, origin) {
+            jar.store_response(response, &host, &path, SystemTime::now());
+        }
+        response
+    }
+
+    /// Opens a connection to the destination of a stage, with TLS for `https`.
+    async fn connect(&self, input: &Input) -> io::Result<Box<dyn Connection>> {
+        let (host, port) = self
+            .destination(input)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let stream = TcpStream::connect((host.as_str(), port)).await?;
+        match input.protocol.as_str() {
+            "https" => {
+                // the server name is the stage's destination, even if it's sent elsewhere
+                let name = ServerName::try_from(input.dest_addr.as_str())
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+                let connector = TlsConnector::from(self.tls.clone());
+                Ok(Box::new(connector.connect(name, stream).await?))
+            }
+            _ => Ok(Box::new(stream)),
+        }
+    }
+
+    /// Sends a raw request as it is over a new connection, since hyper would normalise it, and
+    /// reads the response until it's complete or the connection is closed.
+    async fn send_raw(&self, raw: &[u8], input: &Input) -> Response {
+        let exchange = async {
+            let mut stream = self.connect(input).await?;
+            stream.write_all(raw).await?;
+            let mut received = vec![];
+            let mut buffer = [0; 8192];
+            let response = loop {
+                let read = stream.read(&mut buffer).await?;
+                received.extend_from_slice(&buffer[..read]);
+                let response = http1::parse_response(&received, read == 0);
+                if response.is_some() || read == 0 {
+                    break response;
+                }
+            };
+            Ok::<_, io::Error>(response)
+        };
+        match tokio::time::timeout(self.config.timeout, exchange).await {
+            Ok(Ok(Some(response))) => Ok(response),
+            Ok(Ok(None)) => Err("the connection was closed without a valid response".into()),
+            Ok(Err(error)) => Err(format!("request failed, {}", error)),
+            Err(_) => Err(format!("no response within {:?}", self.config.timeout)),
+        }
+    }
+
+    async fn exchange(
+        &self,
+        client: &Client<HttpsConnector<Connector>>,
+        request: hyper::Request<Body>,
+    ) -> Response {
+        let exchange = async {
+            let (parts, body) = client.request(request).await?.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>(http::Response::from_parts(parts, body.to_vec()))
+        };
+        match tokio::time::timeout(self.config.timeout, exchange).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error)) => Err(format!("request failed, {}", error)),
+            Err(_) => Err(format!("no response within {:?}", self.config.timeout)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// A server that denies requests whose request line contains `attack`, and records the
+    /// requests it receives.
+    struct Server {
+        port: u16,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Server {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let requests = Arc::new(Mutex::new(vec![]));
+            let received = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let received = received.clone();
+                    tokio::spawn(async move {
+                        let mut request = vec![];
+                        let mut buffer = [0; 1024];
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match stream.read(&mut buffer).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(read) => request.extend_from_slice(&buffer[..read]),
+                            }
+                        }
+                        let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+                        let (status, body) = match line.windows(6).any(|w| w == b"attack") {
+                            true => ("403 Forbidden", "denied"),
+                            false => ("200 OK", "welcome"),
+                        };
+                        received.lock().unwrap().push(request);
+                        let response = format!(
+                            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        );
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    });
+                }
+            });
+            Self { port, requests }
+        }
+
+        fn runner(&self) -> Runner {
+            Runner::new(RunnerConfig {
+                host: Some("127.0.0.1".into()),
+                port: Some(self.port),
+                timeout: Duration::from_secs(5),
+                ..Default::default()
+            })
+            .unwrap()
+        }
+
+        fn requests(&self) -> Vec<String> {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .map(|request| String::from_utf8_lossy(request).into_owned())
+                .collect()
+        }
+    }
+
+    fn file(tests: &str) -> File {
+        File::from_str(&format!("meta:\n  enabled: true\ntests:\n{}", tests)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_responses() {
+        let server = Server::start().await;
+        let file = file(
+            r#"
+  - test_title: allowed
+    stages:
+      - stage:
+          input:
+            uri: /index.html
+          output:
+            status: 200
+            response_contains: welcome
+  - test_title: denied
+    stages:
+      - stage:
+          input:
+            uri: /?q=attack
+          output:
+            status: [403]
+  - test_title: wrong status
+    stages:
+      - stage:
+          input:
+            uri: /?q=attack
+          output:
+            status: 200
+"#,
+        );
+        let results = server.runner().run_file(&file).await;
+        let statuses: Vec<_> = results.iter().map(|r| r.status.clone()).collect();
+        assert_eq!(
+            statuses,
+            [
+                Status::Passed,
+                Status::Passed,
+                Status::Failed("stage 1: status:\n  - expected: 200\n  + actual:   403".into()),
+            ]
+        );
+        assert_eq!(server.requests().len(), 3);
+        assert!(server.requests()[0].starts_with("GET /index.html HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_failed_stage() {
+        let server = Server::start().await;
+        let file = file(
+            r#"
+  - test_title: stages
+    stages:
+      - stage:
+          input:
+            uri: /first
+          output:
+            status: 200
+      - stage:
+          input:
+            uri: /attack
+          output:
+            status: 200
+      - stage:
+          input:
+            uri: /third
+"#,
+        );
+        let results = server.runner().run_file(&file).await;
+        assert!(results[0].status.is_failed());
+        assert_eq!(results[0].stages.len(), 2);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn skips_log_checks_without_the_log() {
+        let server = Server::start().await;
+        let file = file(
+            r#"
+  - test_title: log
+    stages:
+      - stage:
+          input:
+            uri: /
+          output:
+            status: 200
+            log_contains: id "1"
+"#,
+        );
+        let results = server.runner().run_file(&file).await;
+        assert_eq!(
+            results[0].status,
+            Status::Skipped("stage 1: log_contains can't be checked without the WAF log".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_stages_without_a_response() {
+        // nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let runner = Runner::new(RunnerConfig {
+            host: Some("127.0.0.1".into()),
+            port: Some(port),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let file = file(
+            r#"
+  - test_title: no response
+    stages:
+      - stage:
+          input:
+            uri: /
+  - test_title: expected error
+    stages:
+      - stage:
+          input:
+            uri: /
+          output:
+            expect_error: true
+"#,
+        );
+        let results = runner.run_file(&file).await;
+        assert!(results[0].status.is_failed(), "{:?}", results[0]);
+        assert_eq!(results[1].status, Status::Passed);
+    }
+
+    #[tokio::test]
+    async fn only_adds_a_host_header_with_magic() {
+        let server = Server::start().await;
+        let file = file(
+            r#"
+  - test_title: magic
+    stages:
+      - stage:
+          input:
+            dest_addr: example.com
+            uri: /magic
+  - test_title: stop_magic
+    stages:
+      - stage:
+          input:
+            dest_addr: example.com
+            uri: /stop_magic
+            stop_magic: true
+  - test_title: autocomplete_headers
+    stages:
+      - stage:
+          input:
+            dest_addr: example.com
+            uri: /autocomplete_headers
+            version: HTTP/1.0
+            autocomplete_headers: false
+  - test_title: explicit host
+    stages:
+      - stage:
+          input:
+            dest_addr: example.com
+            headers:
+              Host: localhost
+            uri: /explicit
+            stop_magic: true
+"#,
+        );
+        let results = server.runner().run_file(&file).await;
+        assert!(
+            results.iter().all(|r| r.status.is_passed()),
+            "{:?}",
+            results
+        );
+
+        let requests = server.requests();
+        let host = |request: &str| {
+            request
+                .lines()
+                .find_map(|line| line.strip_prefix("host: ").or(line.strip_prefix("Host: ")))
+                .map(String::from)
+        };
+        assert_eq!(host(&requests[0]).as_deref(), Some("example.com"));
+        assert_eq!(host(&requests[1]), None);
+        assert!(requests[1].starts_with("GET /stop_magic HTTP/1.1\r\n"));
+        assert_eq!(host(&requests[2]), None);
+        assert!(requests[2].starts_with("GET /autocomplete_headers HTTP/1.0\r\n"));
+        assert_eq!(host(&requests[3]).as_deref(), Some("localhost"));
+    }
+
+    #[test]
+    fn reports_stage_statuses() {
+        let output: Output = serde_yaml::from_str("status: 403\nlog_contains: id").unwrap();
+        let expectations = Expectations::compile(&output).unwrap();
+        let response: Response = Ok(http::Response::builder().status(403).body(vec![]).unwrap());
+        assert_eq!(
+            stage_status(Some(&expectations), &response, Some(r#"[id "1"]"#)),
+            Status::Passed
+        );
+        assert!(stage_status(Some(&expectations), &response, Some("")).is_failed());
+        assert!(stage_status(Some(&expectations), &response, None).is_skipped());
+
+        assert_eq!(stage_status(None, &response, None), Status::Passed);
+        let error: Response = Err("refused".into());
+        assert_eq!(
+            stage_status(None, &error, None),
+            Status::Failed("refused".into())
+        );
+    }
+}
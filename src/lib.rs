@@ -3,6 +3,7 @@ use crate::syntax::CRSEntry;
 pub mod engine;
 pub mod expr;
 pub mod ftw;
+pub mod lint;
 pub mod rules;
 pub mod syntax;
 
@@ -0,0 +1,686 @@
+//! CRS-specific checks over parsed rule configs.
+//!
+//! The checks here go beyond what's needed for a config to load in ModSecurity, they enforce the
+//! conventions that the core rule set itself follows (see the CRS contribution guidelines), e.g.
+//! every rule carries `ver` and `severity`, chained rules don't carry metadata, and tags follow
+//! the `attack-*` and `paranoia-level/N` naming schemes.
+
+use crate::syntax::{Action, ActionKind, ActionType, CRSEntry, CRSFile, CRSParseError, Span};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    /// The file (or a directive in it) could not be parsed.
+    ParseError,
+    /// The same rule id is used more than once.
+    DuplicateId,
+    MissingId,
+    MissingPhase,
+    MissingVer,
+    MissingSeverity,
+    /// A rule that starts a chain has no id.
+    ChainStarterMissingId,
+    /// A rule inside a chain carries an action that's only allowed on the chain starter.
+    ChainedDisallowedAction,
+    /// `t:none` is used, but isn't the first transformation (so everything before it is dropped).
+    TNoneNotFirst,
+    /// The same idempotent transformation is applied more than once.
+    RedundantTransform,
+    /// `capture` is used with an operator that doesn't support it.
+    CaptureUnsupportedOperator,
+    /// `skipAfter` refers to a marker that doesn't exist.
+    SkipAfterMissingMarker,
+    /// A tag doesn't follow the CRS naming conventions.
+    TagConvention,
+}
+
+impl Check {
+    pub fn name(&self) -> &'static str {
+        use Check::*;
+        match self {
+            ParseError => "parse-error",
+            DuplicateId => "duplicate-id",
+            MissingId => "missing-id",
+            MissingPhase => "missing-phase",
+            MissingVer => "missing-ver",
+            MissingSeverity => "missing-severity",
+            ChainStarterMissingId => "chain-starter-missing-id",
+            ChainedDisallowedAction => "chained-disallowed-action",
+            TNoneNotFirst => "t-none-not-first",
+            RedundantTransform => "redundant-transform",
+            CaptureUnsupportedOperator => "capture-unsupported-operator",
+            SkipAfterMissingMarker => "skip-after-missing-marker",
+            TagConvention => "tag-convention",
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        use Check::*;
+        match self {
+            ParseError
+            | DuplicateId
+            | MissingId
+            | MissingPhase
+            | ChainStarterMissingId
+            | ChainedDisallowedAction
+            | SkipAfterMissingMarker => Level::Error,
+            MissingVer
+            | MissingSeverity
+            | TNoneNotFirst
+            | RedundantTransform
+            | CaptureUnsupportedOperator
+            | TagConvention => Level::Warning,
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Warning,
+    Error,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Warning => f.write_str("warning"),
+            Level::Error => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub check: Check,
+    pub level: Level,
+    pub message: String,
+    /// Id of the rule (or chain) the diagnostic applies to, if it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Diagnostic {
+    pub fn new(check: Check, span: &Span, rule_id: Option<&str>, message: String) -> Self {
+        Self {
+            check,
+            level: check.level(),
+            message,
+            rule_id: rule_id.map(Into::into),
+            file: span.path().map(|p| p.display().to_string()),
+            line: span.start.line,
+            column: span.start.column,
+        }
+    }
+
+    pub fn from_parse_error(error: &CRSParseError) -> Self {
+        // the span is already part of the location, so only keep the message
+        Self::new(Check::ParseError, error.span(), None, error.message())
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.line, self.column, self.level, self.check, self.message
+        )?;
+        if let Some(id) = &self.rule_id {
+            write!(f, " (rule {})", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Transformations where applying them a second time never changes the result.
+const IDEMPOTENT_TRANSFORMS: &[&str] = &[
+    "cmdLine",
+    "compressWhitespace",
+    "lowercase",
+    "none",
+    "normalisePath",
+    "normalisePathWin",
+    "normalizePath",
+    "normalizePathWin",
+    "removeComments",
+    "removeCommentsChar",
+    "removeNulls",
+    "removeWhitespace",
+    "replaceComments",
+    "replaceNulls",
+    "trim",
+    "trimLeft",
+    "trimRight",
+    "uppercase",
+];
+
+/// Disruptive actions, phases, metadata and skips may only be used by the first rule in a chain.
+fn allowed_in_chained_rule(action: ActionType) -> bool {
+    use ActionType::*;
+    action.kind() != ActionKind::Disruptive
+        && !matches!(
+            action,
+            Phase | Id | Revision | Msg | Tag | Severity | LogData | Skip | SkipAfter
+        )
+}
+
+fn find(actions: &[Action], action: ActionType) -> Option<&Action> {
+    actions.iter().find(|a| a.action == action)
+}
+
+fn has(actions: &[Action], action: ActionType) -> bool {
+    find(actions, action).is_some()
+}
+
+fn check_tag(tag: &str) -> Option<String> {
+    if let Some(level) = tag.strip_prefix("paranoia-level") {
+        let valid = matches!(level, "/1" | "/2" | "/3" | "/4");
+        (!valid).then(|| {
+            format!(
+                "tag '{}' should be of the form paranoia-level/N, N in 1-4",
+                tag
+            )
+        })
+    } else if let Some(attack) = tag.strip_prefix("attack") {
+        let valid = attack
+            .strip_prefix('-')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                name.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            })
+            .unwrap_or(false);
+        (!valid).then(|| {
+            format!(
+                "tag '{}' should be of the form attack-<lowercase name>",
+                tag
+            )
+        })
+    } else {
+        None
+    }
+}
+
+/// Runs all checks over a set of files. Checks that look across rules (duplicate ids, skipAfter
+/// markers) consider every file passed in.
+pub fn lint(files: &[CRSFile]) -> Vec<Diagnostic> {
+    let markers: HashSet<&str> = files
+        .iter()
+        .flat_map(|file| file.entries.iter())
+        .filter_map(|entry| match entry {
+            CRSEntry::SecMarker { marker, .. } => Some(marker.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let mut linter = Linter {
+        markers,
+        ids: HashMap::new(),
+        diagnostics: vec![],
+    };
+    for file in files {
+        linter.lint_entries(&file.entries);
+    }
+    linter.diagnostics
+}
+
+struct Linter<'a> {
+    markers: HashSet<&'a str>,
+    ids: HashMap<&'a str, &'a Span>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, check: Check, span: &Span, rule_id: Option<&str>, message: String) {
+        self.diagnostics
+            .push(Diagnostic::new(check, span, rule_id, message));
+    }
+
+    fn lint_entries(&mut self, entries: &'a [CRSEntry]) {
+        // id of the current chain, if the previous rule started or continued a chain
+        let mut chain: Option<Option<&'a str>> = None;
+
+        for entry in entries {
+            match entry {
+                CRSEntry::SecRule {
+                    actions,
+                    test,
+                    span,
+                    ..
+                } => {
+                    let chained = chain.is_some();
+                    let id = match chain {
+                        Some(chain_id) => chain_id,
+                        None => find(actions, ActionType::Id).and_then(Action::argument),
+                    };
+
+                    if chained {
+                        self.lint_chained(actions, id);
+                    } else {
+                        self.lint_starter(entry, actions, span, id);
+                    }
+                    self.lint_transforms(actions, id);
+                    self.lint_skip_after(actions, id);
+
+                    if has(actions, ActionType::Capture) && !test.operator.op.supports_capture() {
+                        self.report(
+                            Check::CaptureUnsupportedOperator,
+                            &test.operator.span,
+                            id,
+                            format!(
+                                "capture has no effect with @{}, use @rx or @pm",
+                                test.operator.op.name()
+                            ),
+                        );
+                    }
+
+                    chain = has(actions, ActionType::Chain).then_some(id);
+                }
+                CRSEntry::SecAction { actions, span } => {
+                    let id = find(actions, ActionType::Id).and_then(Action::argument);
+                    self.lint_starter(entry, actions, span, id);
+                    self.lint_transforms(actions, id);
+                    self.lint_skip_after(actions, id);
+                    chain = None;
+                }
+                CRSEntry::SecMarker { .. }
+                | CRSEntry::SecComponentSignature { .. }
+                | CRSEntry::SecCollectionTimeout { .. } => {
+                    chain = None;
+                }
+            }
+        }
+    }
+
+    /// Checks for rules that start a chain (or aren't part of one) and SecActions.
+    fn lint_starter(
+        &mut self,
+        entry: &CRSEntry,
+        actions: &'a [Action],
+        span: &Span,
+        id: Option<&'a str>,
+    ) {
+        let is_rule = matches!(entry, CRSEntry::SecRule { .. });
+        let directive = if is_rule { "rule" } else { "SecAction" };
+
+        match find(actions, ActionType::Id) {
+            Some(action) => {
+                if let Some(id) = action.argument() {
+                    if let Some(previous) = self.ids.insert(id, &action.span) {
+                        let message = format!("id {} is already used at {}", id, previous);
+                        self.report(Check::DuplicateId, &action.span, Some(id), message);
+                    }
+                }
+            }
+            None if is_rule && has(actions, ActionType::Chain) => self.report(
+                Check::ChainStarterMissingId,
+                span,
+                None,
+                "chain starter is missing an id".into(),
+            ),
+            None => self.report(
+                Check::MissingId,
+                span,
+                None,
+                format!("{} is missing an id", directive),
+            ),
+        }
+
+        if !has(actions, ActionType::Phase) {
+            let message = format!("{} is missing a phase", directive);
+            self.report(Check::MissingPhase, span, id, message);
+        }
+        if !has(actions, ActionType::Version) {
+            let message = format!("{} is missing ver", directive);
+            self.report(Check::MissingVer, span, id, message);
+        }
+        // rules that don't log (e.g. flow control and setup rules) don't need a severity
+        if is_rule && !has(actions, ActionType::NoLog) && !has(actions, ActionType::Severity) {
+            self.report(
+                Check::MissingSeverity,
+                span,
+                id,
+                "rule is missing severity".into(),
+            );
+        }
+
+        for action in actions.iter().filter(|a| a.action == ActionType::Tag) {
+            if let Some(message) = action.argument().and_then(check_tag) {
+                self.report(Check::TagConvention, &action.span, id, message);
+            }
+        }
+    }
+
+    fn lint_chained(&mut self, actions: &[Action], id: Option<&str>) {
+        for action in actions {
+            if !allowed_in_chained_rule(action.action) {
+                let message = format!(
+                    "{} can only be used by the chain starter",
+                    action.action.name()
+                );
+                self.report(Check::ChainedDisallowedAction, &action.span, id, message);
+            }
+        }
+    }
+
+    fn lint_transforms(&mut self, actions: &[Action], id: Option<&str>) {
+        let transforms: Vec<&Action> = actions
+            .iter()
+            .filter(|a| a.action == ActionType::Transform)
+            .collect();
+
+        for (index, action) in transforms.iter().enumerate() {
+            let name = action.argument().unwrap_or_default();
+            if name == "none" && index > 0 {
+                self.report(
+                    Check::TNoneNotFirst,
+                    &action.span,
+                    id,
+                    "t:none should be the first transformation, it discards any before it".into(),
+                );
+            }
+
+            let repeated = transforms[..index]
+                .iter()
+                .any(|previous| previous.argument() == Some(name));
+            if repeated && IDEMPOTENT_TRANSFORMS.contains(&name) {
+                let message = format!("t:{} is applied more than once", name);
+                self.report(Check::RedundantTransform, &action.span, id, message);
+            }
+        }
+    }
+
+    fn lint_skip_after(&mut self, actions: &[Action], id: Option<&str>) {
+        for action in actions.iter().filter(|a| a.action == ActionType::SkipAfter) {
+            let marker = action.argument().unwrap_or_default();
+            if !self.markers.contains(marker) {
+                let message = format!("skipAfter target {} has no matching SecMarker", marker);
+                self.report(Check::SkipAfterMissingMarker, &action.span, id, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parse_entries_recovering;
+
+    /// A rule that follows every convention.
+    const CLEAN: &str = r#"SecRule ARGS "@rx a" "id:1,phase:2,block,t:none,t:lowercase,capture,ver:'OWASP_CRS/4.0.0',severity:'CRITICAL',tag:'attack-sqli',tag:'paranoia-level/1'"
+"#;
+
+    fn lint_str(text: &str) -> Vec<Diagnostic> {
+        let (file, errors) = parse_entries_recovering(text).into_file();
+        let mut diagnostics: Vec<_> = errors.iter().map(Diagnostic::from_parse_error).collect();
+        diagnostics.extend(lint(&[file]));
+        diagnostics
+    }
+
+    /// The checks that fired, with the rule they fired for and their message.
+    fn checks(text: &str) -> Vec<(Check, Option<String>, String)> {
+        lint_str(text)
+            .into_iter()
+            .map(|d| (d.check, d.rule_id, d.message))
+            .collect()
+    }
+
+    fn only(text: &str) -> (Check, Option<String>, String) {
+        let mut checks = checks(text);
+        assert_eq!(checks.len(), 1, "{:?}", checks);
+        checks.remove(0)
+    }
+
+    #[test]
+    fn accepts_clean_rules() {
+        assert!(lint_str(CLEAN).is_empty(), "{:?}", lint_str(CLEAN));
+        let setup = r#"SecAction "id:2,phase:1,pass,nolog,ver:'OWASP_CRS/4.0.0',setvar:tx.a=1"
+"#;
+        assert!(lint_str(setup).is_empty());
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let diagnostics = lint_str(&format!("{}SecRule ARGS\n", CLEAN));
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert_eq!(diagnostics[0].check, Check::ParseError);
+        assert_eq!(diagnostics[0].level, Level::Error);
+        assert_eq!(diagnostics[0].line, 2);
+    }
+
+    #[test]
+    fn reports_duplicate_ids() {
+        let (check, id, message) = only(&format!("{}{}", CLEAN, CLEAN));
+        assert_eq!(check, Check::DuplicateId);
+        assert_eq!(id.as_deref(), Some("1"));
+        assert!(
+            message.starts_with("id 1 is already used at "),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_ids_across_files() {
+        let file = || parse_entries_recovering(CLEAN).into_file().0;
+        let diagnostics = lint(&[file(), file()]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].check, Check::DuplicateId);
+    }
+
+    #[test]
+    fn reports_missing_ids() {
+        assert_eq!(
+            only(&CLEAN.replace("id:1,", "")),
+            (Check::MissingId, None, "rule is missing an id".into())
+        );
+        assert_eq!(
+            only(
+                r#"SecAction "phase:1,pass,nolog,ver:'OWASP_CRS/4.0.0'"
+"#
+            ),
+            (Check::MissingId, None, "SecAction is missing an id".into())
+        );
+    }
+
+    #[test]
+    fn reports_missing_phase_ver_and_severity() {
+        assert_eq!(
+            only(&CLEAN.replace("phase:2,", "")),
+            (
+                Check::MissingPhase,
+                Some("1".into()),
+                "rule is missing a phase".into()
+            )
+        );
+        assert_eq!(
+            only(&CLEAN.replace("ver:'OWASP_CRS/4.0.0',", "")),
+            (
+                Check::MissingVer,
+                Some("1".into()),
+                "rule is missing ver".into()
+            )
+        );
+        assert_eq!(
+            only(&CLEAN.replace("severity:'CRITICAL',", "")),
+            (
+                Check::MissingSeverity,
+                Some("1".into()),
+                "rule is missing severity".into()
+            )
+        );
+        // rules that don't log don't need a severity
+        assert!(checks(&CLEAN.replace("severity:'CRITICAL',", "nolog,")).is_empty());
+    }
+
+    #[test]
+    fn reports_chain_starters_without_an_id() {
+        let rules = r#"SecRule ARGS "@rx a" "phase:2,block,ver:'OWASP_CRS/4.0.0',severity:'CRITICAL',chain"
+    SecRule ARGS "@rx b" "t:none"
+"#;
+        assert_eq!(
+            only(rules),
+            (
+                Check::ChainStarterMissingId,
+                None,
+                "chain starter is missing an id".into()
+            )
+        );
+    }
+
+    #[test]
+    fn reports_disallowed_actions_in_chained_rules() {
+        let rules = r#"SecRule ARGS "@rx a" "id:1,phase:2,block,ver:'OWASP_CRS/4.0.0',severity:'CRITICAL',chain"
+    SecRule ARGS "@rx b" "t:none,msg:'chained',deny,setvar:tx.a=1"
+"#;
+        assert_eq!(
+            checks(rules),
+            [
+                (
+                    Check::ChainedDisallowedAction,
+                    Some("1".into()),
+                    "msg can only be used by the chain starter".into()
+                ),
+                (
+                    Check::ChainedDisallowedAction,
+                    Some("1".into()),
+                    "deny can only be used by the chain starter".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_t_none_after_other_transformations() {
+        let (check, id, _) = only(&CLEAN.replace("t:none,t:lowercase", "t:lowercase,t:none"));
+        assert_eq!((check, id.as_deref()), (Check::TNoneNotFirst, Some("1")));
+    }
+
+    #[test]
+    fn reports_redundant_transformations() {
+        assert_eq!(
+            only(&CLEAN.replace("t:lowercase", "t:lowercase,t:urlDecode,t:lowercase")),
+            (
+                Check::RedundantTransform,
+                Some("1".into()),
+                "t:lowercase is applied more than once".into()
+            )
+        );
+        // decoding twice can change the result
+        assert!(checks(&CLEAN.replace("t:lowercase", "t:urlDecode,t:urlDecode")).is_empty());
+    }
+
+    #[test]
+    fn reports_capture_with_unsupported_operators() {
+        assert_eq!(
+            only(&CLEAN.replace("@rx a", "@streq a")),
+            (
+                Check::CaptureUnsupportedOperator,
+                Some("1".into()),
+                "capture has no effect with @streq, use @rx or @pm".into()
+            )
+        );
+        assert!(checks(&CLEAN.replace("@rx a", "@pm a b")).is_empty());
+    }
+
+    #[test]
+    fn reports_skip_after_without_a_marker() {
+        let rule = CLEAN.replace("capture,", "skipAfter:END-CHECKS,");
+        assert_eq!(
+            only(&rule),
+            (
+                Check::SkipAfterMissingMarker,
+                Some("1".into()),
+                "skipAfter target END-CHECKS has no matching SecMarker".into()
+            )
+        );
+        assert!(checks(&format!("{}SecMarker \"END-CHECKS\"\n", rule)).is_empty());
+    }
+
+    #[test]
+    fn reports_tags_against_the_conventions() {
+        for (tag, message) in [
+            (
+                "paranoia-level/5",
+                "tag 'paranoia-level/5' should be of the form paranoia-level/N, N in 1-4",
+            ),
+            (
+                "attack_sqli",
+                "tag 'attack_sqli' should be of the form attack-<lowercase name>",
+            ),
+            (
+                "attack-SQLi",
+                "tag 'attack-SQLi' should be of the form attack-<lowercase name>",
+            ),
+        ] {
+            let rule = CLEAN.replace("attack-sqli", tag);
+            assert_eq!(
+                only(&rule),
+                (Check::TagConvention, Some("1".into()), message.into())
+            );
+        }
+        assert!(checks(&CLEAN.replace("attack-sqli", "platform-multi")).is_empty());
+    }
+
+    #[test]
+    fn formats_diagnostics() {
+        let diagnostics = lint_str(&CLEAN.replace("phase:2,", ""));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "1:1: error[missing-phase]: rule is missing a phase (rule 1)"
+        );
+        let diagnostics = lint_str(&CLEAN.replace("t:none,t:lowercase", "t:lowercase,t:none"));
+        assert_eq!(diagnostics[0].level, Level::Warning);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "1:54: warning[t-none-not-first]: t:none should be the first transformation, it \
+             discards any before it (rule 1)"
+        );
+    }
+
+    #[test]
+    fn serializes_diagnostics_to_json() {
+        let diagnostics = lint_str(&format!(
+            "{}{}",
+            CLEAN.replace("severity:'CRITICAL',", ""),
+            CLEAN.replace("id:1,", "")
+        ));
+        assert_eq!(
+            serde_json::to_value(&diagnostics).unwrap(),
+            serde_json::json!([
+                {
+                    "check": "missing-severity",
+                    "level": "warning",
+                    "message": "rule is missing severity",
+                    "rule_id": "1",
+                    "line": 1,
+                    "column": 1
+                },
+                {
+                    "check": "missing-id",
+                    "level": "error",
+                    "message": "rule is missing an id",
+                    "line": 2,
+                    "column": 1
+                }
+            ])
+        );
+    }
+}
@@ -1,9 +1,22 @@
+use super::Span;
 use crate::enum_token;
 use std::fmt::{Display, Formatter};
 use thiserror::Error;
 
 enum_token! {
     pub enum ActionType {
+        /// Specifies the relative accuracy level of the rule related to false positives/negatives.
+        /// The value is a number between 1 (low accuracy) and 9 (high accuracy).
+        Accuracy   = "accuracy",
+        /// Stops rule processing on a successful match and allows the transaction to proceed.
+        ///
+        /// Used on its own, it stops all further processing of the transaction. With `allow:phase`
+        /// only the remaining rules in the current phase are skipped, and with `allow:request`
+        /// the remaining request phases are skipped but the response is still inspected.
+        Allow      = "allow",
+        /// Appends text given as parameter to the end of the response body. Macro expansion is
+        /// performed.
+        Append     = "append",
         /// Marks the transaction for logging in the audit log.
         AuditLog   = "auditlog",
         /// Performs the disruptive action defined by the previous SecDefaultAction.
@@ -27,10 +40,16 @@ enum_token! {
         /// default configuration, as well as the other transactions running in parallel, will be
         /// unaffected.
         Ctl        = "ctl",
+        /// Marks a collection variable for deprecation: the numerical value of the variable is
+        /// decreased by the given amount every given number of seconds, e.g.
+        /// `deprecatevar:SESSION.score=60/3600`.
+        DeprecateVar = "deprecatevar",
         /// Stops rule processing and intercepts transaction.
         Deny       = "deny",
         /// Initiates an immediate close of the TCP connection by sending a FIN packet.
         Drop       = "drop",
+        /// Executes an external script/binary supplied as parameter, after the rule matches.
+        Exec       = "exec",
         /// Configures a collection variable to expire after the given time period (in seconds).
         ExpireVar  = "expirevar",
         /// Assigns a unique ID to the rule or chain in which it appears.
@@ -43,6 +62,10 @@ enum_token! {
         /// Logs a data fragment as part of the alert message. Macro expansion is performed, so you
         /// may use variable names such as %{TX.0} or %{MATCHED_VAR}.
         LogData    = "logdata",
+        /// Specifies the relative maturity level of the rule related to the length of time the
+        /// rule has been public and the amount of testing it has received. The value is a number
+        /// between 1 (least mature) and 9 (most mature).
+        Maturity   = "maturity",
         /// Assigns a custom message to the rule or chain in which it appears. The message will be
         /// logged along with every alert.
         Msg        = "msg",
@@ -62,9 +85,49 @@ enum_token! {
         NoLog      = "nolog",
         /// Continues processing with the next rule in spite of a successful match.
         Pass       = "pass",
+        /// Pauses transaction processing for the specified number of milliseconds, then continues
+        /// with the next rule.
+        Pause      = "pause",
         /// Places the rule or chain into one of five available processing phases. It can also be
         /// used in SecDefaultAction to establish the rule defaults.
         Phase      = "phase",
+        /// Prepends text given as parameter to the response body. Macro expansion is performed.
+        Prepend    = "prepend",
+        /// Intercepts the current transaction by forwarding the request to another web server
+        /// using the proxy backend. Macro expansion is performed on the URL.
+        Proxy      = "proxy",
+        /// Intercepts the transaction by issuing an external (client-visible) redirection to the
+        /// given location. Macro expansion is performed on the URL. Unless `status` specifies
+        /// a 301, 303 or 307 status code, a 302 is used.
+        Redirect   = "redirect",
+        /// Specifies rule revision. It is useful in combination with the id action to provide an
+        /// indication that a rule has been changed.
+        Revision   = "rev",
+        /// Prevents sensitive request parameter data from being logged to the audit log. Each byte
+        /// of the named parameter(s) is replaced with an asterisk.
+        SanitiseArg = "sanitiseArg",
+        /// Prevents the sensitive data contained in the variable that caused the rule to match
+        /// from being logged to the audit log.
+        SanitiseMatched = "sanitiseMatched",
+        /// Like sanitiseMatched, but only replaces the bytes that matched. An optional `N/M`
+        /// argument keeps the first N and last M bytes of the match visible.
+        SanitiseMatchedBytes = "sanitiseMatchedBytes",
+        /// Prevents sensitive request header data from being logged to the audit log.
+        SanitiseRequestHeader = "sanitiseRequestHeader",
+        /// Prevents sensitive response header data from being logged to the audit log.
+        SanitiseResponseHeader = "sanitiseResponseHeader",
+        /// Creates, removes, or updates an environment variable that can be accessed by the
+        /// implementation environment.
+        SetEnv     = "setenv",
+        /// Special-purpose action that initializes the RESOURCE collection using a key provided as
+        /// parameter.
+        SetResource = "setrsc",
+        /// Special-purpose action that initializes the SESSION collection using the session token
+        /// provided as parameter.
+        SetSession = "setsid",
+        /// Special-purpose action that initializes the USER collection using the username provided
+        /// as parameter.
+        SetUser    = "setuid",
         /// Creates, removes, or updates a variable. Variable names are case-insensitive.
         ///
         /// Note: When used in a chain this action will be executed when an individual rule matches
@@ -81,6 +144,9 @@ enum_token! {
         /// Assigns severity to the rule in which it is used. Severity values in ModSecurity follows
         /// the numeric scale of syslog (where 0 is the most severe).
         Severity   = "severity",
+        /// Skips one or more rules (or chains) on a successful match. The argument is the number of
+        /// rules to skip, and must be greater than zero.
+        Skip       = "skip",
         /// Skips one or more rules (or chains) on a successful match, resuming rule execution with
         /// the first rule that follows the rule (or marker created by SecMarker) with the provided
         /// ID.
@@ -94,13 +160,81 @@ enum_token! {
         Transform  = "t",
         /// Specifies the rule set version.
         Version    = "ver",
+        /// Configures an XML namespace, which will be used in the execution of XPath expressions,
+        /// e.g. `xmlns:xsd="http://www.w3.org/2001/XMLSchema"`.
+        XmlNs      = "xmlns",
+    }
+}
+
+/// The categories actions are grouped into by the ModSecurity reference manual.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ActionKind {
+    /// Tells the engine to do something to the transaction, e.g. `deny` or `allow`. A rule (or
+    /// chain) can only have one disruptive action, and it only takes effect if the whole chain
+    /// matches.
+    Disruptive,
+    /// Affects the rule flow, e.g. `chain` or `skipAfter`.
+    Flow,
+    /// Provides information about the rule, e.g. `id` or `msg`.
+    Metadata,
+    /// Containers that hold data for other actions to use, e.g. `status` for `deny`.
+    Data,
+    /// Does something as a side effect of the rule matching, e.g. `setvar` or `t`.
+    NonDisruptive,
+}
+
+impl ActionType {
+    pub fn kind(&self) -> ActionKind {
+        use ActionType::*;
+        match self {
+            Allow | Block | Deny | Drop | Pass | Pause | Proxy | Redirect => ActionKind::Disruptive,
+            Chain | Skip | SkipAfter => ActionKind::Flow,
+            Accuracy | Id | Maturity | Msg | Phase | Revision | Severity | Tag | Version => {
+                ActionKind::Metadata
+            }
+            Status | XmlNs => ActionKind::Data,
+            Append
+            | AuditLog
+            | Capture
+            | Ctl
+            | DeprecateVar
+            | Exec
+            | ExpireVar
+            | InitCollection
+            | Log
+            | LogData
+            | MultiMatch
+            | NoAuditLog
+            | NoLog
+            | Prepend
+            | SanitiseArg
+            | SanitiseMatched
+            | SanitiseMatchedBytes
+            | SanitiseRequestHeader
+            | SanitiseResponseHeader
+            | SetEnv
+            | SetResource
+            | SetSession
+            | SetUser
+            | Setvar
+            | Transform => ActionKind::NonDisruptive,
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Action {
     pub action: ActionType,
-    pub arg: Option<String>,
+    pub arg: Option<ActionArgument>,
+    pub span: Span,
+}
+
+impl Action {
+    /// The (unescaped) argument value, if the action has one.
+    #[inline]
+    pub fn argument(&self) -> Option<&str> {
+        self.arg.as_ref().map(ActionArgument::value)
+    }
 }
 
 impl Display for Action {
@@ -113,19 +247,253 @@ impl Display for Action {
     }
 }
 
+/// How an action argument is quoted in the action list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Quoting {
+    /// `msg:foo`
+    Unquoted,
+    /// `msg:'foo'`, which allows commas and whitespace as part of the value.
+    Single,
+}
+
+/// The argument of an action, e.g. the `foo's` in `msg:'foo\'s'`.
+///
+/// The value is stored unescaped, so two arguments are equal if their values are, regardless of
+/// how they were quoted or escaped. The exact text the argument was parsed from is kept as well,
+/// so that writing it back out reproduces the original input byte for byte.
+///
+/// Within the argument, `\"` is an escaped double quote (since the action list is itself a
+/// double-quoted string), and in single-quoted arguments `\'` and `\\` are an escaped single
+/// quote and backslash. Any other backslash is part of the value.
+#[derive(Debug, Clone)]
+pub struct ActionArgument {
+    value: String,
+    quoting: Quoting,
+    /// The exact text this argument was parsed from, if it hasn't been modified since.
+    raw: Option<String>,
+}
+
+impl ActionArgument {
+    pub fn new(value: impl Into<String>, quoting: Quoting) -> Self {
+        Self {
+            value: value.into(),
+            quoting,
+            raw: None,
+        }
+    }
+
+    /// Parses an argument as written in an action list, including any quotes.
+    pub fn parse(raw: &str) -> Self {
+        let quoted = raw
+            .strip_prefix('\'')
+            .and_then(|r| r.strip_suffix('\''))
+            .filter(|_| raw.len() >= 2);
+
+        let (value, quoting) = match quoted {
+            Some(inner) => (unescape(inner, Quoting::Single), Quoting::Single),
+            None => (unescape(raw, Quoting::Unquoted), Quoting::Unquoted),
+        };
+
+        Self {
+            value,
+            quoting,
+            raw: Some(raw.into()),
+        }
+    }
+
+    #[inline]
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    #[inline]
+    pub fn quoting(&self) -> Quoting {
+        self.quoting
+    }
+
+    pub fn set_value(&mut self, value: impl Into<String>) {
+        self.value = value.into();
+        self.raw = None;
+    }
+
+    pub fn set_quoting(&mut self, quoting: Quoting) {
+        self.quoting = quoting;
+        self.raw = None;
+    }
+
+    /// Writes the argument with canonical escaping, ignoring how it was originally written.
+    /// Values that can't be written unquoted, e.g. because they contain a comma, are single
+    /// quoted regardless of their quoting style.
+    pub fn to_canonical_string(&self) -> String {
+        let quoting = match self.quoting {
+            Quoting::Unquoted if !can_be_unquoted(&self.value) => Quoting::Single,
+            quoting => quoting,
+        };
+        let escaped = escape(&self.value, quoting);
+        match quoting {
+            Quoting::Unquoted => escaped,
+            Quoting::Single => format!("'{}'", escaped),
+        }
+    }
+}
+
+impl PartialEq for ActionArgument {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for ActionArgument {}
+
+impl Display for ActionArgument {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.raw {
+            Some(raw) => f.write_str(raw),
+            None => f.write_str(&self.to_canonical_string()),
+        }
+    }
+}
+
+fn unescape(raw: &str, quoting: Quoting) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek(), quoting) {
+            ('\\', Some('"'), _)
+            | ('\\', Some('\''), Quoting::Single)
+            | ('\\', Some('\\'), Quoting::Single) => value.push(chars.next().unwrap()),
+            _ => value.push(c),
+        }
+    }
+    value
+}
+
+/// Whether the value reads back the same when written without quotes: unquoted arguments end at
+/// a comma, can't be empty, and a trailing backslash would escape the quote closing the action
+/// list.
+fn can_be_unquoted(value: &str) -> bool {
+    !value.is_empty()
+        && !value.ends_with('\\')
+        && !value
+            .chars()
+            .any(|c| c == ',' || c == '\'' || c.is_whitespace())
+}
+
+fn escape(value: &str, quoting: Quoting) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoting) {
+            ('"', _) | ('\'', Quoting::Single) => escaped.push('\\'),
+            // backslashes only need escaping if they'd otherwise be read as part of an escape
+            ('\\', Quoting::Single) => {
+                if matches!(chars.peek(), None | Some('\'') | Some('\\') | Some('"')) {
+                    escaped.push('\\');
+                }
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 #[derive(Error, Debug)]
 pub enum ActionParseError {
     #[error("unknown action {0}")]
     UnknownAction(String),
 }
 
-pub fn parse_action(action: String, argument: Option<String>) -> Result<Action, ActionParseError> {
+pub fn parse_action(
+    action: String,
+    argument: Option<&str>,
+    span: Span,
+) -> Result<Action, ActionParseError> {
     use ActionParseError::*;
     match ActionType::from_name(&action) {
         Some(action) => Ok(Action {
             action,
-            arg: argument,
+            arg: argument.map(ActionArgument::parse),
+            span,
         }),
         None => Err(UnknownAction(action))?,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::{parse_entries, CRSEntry};
+
+    /// Writes the argument into an action list and parses it back.
+    fn round_trip(argument: &ActionArgument) -> ActionArgument {
+        let text = format!(
+            "SecAction \"id:1,msg:{},pass\"\n",
+            argument.to_canonical_string()
+        );
+        let entries = parse_entries(&text).unwrap_or_else(|e| panic!("{}: {:?}", text, e));
+        match &entries[..] {
+            [CRSEntry::SecAction { actions, .. }] => {
+                assert_eq!(actions.len(), 3, "{}", text);
+                actions[1].arg.clone().unwrap()
+            }
+            entries => panic!("{:?}", entries),
+        }
+    }
+
+    const VALUES: &[&str] = &[
+        "foo",
+        "a,b",
+        "two words",
+        "tab\tseparated",
+        "it's",
+        "'quoted'",
+        "say \"hi\"",
+        "back\\slash",
+        "trailing\\",
+        "\\",
+        "\\\"",
+        "\\'",
+        "\\\\",
+        "%{tx.0}",
+        "",
+    ];
+
+    #[test]
+    fn canonical_strings_round_trip() {
+        for value in VALUES {
+            for quoting in [Quoting::Unquoted, Quoting::Single] {
+                let argument = ActionArgument::new(*value, quoting);
+                assert_eq!(
+                    round_trip(&argument).value(),
+                    *value,
+                    "{:?} written as {}",
+                    quoting,
+                    argument.to_canonical_string()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unquotable_values_are_single_quoted() {
+        let canonical = |value| ActionArgument::new(value, Quoting::Unquoted).to_canonical_string();
+        assert_eq!(canonical("foo"), "foo");
+        assert_eq!(canonical("a,b"), "'a,b'");
+        assert_eq!(canonical("a b"), "'a b'");
+        assert_eq!(canonical("it's"), "'it\\'s'");
+        assert_eq!(canonical("a\\"), "'a\\\\'");
+        assert_eq!(canonical(""), "''");
+        assert_eq!(canonical("say \"hi\""), "'say \\\"hi\\\"'");
+        assert_eq!(canonical("a\\\"b"), "a\\\\\"b");
+    }
+
+    #[test]
+    fn parsed_arguments_keep_their_raw_text() {
+        for raw in ["foo", "'a,b'", "'it\\'s'", "a\\\"b", "'x\\y'"] {
+            let argument = ActionArgument::parse(raw);
+            assert_eq!(argument.to_string(), raw);
+            assert_eq!(round_trip(&argument), argument);
+        }
+    }
+}
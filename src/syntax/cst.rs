@@ -0,0 +1,475 @@
+//! Lossless (concrete) syntax tree for rule config files.
+//!
+//! Unlike [`CRSEntry`], which only keeps the meaning of each directive, the types in this module
+//! keep the exact text they were parsed from, including comments, blank lines, backslash line
+//! continuations, indentation and quoting style. Writing a [`ConfDocument`] back out with
+//! [`Display`] reproduces the original input byte for byte, and edits made through
+//! [`Directive`] only touch the part of the text that actually changed.
+
+use super::{parse_entry, util, CRSEntry, CRSParseError, Rule, SpanContext};
+use crate::syntax::action::ActionType;
+use pest::iterators::Pair;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, io};
+
+#[derive(Debug)]
+pub enum Item {
+    /// An empty or whitespace-only line, including its line ending.
+    Blank(String),
+    /// A line comment, including its line ending.
+    Comment(String),
+    /// A directive that was parsed successfully.
+    Directive(Box<Directive>),
+    /// A directive that failed to parse, kept verbatim so that the document stays lossless.
+    Unparsed(String),
+}
+
+impl Item {
+    pub fn text(&self) -> &str {
+        match self {
+            Item::Blank(text) | Item::Comment(text) | Item::Unparsed(text) => text,
+            Item::Directive(directive) => directive.text(),
+        }
+    }
+}
+
+/// The location of a single action within the text of a directive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ActionNode {
+    /// The whole action, e.g. `msg:'some message'`.
+    range: Range<usize>,
+    name: Range<usize>,
+    argument: Option<Range<usize>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EditError {
+    #[error("action index {index} out of bounds for directive with {len} actions")]
+    IndexOutOfBounds { index: usize, len: usize },
+    #[error("directive has no action list to edit")]
+    NoActions,
+    #[error("the only action of a directive that requires actions can't be removed")]
+    RequiredAction,
+    #[error("edit produced an invalid directive, {0}")]
+    Invalid(#[from] CRSParseError),
+}
+
+/// A single directive along with the exact text it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Directive {
+    text: String,
+    entry: CRSEntry,
+    /// Byte range of the action list within `text` (excluding the surrounding double quotes).
+    action_list: Option<Range<usize>>,
+    actions: Vec<ActionNode>,
+    ctx: SpanContext,
+}
+
+impl Directive {
+    fn parse(text: String, ctx: SpanContext) -> Result<Self, CRSParseError> {
+        let record = super::parse_directive_record(&text, &ctx)?;
+
+        let mut action_list = None;
+        let mut actions = vec![];
+        if let Some(list) = find_actions(record.clone()) {
+            let span = list.as_span();
+            action_list = Some(span.start()..span.end());
+
+            for action in list.into_inner() {
+                let span = action.as_span();
+                let mut node = ActionNode {
+                    range: span.start()..span.end(),
+                    name: 0..0,
+                    argument: None,
+                };
+                for part in action.into_inner() {
+                    let span = part.as_span();
+                    match part.as_rule() {
+                        Rule::action_name => node.name = span.start()..span.end(),
+                        Rule::action_argument => node.argument = Some(span.start()..span.end()),
+                        _ => unreachable!(),
+                    }
+                }
+                actions.push(node);
+            }
+        }
+
+        let entry = parse_entry(record, &ctx)?;
+        Ok(Self {
+            text,
+            entry,
+            action_list,
+            actions,
+            ctx,
+        })
+    }
+
+    /// The exact text of the directive, including any continuation lines and the final line
+    /// ending.
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The parsed meaning of the directive.
+    ///
+    /// Note that spans are relative to the document as it was originally parsed, edits to
+    /// earlier directives don't shift them.
+    #[inline]
+    pub fn entry(&self) -> &CRSEntry {
+        &self.entry
+    }
+
+    #[inline]
+    pub fn action_count(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// The name of the action at `index`, exactly as written.
+    pub fn action_name(&self, index: usize) -> Option<&str> {
+        self.actions.get(index).map(|a| &self.text[a.name.clone()])
+    }
+
+    /// The argument of the action at `index`, exactly as written (including any quotes).
+    pub fn action_argument(&self, index: usize) -> Option<&str> {
+        self.actions
+            .get(index)?
+            .argument
+            .as_ref()
+            .map(|range| &self.text[range.clone()])
+    }
+
+    /// The full text of the action at `index`, e.g. `tag:'paranoia-level/1'`.
+    pub fn action_text(&self, index: usize) -> Option<&str> {
+        self.actions.get(index).map(|a| &self.text[a.range.clone()])
+    }
+
+    /// Returns the indices of all actions of the given type.
+    pub fn find_actions(&self, action: ActionType) -> impl Iterator<Item = usize> + '_ {
+        (0..self.actions.len()).filter(move |&i| self.action_name(i) == Some(action.name()))
+    }
+
+    /// Replaces the argument of the action at `index`. The argument is inserted as-is, so it
+    /// must include any quotes it should be written with.
+    pub fn set_action_argument(&mut self, index: usize, argument: &str) -> Result<(), EditError> {
+        let node = self.action(index)?;
+        let range = match &node.argument {
+            Some(range) => range.clone(),
+            // no existing argument, so insert one after the action name
+            None => node.name.end..node.name.end,
+        };
+        let replacement = if node.argument.is_some() {
+            argument.to_string()
+        } else {
+            format!(":{}", argument)
+        };
+        self.splice(range, &replacement)
+    }
+
+    /// Replaces the full text of the action at `index`, e.g. with `tag:'paranoia-level/2'`.
+    pub fn replace_action(&mut self, index: usize, action: &str) -> Result<(), EditError> {
+        let range = self.action(index)?.range.clone();
+        self.splice(range, action)
+    }
+
+    /// Inserts an action so that it ends up at `index`, using the same separator (and so the
+    /// same line continuation layout) as the surrounding actions.
+    pub fn insert_action(&mut self, index: usize, action: &str) -> Result<(), EditError> {
+        let len = self.actions.len();
+        if index > len {
+            return Err(EditError::IndexOutOfBounds { index, len });
+        }
+
+        if len == 0 {
+            return self.insert_action_list(action);
+        }
+
+        let separator = self.separator(index);
+        if index == len {
+            let end = self.actions[len - 1].range.end;
+            self.splice(end..end, &format!("{}{}", separator, action))
+        } else {
+            let start = self.actions[index].range.start;
+            self.splice(start..start, &format!("{}{}", action, separator))
+        }
+    }
+
+    /// Removes the action at `index`, along with the separator before it (or after it, for the
+    /// first action). Removing the only action of a SecRule removes its action list, while
+    /// SecAction can't be left without actions.
+    pub fn remove_action(&mut self, index: usize) -> Result<(), EditError> {
+        let node = self.action(index)?;
+        let range = if index > 0 {
+            self.actions[index - 1].range.end..node.range.end
+        } else if let Some(next) = self.actions.get(1) {
+            node.range.start..next.range.start
+        } else {
+            return self.remove_action_list();
+        };
+        self.splice(range, "")
+    }
+
+    fn action(&self, index: usize) -> Result<&ActionNode, EditError> {
+        self.actions.get(index).ok_or(EditError::IndexOutOfBounds {
+            index,
+            len: self.actions.len(),
+        })
+    }
+
+    /// Text between two neighbouring actions near `index`, e.g. `,` or `,\` + newline + indent.
+    fn separator(&self, index: usize) -> String {
+        let before = index.min(self.actions.len() - 1).saturating_sub(1);
+        match (self.actions.get(before), self.actions.get(before + 1)) {
+            (Some(a), Some(b)) => self.text[a.range.end..b.range.start].to_string(),
+            _ => ",".into(),
+        }
+    }
+
+    /// Adds an action list to a directive that doesn't have one yet (only valid for SecRule).
+    fn insert_action_list(&mut self, action: &str) -> Result<(), EditError> {
+        if !matches!(self.entry, CRSEntry::SecRule { .. }) {
+            return Err(EditError::NoActions);
+        }
+        let end = self.text.trim_end_matches(&['\r', '\n'][..]).len();
+        self.splice(end..end, &format!(" \"{}\"", action))
+    }
+
+    /// Removes the action list, along with its quotes and the whitespace and line continuations
+    /// before it (only valid for SecRule).
+    fn remove_action_list(&mut self) -> Result<(), EditError> {
+        if !matches!(self.entry, CRSEntry::SecRule { .. }) {
+            return Err(EditError::RequiredAction);
+        }
+        let list = self.action_list.clone().ok_or(EditError::NoActions)?;
+        // the action list is quoted, and follows the operator's closing quote
+        let start = self.text[..list.start - 1]
+            .trim_end_matches(|c: char| c.is_whitespace() || c == '\\')
+            .len();
+        self.splice(start..list.end + 1, "")
+    }
+
+    /// Replaces `range` within the text and re-parses, leaving the directive unchanged if the
+    /// result is invalid.
+    fn splice(&mut self, range: Range<usize>, replacement: &str) -> Result<(), EditError> {
+        let mut text = self.text.clone();
+        text.replace_range(range, replacement);
+        *self = Directive::parse(text, self.ctx.clone())?;
+        Ok(())
+    }
+
+    /// Byte range of the action list within the directive text.
+    #[inline]
+    pub fn action_list_range(&self) -> Option<Range<usize>> {
+        self.action_list.clone()
+    }
+}
+
+impl Display for Directive {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn find_actions(record: Pair<Rule>) -> Option<Pair<Rule>> {
+    record
+        .into_inner()
+        .find(|part| part.as_rule() == Rule::actions)
+}
+
+/// A rule config file that remembers everything needed to write it back out unchanged.
+#[derive(Debug, Default)]
+pub struct ConfDocument {
+    pub items: Vec<Item>,
+}
+
+impl ConfDocument {
+    /// Parses a document, keeping any directives that fail to parse as [`Item::Unparsed`] and
+    /// returning their errors alongside.
+    pub fn parse(input: &str) -> (Self, Vec<CRSParseError>) {
+        Self::parse_with(input, &SpanContext::default())
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<(Self, Vec<CRSParseError>)> {
+        let content = fs::read_to_string(path.as_ref())?;
+        let ctx = SpanContext::new(Some(Arc::from(path.as_ref())));
+        Ok(Self::parse_with(&content, &ctx))
+    }
+
+    fn parse_with(input: &str, ctx: &SpanContext) -> (Self, Vec<CRSParseError>) {
+        let mut document = Self::default();
+        let mut errors = vec![];
+
+        for segment in util::split_directives(input) {
+            let text = segment.text.to_string();
+            let item = match segment.kind {
+                util::SegmentKind::Blank => Item::Blank(text),
+                util::SegmentKind::Comment => Item::Comment(text),
+                util::SegmentKind::Directive => {
+                    match Directive::parse(text, ctx.with_base(segment.start)) {
+                        Ok(directive) => Item::Directive(Box::new(directive)),
+                        Err(err) => {
+                            errors.push(err);
+                            Item::Unparsed(segment.text.to_string())
+                        }
+                    }
+                }
+            };
+            document.items.push(item);
+        }
+
+        (document, errors)
+    }
+
+    pub fn directives(&self) -> impl Iterator<Item = &Directive> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Directive(directive) => Some(directive.as_ref()),
+            _ => None,
+        })
+    }
+
+    pub fn directives_mut(&mut self) -> impl Iterator<Item = &mut Directive> {
+        self.items.iter_mut().filter_map(|item| match item {
+            Item::Directive(directive) => Some(directive.as_mut()),
+            _ => None,
+        })
+    }
+
+    /// All successfully parsed entries, in order.
+    pub fn entries(&self) -> impl Iterator<Item = &CRSEntry> {
+        self.directives().map(Directive::entry)
+    }
+}
+
+impl Display for ConfDocument {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.items
+            .iter()
+            .try_for_each(|item| f.write_str(item.text()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directive(text: &str) -> Directive {
+        let (document, errors) = ConfDocument::parse(text);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let directive = document.directives().next().unwrap().clone();
+        directive
+    }
+
+    const RULE: &str = "SecRule ARGS \"@rx foo\" \\\n    \"id:1,\\\n    phase:2,\\\n    msg:'some message',\\\n    pass\"\n";
+
+    #[test]
+    fn round_trips_documents() {
+        let input = format!("# comment\n\n{}SecAction \"id:2,pass\"\n", RULE);
+        let (document, errors) = ConfDocument::parse(&input);
+        assert!(errors.is_empty());
+        assert_eq!(document.to_string(), input);
+        assert_eq!(document.entries().count(), 2);
+    }
+
+    #[test]
+    fn reads_actions() {
+        let directive = directive(RULE);
+        assert_eq!(directive.action_count(), 4);
+        assert_eq!(directive.action_name(2), Some("msg"));
+        assert_eq!(directive.action_argument(2), Some("'some message'"));
+        assert_eq!(directive.action_text(3), Some("pass"));
+        assert_eq!(directive.action_argument(3), None);
+        assert_eq!(
+            directive
+                .find_actions(ActionType::Phase)
+                .collect::<Vec<_>>(),
+            [1]
+        );
+    }
+
+    #[test]
+    fn sets_action_arguments() {
+        let mut directive = directive("SecAction \"id:1,pass,msg:'a'\"\n");
+        directive.set_action_argument(2, "'b'").unwrap();
+        assert_eq!(directive.text(), "SecAction \"id:1,pass,msg:'b'\"\n");
+        directive.replace_action(1, "block").unwrap();
+        assert_eq!(directive.text(), "SecAction \"id:1,block,msg:'b'\"\n");
+
+        let mut directive = self::directive("SecAction \"id:1,t\"\n");
+        directive.set_action_argument(1, "none").unwrap();
+        assert_eq!(directive.text(), "SecAction \"id:1,t:none\"\n");
+    }
+
+    #[test]
+    fn inserts_actions_with_the_same_layout() {
+        let mut directive = directive(RULE);
+        directive.insert_action(4, "tag:'a'").unwrap();
+        directive.insert_action(0, "nolog").unwrap();
+        assert_eq!(
+            directive.text(),
+            "SecRule ARGS \"@rx foo\" \\\n    \"nolog,\\\n    id:1,\\\n    phase:2,\\\n    msg:'some message',\\\n    pass,\\\n    tag:'a'\"\n"
+        );
+        assert!(matches!(
+            directive.insert_action(10, "pass"),
+            Err(EditError::IndexOutOfBounds { index: 10, len: 6 })
+        ));
+    }
+
+    #[test]
+    fn inserts_an_action_list() {
+        let mut directive = directive("SecRule ARGS \"@rx foo\"\n");
+        directive.insert_action(0, "id:1").unwrap();
+        assert_eq!(directive.text(), "SecRule ARGS \"@rx foo\" \"id:1\"\n");
+
+        let mut marker = self::directive("SecMarker \"END\"\n");
+        assert!(matches!(
+            marker.insert_action(0, "id:1"),
+            Err(EditError::NoActions)
+        ));
+    }
+
+    #[test]
+    fn removes_actions() {
+        let mut directive = directive(RULE);
+        directive.remove_action(0).unwrap();
+        directive.remove_action(1).unwrap();
+        assert_eq!(
+            directive.text(),
+            "SecRule ARGS \"@rx foo\" \\\n    \"phase:2,\\\n    pass\"\n"
+        );
+        directive.remove_action(1).unwrap();
+        assert_eq!(
+            directive.text(),
+            "SecRule ARGS \"@rx foo\" \\\n    \"phase:2\"\n"
+        );
+        directive.remove_action(0).unwrap();
+        assert_eq!(directive.text(), "SecRule ARGS \"@rx foo\"\n");
+        assert_eq!(directive.action_count(), 0);
+        assert!(matches!(
+            directive.remove_action(0),
+            Err(EditError::IndexOutOfBounds { index: 0, len: 0 })
+        ));
+    }
+
+    #[test]
+    fn keeps_the_only_action_of_sec_action() {
+        let mut directive = directive("SecAction \"id:1\"\n");
+        assert!(matches!(
+            directive.remove_action(0),
+            Err(EditError::RequiredAction)
+        ));
+        assert_eq!(directive.text(), "SecAction \"id:1\"\n");
+    }
+
+    #[test]
+    fn rejects_invalid_edits() {
+        let mut directive = directive("SecAction \"id:1,pass\"\n");
+        assert!(matches!(
+            directive.replace_action(1, "bogus"),
+            Err(EditError::Invalid(_))
+        ));
+        assert_eq!(directive.text(), "SecAction \"id:1,pass\"\n");
+    }
+}
@@ -1,4 +1,4 @@
-use super::Rule;
+use super::{Rule, Span, SpanContext};
 use crate::enum_token;
 use pest::iterators::Pair;
 use std::fmt::{Display, Formatter};
@@ -8,6 +8,7 @@ use thiserror::Error;
 pub struct Input {
     pub input: InputType,
     pub selector: Selector,
+    pub span: Span,
 }
 
 enum_token! {
@@ -40,10 +41,17 @@ enum_token! {
         /// Contains a collection of original file names (as they were called on the remote user's
         /// filesystem). Available only on inspected multipart/form-data requests.
         Files                = "FILES",
+        /// Contains the size of the entire request, including the request line, headers and
+        /// body.
+        FullRequestLength    = "FULL_REQUEST_LENGTH",
         /// GEO is a collection populated by the results of the last @geoLookup operator.
         /// The collection can be used to match geographical fields looked from an IP address or
         /// hostname.
         Geo                  = "GEO",
+        /// A persistent collection shared by all transactions, opened with `initcol:global=...`.
+        Global               = "GLOBAL",
+        /// A persistent collection of data about the client, usually keyed by its IP address
+        /// and opened with `initcol:ip=...`.
         Ip                   = "IP",
         /// This variable holds the value of the most-recently matched variable. It is similar to
         /// TX:0, but it is automatically supported by all operators and there is no need to specify
@@ -77,6 +85,8 @@ enum_token! {
         /// application/x-www-form-urlencoded content type is detected, or if the use of the
         /// URLENCODED request body parser was forced.
         RequestBody          = "REQUEST_BODY",
+        /// Contains the number of bytes read from the request body.
+        RequestBodyLength    = "REQUEST_BODY_LENGTH",
         /// This variable is a collection of the names of all request cookies.
         RequestCookiesNames  = "REQUEST_COOKIES_NAMES",
         /// This variable is a collection of all of request cookies (values only).
@@ -107,11 +117,35 @@ enum_token! {
         /// Same as REQUEST_URI but will contain the domain name if it was provided on the request
         /// line (e.g., http://www.example.com/index.php?p=X).
         RequestUriRaw        = "REQUEST_URI_RAW",
+        /// A persistent collection of data about the requested resource, opened with `setrsc` or
+        /// `initcol:resource=...`.
+        Resource             = "RESOURCE",
         /// This variable holds the data for the response body, but only when response body
         /// buffering is enabled.
         ResponseBody         = "RESPONSE_BODY",
         /// This variable holds the HTTP response status code.
         ResponseStatus       = "RESPONSE_STATUS",
+        /// A persistent collection of data about the user's session, opened with `setsid` or
+        /// `initcol:session=...`.
+        Session              = "SESSION",
+        /// This variable holds a formatted string representing the time (hour:minute:second).
+        Time                 = "TIME",
+        /// This variable holds the current date (1–31).
+        TimeDay              = "TIME_DAY",
+        /// This variable holds the time in seconds since 1970.
+        TimeEpoch            = "TIME_EPOCH",
+        /// This variable holds the current hour value (0–23).
+        TimeHour             = "TIME_HOUR",
+        /// This variable holds the current minute value (0–59).
+        TimeMin              = "TIME_MIN",
+        /// This variable holds the current month value (0–11).
+        TimeMon              = "TIME_MON",
+        /// This variable holds the current second value (0–59).
+        TimeSec              = "TIME_SEC",
+        /// This variable holds the current weekday value (0–6, where Sunday is 0).
+        TimeWday             = "TIME_WDAY",
+        /// This variable holds the current four-digit year value.
+        TimeYear             = "TIME_YEAR",
         /// This is the transient transaction collection, which is used to store pieces of data,
         /// create a transaction anomaly score, and so on. The variables placed into this collection
         /// are available only until the transaction is complete.
@@ -129,6 +163,9 @@ enum_token! {
         /// token for each request which is guaranteed to be unique across "all" requests under
         /// very specific conditions.
         UniqueId             = "UNIQUE_ID",
+        /// A persistent collection of data about the user, opened with `setuid` or
+        /// `initcol:user=...`.
+        User                 = "USER",
         /// Special collection used to interact with the XML parser. It can be used standalone as a
         /// target for the validateDTD and validateSchema operator. Otherwise, it must contain a
         /// valid XPath expression, which will then be evaluated against a previously parsed XML
@@ -206,9 +243,13 @@ fn parse_selector(
     })
 }
 
-pub fn parse_input(input_record: Pair<Rule>) -> Result<Input, InputParseError> {
+pub(crate) fn parse_input(
+    input_record: Pair<Rule>,
+    ctx: &SpanContext,
+) -> Result<Input, InputParseError> {
     use InputParseError::*;
     let record = input_record.as_str();
+    let span = ctx.span(input_record.as_span());
 
     let mut input_type = None;
     let mut selector = None;
@@ -236,6 +277,7 @@ pub fn parse_input(input_record: Pair<Rule>) -> Result<Input, InputParseError> {
     let input = Input {
         input: input_type.expect("input_type should never be None"),
         selector: parse_selector(modifier, selector, record)?,
+        span,
     };
 
     Ok(input)
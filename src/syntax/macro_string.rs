@@ -0,0 +1,109 @@
+use std::fmt::{Display, Formatter};
+
+/// A single piece of a [`MacroString`].
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum MacroPart {
+    Literal(String),
+    /// A macro such as `%{tx.anomaly_score}` or `%{MATCHED_VAR}`.
+    ///
+    /// The collection name is kept as written, but like variable names it should be treated
+    /// case-insensitively.
+    Variable {
+        collection: String,
+        key: Option<String>,
+    },
+}
+
+/// A string that may contain macros (`%{collection.key}`) which are expanded at runtime.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct MacroString {
+    pub parts: Vec<MacroPart>,
+}
+
+impl MacroString {
+    /// Splits a string into literals and macros. Text that looks like the start of a macro but
+    /// is never closed is kept as a literal.
+    pub fn parse(s: &str) -> Self {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("%{") {
+            let after = &rest[start + 2..];
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+
+            literal.push_str(&rest[..start]);
+            if !literal.is_empty() {
+                parts.push(MacroPart::Literal(std::mem::take(&mut literal)));
+            }
+
+            let name = &after[..end];
+            parts.push(match name.split_once('.') {
+                Some((collection, key)) => MacroPart::Variable {
+                    collection: collection.into(),
+                    key: Some(key.into()),
+                },
+                None => MacroPart::Variable {
+                    collection: name.into(),
+                    key: None,
+                },
+            });
+            rest = &after[end + 1..];
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(MacroPart::Literal(literal));
+        }
+
+        Self { parts }
+    }
+
+    /// Returns true if there's nothing to expand.
+    pub fn is_literal(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, MacroPart::Literal(_)))
+    }
+
+    /// Expands the string, using `resolve` to look up the value of each macro.
+    pub fn expand<F>(&self, mut resolve: F) -> String
+    where
+        F: FnMut(&str, Option<&str>) -> Option<String>,
+    {
+        let mut expanded = String::new();
+        for part in &self.parts {
+            match part {
+                MacroPart::Literal(literal) => expanded.push_str(literal),
+                MacroPart::Variable { collection, key } => {
+                    if let Some(value) = resolve(collection, key.as_deref()) {
+                        expanded.push_str(&value);
+                    }
+                }
+            }
+        }
+        expanded
+    }
+}
+
+impl Display for MacroString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for part in &self.parts {
+            match part {
+                MacroPart::Literal(literal) => f.write_str(literal)?,
+                MacroPart::Variable {
+                    collection,
+                    key: Some(key),
+                } => write!(f, "%{{{}.{}}}", collection, key)?,
+                MacroPart::Variable {
+                    collection,
+                    key: None,
+                } => write!(f, "%{{{}}}", collection)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,17 +2,28 @@ use pest::iterators::Pair;
 use pest::Parser;
 use std::fmt::{Debug, Display, Formatter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{fs, io};
 use thiserror::Error;
 
 mod action;
+pub mod cst;
 mod input;
+mod macro_string;
 mod operator;
+pub mod pretty;
+mod span;
 mod util;
 
-pub use action::Action;
+pub use action::{Action, ActionArgument, ActionKind, ActionType, Quoting};
 pub use input::{Input, InputType, Selector};
-pub use operator::Operator;
+pub use macro_string::{MacroPart, MacroString};
+pub use operator::{
+    atoi, compile_regex, Cidr, Operator, OperatorArgument, OperatorType, Substitution,
+};
+pub use span::{Position, Span};
+
+use span::SpanContext;
 
 #[derive(pest_derive::Parser)]
 #[grammar = "syntax/crs.pest"]
@@ -20,27 +31,83 @@ struct CRSParser;
 
 #[derive(Error, Debug)]
 pub enum CRSParseError {
-    #[error(transparent)]
-    SyntaxParseError(#[from] pest::error::Error<Rule>),
-    #[error(transparent)]
-    ActionParseError(#[from] action::ActionParseError),
-    #[error(transparent)]
-    OperatorParseError(#[from] operator::OperatorParseError),
-    #[error(transparent)]
-    InputParseError(#[from] input::InputParseError),
-    #[error(transparent)]
-    IoError(#[from] io::Error),
-    #[error(transparent)]
-    FmtError(#[from] std::fmt::Error),
-    #[error("parsing failure during round-trip validation {0}")]
-    RoundTripParseFailed(#[from] Box<CRSParseError>),
-    #[error("round-trip validation failed, expected entries: {expected:?}, actual: {actual:?}")]
+    #[error("{span}: {}", .source.variant.message())]
+    SyntaxParseError {
+        source: Box<pest::error::Error<Rule>>,
+        span: Span,
+    },
+    #[error("{span}: {source}")]
+    ActionParseError {
+        source: action::ActionParseError,
+        span: Span,
+    },
+    #[error("{span}: {source}")]
+    OperatorParseError {
+        source: operator::OperatorParseError,
+        span: Span,
+    },
+    #[error("{span}: {source}")]
+    InputParseError {
+        source: input::InputParseError,
+        span: Span,
+    },
+    #[error("{span}: {source}")]
+    IoError { source: io::Error, span: Span },
+    #[error("{span}: {source}")]
+    FmtError { source: std::fmt::Error, span: Span },
+    #[error("{span}: parsing failure during round-trip validation {source}")]
+    RoundTripParseFailed {
+        source: Box<CRSParseError>,
+        span: Span,
+    },
+    #[error(
+        "{span}: round-trip validation failed, expected entry: {expected:?}, actual: {actual:?}"
+    )]
     RoundTripNotEqual {
-        expected: Vec<CRSEntry>,
+        expected: Box<CRSEntry>,
         actual: Vec<CRSEntry>,
+        span: Span,
     },
 }
 
+impl CRSParseError {
+    /// The location in the source file that this error refers to.
+    pub fn span(&self) -> &Span {
+        match self {
+            CRSParseError::SyntaxParseError { span, .. }
+            | CRSParseError::ActionParseError { span, .. }
+            | CRSParseError::OperatorParseError { span, .. }
+            | CRSParseError::InputParseError { span, .. }
+            | CRSParseError::IoError { span, .. }
+            | CRSParseError::FmtError { span, .. }
+            | CRSParseError::RoundTripParseFailed { span, .. }
+            | CRSParseError::RoundTripNotEqual { span, .. } => span,
+        }
+    }
+
+    /// The message of the error without its location, for reporting it alongside the span.
+    pub fn message(&self) -> String {
+        match self {
+            CRSParseError::SyntaxParseError { source, .. } => source.variant.message().into(),
+            CRSParseError::ActionParseError { source, .. } => source.to_string(),
+            CRSParseError::OperatorParseError { source, .. } => source.to_string(),
+            CRSParseError::InputParseError { source, .. } => source.to_string(),
+            CRSParseError::IoError { source, .. } => source.to_string(),
+            CRSParseError::FmtError { source, .. } => source.to_string(),
+            CRSParseError::RoundTripParseFailed { source, .. } => format!(
+                "parsing failure during round-trip validation {}",
+                source.message()
+            ),
+            CRSParseError::RoundTripNotEqual {
+                expected, actual, ..
+            } => format!(
+                "round-trip validation failed, expected entry: {:?}, actual: {:?}",
+                expected, actual
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CRSFile {
     pub path: PathBuf,
@@ -52,22 +119,39 @@ pub enum CRSEntry {
     /// Adds a fixed rule marker that can be used as a target in a skipAfter action.
     /// A SecMarker directive essentially creates a rule that does nothing and whose
     /// only purpose is to carry the given ID.
-    SecMarker(String),
+    SecMarker { marker: String, span: Span },
     /// Unconditionally processes the action list it receives as the first and only parameter.
     /// The syntax of the parameter is identical to that of the third parameter of SecRule.
-    SecAction(Vec<Action>),
+    SecAction { actions: Vec<Action>, span: Span },
     /// Appends component signature to the ModSecurity signature. This directive is used to make
     /// the presence of significant rule sets known. The entire signature will be recorded in the
     /// transaction audit log.
-    SecComponentSignature(String),
+    SecComponentSignature { signature: String, span: Span },
+    /// Sets how many seconds records of persistent collections are kept after their last
+    /// update, unless a record's `TIMEOUT` variable is set.
+    SecCollectionTimeout { seconds: u64, span: Span },
     /// Creates a rule that will analyze the selected variables using the selected operator.
     SecRule {
         inputs: Vec<Input>,
         test: Test,
         actions: Vec<Action>,
+        span: Span,
     },
 }
 
+impl CRSEntry {
+    /// The location of the whole directive in the source file.
+    pub fn span(&self) -> &Span {
+        match self {
+            CRSEntry::SecMarker { span, .. }
+            | CRSEntry::SecAction { span, .. }
+            | CRSEntry::SecComponentSignature { span, .. }
+            | CRSEntry::SecCollectionTimeout { span, .. }
+            | CRSEntry::SecRule { span, .. } => span,
+        }
+    }
+}
+
 fn fmt_iter_join<'a, 'b: 'a, T: Display + 'a>(
     mut iter: impl Iterator<Item = &'a T>,
     f: &mut Formatter<'b>,
@@ -94,18 +178,22 @@ fn format_inputs(inputs: &[Input], f: &mut Formatter<'_>) -> std::fmt::Result {
 impl Display for CRSEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            CRSEntry::SecMarker(marker) => write!(f, "SecMarker \"{}\"", marker),
-            CRSEntry::SecAction(actions) => {
+            CRSEntry::SecMarker { marker, .. } => write!(f, "SecMarker \"{}\"", marker),
+            CRSEntry::SecAction { actions, .. } => {
                 write!(f, "SecAction ")?;
                 format_actions(actions, f)
             }
-            CRSEntry::SecComponentSignature(signature) => {
+            CRSEntry::SecComponentSignature { signature, .. } => {
                 write!(f, "SecComponentSignature \"{}\"", signature)
             }
+            CRSEntry::SecCollectionTimeout { seconds, .. } => {
+                write!(f, "SecCollectionTimeout {}", seconds)
+            }
             CRSEntry::SecRule {
                 inputs,
                 test,
                 actions,
+                ..
             } => {
                 write!(f, "SecRule ")?;
                 format_inputs(inputs, f)?;
@@ -137,86 +225,240 @@ impl Display for Test {
     }
 }
 
-fn parse_entries_impl(input: &str) -> Result<Vec<CRSEntry>, CRSParseError> {
+fn parse_entries_impl(input: &str, ctx: &SpanContext) -> Result<Vec<CRSEntry>, CRSParseError> {
     // get and unwrap the `conf` rule (there's only one, so we can unwrap without worrying)
-    let file = CRSParser::parse(Rule::crs, &input)?.next().unwrap();
+    let file_record = CRSParser::parse(Rule::crs, input)
+        .map_err(|source| CRSParseError::SyntaxParseError {
+            span: ctx.error_span(&source),
+            source: Box::new(source),
+        })?
+        .next()
+        .unwrap();
 
-    file.into_inner()
+    file_record
+        .into_inner()
         .filter(|record| record.as_rule() != Rule::EOI)
-        .map(|record| match record.as_rule() {
-            Rule::sec_marker => Ok(CRSEntry::SecMarker(record.into_inner().as_str().into())),
-            Rule::sec_action => Ok(CRSEntry::SecAction(parse_actions(
-                record.into_inner().next().unwrap(),
-            )?)),
-            Rule::sec_component_signature => Ok(CRSEntry::SecComponentSignature(
-                record.into_inner().as_str().into(),
-            )),
-            Rule::sec_rule => parse_sec_rule(record),
-            _ => unreachable!(),
-        })
+        .map(|record| parse_entry(record, ctx))
         .collect()
 }
 
-fn reserialize(entries: &[CRSEntry]) -> Result<String, std::fmt::Error> {
-    let mut reserialized = String::default();
-    for entry in entries {
-        write!(reserialized, "{}\n", entry)?;
+fn parse_entry(record: Pair<Rule>, ctx: &SpanContext) -> Result<CRSEntry, CRSParseError> {
+    let span = ctx.span(record.as_span());
+    match record.as_rule() {
+        Rule::sec_marker => Ok(CRSEntry::SecMarker {
+            marker: record.into_inner().as_str().into(),
+            span,
+        }),
+        Rule::sec_action => Ok(CRSEntry::SecAction {
+            actions: parse_actions(record.into_inner().next().unwrap(), ctx)?,
+            span,
+        }),
+        Rule::sec_component_signature => Ok(CRSEntry::SecComponentSignature {
+            signature: record.into_inner().as_str().into(),
+            span,
+        }),
+        Rule::sec_collection_timeout => {
+            let seconds = record.into_inner().next().unwrap();
+            Ok(CRSEntry::SecCollectionTimeout {
+                // the grammar only allows digits, so only an overflow can fail
+                seconds: seconds.as_str().parse().unwrap_or(u64::MAX),
+                span,
+            })
+        }
+        Rule::sec_rule => parse_sec_rule(record, ctx),
+        _ => unreachable!(),
     }
-    Ok(reserialized)
 }
 
-fn reparse(entries: &[CRSEntry]) -> Result<Vec<CRSEntry>, CRSParseError> {
-    let reserialized = reserialize(entries)?;
-    match parse_entries_impl(&reserialized) {
-        Ok(reparsed) => Ok(reparsed),
-        Err(err) => Err(CRSParseError::RoundTripParseFailed(Box::new(err))),
+/// Checks that an entry survives being written out and parsed back in unchanged.
+fn validate_round_trip(entry: &CRSEntry) -> Result<(), CRSParseError> {
+    let span = entry.span();
+
+    let mut reserialized = String::default();
+    writeln!(reserialized, "{}", entry).map_err(|source| CRSParseError::FmtError {
+        source,
+        span: span.clone(),
+    })?;
+
+    let reparsed = match parse_entries_impl(&reserialized, &SpanContext::default()) {
+        Ok(reparsed) => reparsed,
+        Err(err) => {
+            return Err(CRSParseError::RoundTripParseFailed {
+                source: Box::new(err),
+                span: span.clone(),
+            })
+        }
+    };
+
+    if reparsed.len() != 1 || &reparsed[0] != entry {
+        Err(CRSParseError::RoundTripNotEqual {
+            expected: Box::new(entry.clone()),
+            actual: reparsed,
+            span: span.clone(),
+        })
+    } else {
+        Ok(())
     }
 }
 
 pub fn parse_all_conf<P: AsRef<Path>>(dir: P) -> Result<Vec<CRSFile>, CRSParseError> {
-    util::get_rule_configs(dir)?
+    get_rule_configs(dir.as_ref())?
         .into_iter()
-        .map(|path| parse_conf(path))
+        .map(parse_conf)
         .collect()
 }
 
 pub fn parse_conf<P: AsRef<Path>>(path: P) -> Result<CRSFile, CRSParseError> {
-    let file_content = fs::read_to_string(path.as_ref())?;
+    let (file_content, ctx) = read_conf(path.as_ref())?;
 
     Ok(CRSFile {
         path: path.as_ref().to_owned(),
-        entries: parse_entries(&file_content)?,
+        entries: parse_entries_from(&file_content, &ctx)?,
     })
 }
 
 pub fn parse_entries(input: &str) -> Result<Vec<CRSEntry>, CRSParseError> {
-    let entries = parse_entries_impl(input)?;
-    let entries_reparsed = reparse(&entries)?;
+    parse_entries_from(input, &SpanContext::default())
+}
 
-    if entries != entries_reparsed {
-        Err(CRSParseError::RoundTripNotEqual {
-            actual: entries_reparsed,
-            expected: entries,
-        })
-    } else {
-        Ok(entries)
+fn parse_entries_from(input: &str, ctx: &SpanContext) -> Result<Vec<CRSEntry>, CRSParseError> {
+    let entries = parse_entries_impl(input, ctx)?;
+    for entry in &entries {
+        validate_round_trip(entry)?;
     }
+    Ok(entries)
 }
 
-fn parse_sec_rule(record: Pair<Rule>) -> Result<CRSEntry, CRSParseError> {
+/// The result of parsing in error-recovering mode: every entry that could be parsed, along with
+/// every error that was encountered along the way.
+#[derive(Debug, Default)]
+pub struct ParseReport {
+    /// The rule config the entries were read from, if any.
+    pub path: Option<PathBuf>,
+    pub entries: Vec<CRSEntry>,
+    pub errors: Vec<CRSParseError>,
+}
+
+impl ParseReport {
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Splits the report into the file that was parsed and the errors.
+    pub fn into_file(self) -> (CRSFile, Vec<CRSParseError>) {
+        let file = CRSFile {
+            path: self.path.unwrap_or_default(),
+            entries: self.entries,
+        };
+        (file, self.errors)
+    }
+}
+
+/// Parses all rule configs in a directory in error-recovering mode, see
+/// [`parse_entries_recovering`], returning a report for each file.
+pub fn parse_all_conf_recovering<P: AsRef<Path>>(
+    dir: P,
+) -> Result<Vec<ParseReport>, CRSParseError> {
+    get_rule_configs(dir.as_ref())?
+        .into_iter()
+        .map(parse_conf_recovering)
+        .collect()
+}
+
+/// Parses a rule config in error-recovering mode, see [`parse_entries_recovering`]. Only failing
+/// to read the file is treated as a fatal error.
+pub fn parse_conf_recovering<P: AsRef<Path>>(path: P) -> Result<ParseReport, CRSParseError> {
+    let (file_content, ctx) = read_conf(path.as_ref())?;
+    Ok(ParseReport {
+        path: Some(path.as_ref().to_owned()),
+        ..parse_entries_recovering_from(&file_content, &ctx)
+    })
+}
+
+/// Parses entries without stopping at the first error.
+///
+/// The input is split into directives (following backslash line continuations) and each one is
+/// parsed on its own, so a syntax error or unknown action/operator/input only causes the
+/// directive it appears in to be skipped. All errors are returned with spans relative to the
+/// start of `input`.
+pub fn parse_entries_recovering(input: &str) -> ParseReport {
+    parse_entries_recovering_from(input, &SpanContext::default())
+}
+
+fn parse_entries_recovering_from(input: &str, ctx: &SpanContext) -> ParseReport {
+    let mut report = ParseReport::default();
+    for segment in util::split_directives(input) {
+        if segment.kind != util::SegmentKind::Directive {
+            continue;
+        }
+
+        let result = parse_directive(segment.text, &ctx.with_base(segment.start))
+            .and_then(|entry| validate_round_trip(&entry).map(|_| entry));
+
+        match result {
+            Ok(entry) => report.entries.push(entry),
+            Err(err) => report.errors.push(err),
+        }
+    }
+    report
+}
+
+/// Parses a single directive (which may span multiple lines).
+fn parse_directive(input: &str, ctx: &SpanContext) -> Result<CRSEntry, CRSParseError> {
+    parse_entry(parse_directive_record(input, ctx)?, ctx)
+}
+
+/// Runs the grammar over a single directive, returning the record for the directive itself.
+fn parse_directive_record<'i>(
+    input: &'i str,
+    ctx: &SpanContext,
+) -> Result<Pair<'i, Rule>, CRSParseError> {
+    Ok(CRSParser::parse(Rule::directive, input)
+        .map_err(|source| CRSParseError::SyntaxParseError {
+            span: ctx.error_span(&source),
+            source: Box::new(source),
+        })?
+        .next()
+        .unwrap()
+        .into_inner()
+        .next()
+        .unwrap())
+}
+
+fn get_rule_configs(dir: &Path) -> Result<Vec<PathBuf>, CRSParseError> {
+    util::get_rule_configs(dir).map_err(|source| CRSParseError::IoError {
+        source,
+        span: Span::for_file(Some(&Arc::from(dir))),
+    })
+}
+
+fn read_conf(path: &Path) -> Result<(String, SpanContext), CRSParseError> {
+    let ctx = SpanContext::new(Some(Arc::from(path)));
+    match fs::read_to_string(path) {
+        Ok(file_content) => Ok((file_content, ctx)),
+        Err(source) => Err(CRSParseError::IoError {
+            source,
+            span: Span::for_file(ctx.file()),
+        }),
+    }
+}
+
+fn parse_sec_rule(record: Pair<Rule>, ctx: &SpanContext) -> Result<CRSEntry, CRSParseError> {
+    let span = ctx.span(record.as_span());
     let mut actions = Default::default();
     let mut inputs = Default::default();
     let mut test = None;
     for part in record.into_inner() {
         match part.as_rule() {
             Rule::inputs => {
-                inputs = parse_inputs(part)?;
+                inputs = parse_inputs(part, ctx)?;
             }
             Rule::test => {
-                test = Some(parse_test(part)?);
+                test = Some(parse_test(part, ctx)?);
             }
             Rule::actions => {
-                actions = parse_actions(part)?;
+                actions = parse_actions(part, ctx)?;
             }
             _ => unreachable!(),
         }
@@ -226,13 +468,18 @@ fn parse_sec_rule(record: Pair<Rule>) -> Result<CRSEntry, CRSParseError> {
         actions,
         inputs,
         test: test.expect("secrules should always have a test defined"),
+        span,
     })
 }
 
-fn parse_actions(action_record: Pair<Rule>) -> Result<Vec<Action>, action::ActionParseError> {
+fn parse_actions(
+    action_record: Pair<Rule>,
+    ctx: &SpanContext,
+) -> Result<Vec<Action>, CRSParseError> {
     action_record
         .into_inner()
         .map(|action| {
+            let span = ctx.span(action.as_span());
             let mut name = Default::default();
             let mut argument = None;
 
@@ -242,22 +489,31 @@ fn parse_actions(action_record: Pair<Rule>) -> Result<Vec<Action>, action::Actio
                         name = part.as_str().into();
                     }
                     Rule::action_argument => {
-                        argument = Some(part.as_str().into());
+                        argument = Some(part.as_str());
                     }
                     _ => unreachable!(),
                 }
             }
 
-            action::parse_action(name, argument)
+            action::parse_action(name, argument, span.clone())
+                .map_err(|source| CRSParseError::ActionParseError { source, span })
         })
         .collect()
 }
 
-fn parse_inputs(input_record: Pair<Rule>) -> Result<Vec<Input>, input::InputParseError> {
-    input_record.into_inner().map(input::parse_input).collect()
+fn parse_inputs(input_record: Pair<Rule>, ctx: &SpanContext) -> Result<Vec<Input>, CRSParseError> {
+    input_record
+        .into_inner()
+        .map(|input| {
+            let span = ctx.span(input.as_span());
+            input::parse_input(input, ctx)
+                .map_err(|source| CRSParseError::InputParseError { source, span })
+        })
+        .collect()
 }
 
-fn parse_test(test_record: Pair<Rule>) -> Result<Test, operator::OperatorParseError> {
+fn parse_test(test_record: Pair<Rule>, ctx: &SpanContext) -> Result<Test, CRSParseError> {
+    let span = ctx.span(test_record.as_span());
     let mut invert = false;
     let mut operator = Default::default();
     let mut argument = None;
@@ -268,7 +524,7 @@ fn parse_test(test_record: Pair<Rule>) -> Result<Test, operator::OperatorParseEr
                 invert = true;
             }
             Rule::test_operator => {
-                operator = part.as_str().into();
+                operator = part.as_str();
             }
             Rule::test_argument => {
                 argument = Some(part.as_str().into());
@@ -279,6 +535,7 @@ fn parse_test(test_record: Pair<Rule>) -> Result<Test, operator::OperatorParseEr
 
     Ok(Test {
         invert,
-        operator: operator::parse_operator(operator, argument)?,
+        operator: operator::parse_operator(operator, argument, span.clone())
+            .map_err(|source| CRSParseError::OperatorParseError { source, span })?,
     })
 }
@@ -1,9 +1,17 @@
+use super::{MacroString, Span};
 use crate::enum_token;
 use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
 use thiserror::Error;
 
 enum_token! {
     pub enum OperatorType {
+        /// Returns true if the parameter string is found at the beginning of the input. Macro
+        /// expansion is performed on the parameter string before comparison.
+        BeginsWith           = "beginsWith",
         /// Returns true if the parameter string is found anywhere in the input. Macro expansion
         /// is performed on the parameter string before comparison.
         Contains             = "contains",
@@ -41,6 +49,13 @@ enum_token! {
         /// Note: If a value is provided that cannot be converted to an integer (i.e a string) this
         /// operator will treat that value as 0.
         Gt                   = "gt",
+        /// Performs numerical comparison and returns true if the input value is less than or
+        /// equal to the operator parameter. Macro expansion is performed on the parameter string
+        /// before comparison.
+        ///
+        /// Note: If a value is provided that cannot be converted to an integer (i.e a string) this
+        /// operator will treat that value as 0.
+        Le                   = "le",
         /// Performs numerical comparison and returns true if the input value is less than the
         /// operator parameter. Macro expansion is performed on the parameter string before
         /// comparison.
@@ -54,10 +69,20 @@ enum_token! {
         ///
         /// Note: The geoLookup operator matches on success.
         GeoLookup            = "geoLookup",
+        /// Executes an external program for every variable in the target list. The contents of the
+        /// variable is provided to the script as the first parameter on the command line. The
+        /// program must be specified as the first parameter to the operator. As of version 2.5.0,
+        /// if the supplied program filename is not absolute, it is treated as relative to the
+        /// directory in which the configuration file resides.
+        ///
+        /// The operator matches unless the first line of the program's output begins with `1`.
+        InspectFile          = "inspectFile",
         /// Performs a fast ipv4 or ipv6 match of REMOTE_ADDR variable data.
         IpMatch              = "ipMatch",
         /// Performs a fast ipv4 or ipv6 match of REMOTE_ADDR variable, loading data from a file.
         IpMatchFromFile      = "ipMatchFromFile",
+        /// Will force the rule to always return false.
+        NoMatch              = "noMatch",
         /// Performs a case-insensitive match of the provided phrases against the desired input
         /// value. The operator uses a set-based matching algorithm (Aho-Corasick), which means
         /// that it will match any number of keywords in parallel. When matching of a large number
@@ -90,14 +115,35 @@ enum_token! {
         ///
         /// Note: This operator supports the "capture" action.
         Regex                = "rx",
+        /// Performs regular expression data substitution when applied to either the
+        /// STREAM_INPUT_BODY or STREAM_OUTPUT_BODY variables. The syntax is
+        /// `s/regex/replacement/flags`, where the supported flags are `i` (case-insensitive
+        /// matching) and `d` (treat the pattern as a literal string).
+        RegexSubstitute      = "rsub",
         /// Performs a string comparison and returns true if the parameter string is identical
         /// to the input string. Macro expansion is performed on the parameter string before
         /// comparison.
         StringEquals         = "streq",
+        /// Performs a string match of the provided word against the desired input value. The
+        /// operator uses the pattern matching Boyer-Moore-Horspool algorithm, which means that it
+        /// is a single pattern matching operator. This operator performs much better than a
+        /// regular expression.
+        StringMatch          = "strmatch",
+        /// Will force the rule to always return true. This is similar to SecAction however all
+        /// actions that alter the transaction are also applied.
+        UnconditionalMatch   = "unconditionalMatch",
         /// Validates that the byte values used in input fall into the range specified by the
         /// operator parameter. This operator matches on an input value that contains bytes that
         /// are not in the specified range.
         ValidateByteRange    = "validateByteRange",
+        /// Validates the XML DOM tree against the supplied DTD. The DOM tree must have been built
+        /// previously using the XML request body processor. This operator matches when the
+        /// validation fails.
+        ValidateDTD          = "validateDTD",
+        /// Validates the XML DOM tree against the supplied XML Schema. The DOM tree must have been
+        /// built previously using the XML request body processor. This operator matches when the
+        /// validation fails.
+        ValidateSchema       = "validateSchema",
         /// Validates the URL-encoded characters in the provided input string.
         ///
         /// ModSecurity will automatically decode the URL-encoded characters in request parameters,
@@ -107,6 +153,10 @@ enum_token! {
         ValidateUrlEncoding  = "validateUrlEncoding",
         /// Check whether the input is a valid UTF-8 string.
         ValidateUtf8Encoding = "validateUtf8Encoding",
+        /// Detects credit card numbers in input. This operator will first use the supplied regular
+        /// expression to perform an initial match, following up with the Luhn algorithm
+        /// calculation to minimize false positives.
+        VerifyCC             = "verifyCC",
         /// Returns true if the input value (the needle) is found anywhere within the @within
         /// parameter (the haystack). Macro expansion is performed on the parameter string before
         /// comparison.
@@ -114,16 +164,181 @@ enum_token! {
     }
 }
 
+impl OperatorType {
+    /// Whether the operator stores what it matched in `TX:0`-`TX:9` when used with the `capture`
+    /// action.
+    pub fn supports_capture(&self) -> bool {
+        use OperatorType::*;
+        matches!(
+            self,
+            Regex | PatternMatch | PatternMatchFromFile | DetectSQLi | DetectXSS
+        )
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Operator {
     pub op: OperatorType,
+    /// The argument exactly as written.
     pub arg: Option<String>,
+    /// The argument parsed according to the operator type.
+    pub argument: OperatorArgument,
+    pub span: Span,
+}
+
+/// Typed operator arguments, parsed and validated when the rule is loaded.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OperatorArgument {
+    /// The operator doesn't take an argument.
+    None,
+    /// Whitespace separated phrases, e.g. for `@pm`.
+    PhraseList(Vec<String>),
+    /// Comma separated IP addresses and/or networks, e.g. for `@ipMatch`.
+    CidrList(Vec<Cidr>),
+    /// Comma separated byte values and ranges, e.g. `1-255,9,10` for `@validateByteRange`.
+    ByteRanges(Vec<RangeInclusive<u8>>),
+    /// Whitespace separated file paths, e.g. for `@pmFromFile`.
+    FileList(Vec<PathBuf>),
+    /// A literal number, for the numeric comparison operators.
+    Number(i64),
+    /// A string that may contain macros to be expanded at runtime.
+    MacroString(MacroString),
+    /// A regular expression that has been checked to compile.
+    Regex(String),
+    /// A regular expression substitution, for `@rsub`.
+    Substitution(Substitution),
+}
+
+/// A parsed `s/regex/replacement/flags` expression.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Substitution {
+    /// The pattern, with any flags already applied (so it can be compiled as-is).
+    pub pattern: String,
+    pub replacement: String,
+}
+
+impl FromStr for Substitution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected s/regex/replacement/flags, got {}", s);
+        let rest = s.strip_prefix("s/").ok_or_else(invalid)?;
+
+        // split on unescaped slashes
+        let mut parts = vec![String::new()];
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('/') => parts.last_mut().unwrap().push('/'),
+                    Some(c) => {
+                        let part = parts.last_mut().unwrap();
+                        part.push('\\');
+                        part.push(c);
+                    }
+                    None => parts.last_mut().unwrap().push('\\'),
+                },
+                '/' => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+
+        let (pattern, replacement, flags) = match parts.as_slice() {
+            [pattern, replacement] => (pattern, replacement, ""),
+            [pattern, replacement, flags] => (pattern, replacement, flags.as_str()),
+            _ => return Err(invalid()),
+        };
+
+        let mut pattern = pattern.clone();
+        for flag in flags.chars() {
+            match flag {
+                'd' => pattern = regex::escape(&pattern),
+                'i' => {}
+                _ => return Err(format!("unknown substitution flag {}", flag)),
+            }
+        }
+        if flags.contains('i') {
+            pattern = format!("(?i){}", pattern);
+        }
+
+        Ok(Self {
+            pattern,
+            replacement: replacement.clone(),
+        })
+    }
+}
+
+/// An IPv4 or IPv6 network, e.g. `192.168.0.0/16` or `::1` (a single address).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        fn masked(bits: u128, width: u8, prefix_len: u8) -> u128 {
+            match prefix_len {
+                0 => 0,
+                _ => bits >> (width - prefix_len),
+            }
+        }
+
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let network = u32::from(network) as u128;
+                let addr = u32::from(*addr) as u128;
+                masked(network, 32, self.prefix_len) == masked(addr, 32, self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let network = u128::from(network);
+                let addr = u128::from(*addr);
+                masked(network, 128, self.prefix_len) == masked(addr, 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP address {}", addr))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("invalid network prefix length {}", len)),
+            },
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum OperatorParseError {
     #[error("unknown operator {0}")]
     UnknownOperator(String),
+    #[error("operator @{0} requires an argument")]
+    MissingArgument(&'static str),
+    #[error("invalid argument for operator @{op}, {reason}")]
+    InvalidArgument { op: &'static str, reason: String },
 }
 
 impl Display for Operator {
@@ -136,10 +351,169 @@ impl Display for Operator {
     }
 }
 
-pub fn parse_operator(op: &str, argument: Option<String>) -> Result<Operator, OperatorParseError> {
+/// Compiles a regex with the same settings ModSecurity uses for `@rx` (the input is treated as
+/// a single line and `.` matches newlines). Patterns are matched against raw bytes, though
+/// Unicode-aware patterns are still accepted if they can't be compiled otherwise.
+pub fn compile_regex(pattern: &str) -> Result<regex::bytes::Regex, regex::Error> {
+    let build = |unicode| {
+        regex::bytes::RegexBuilder::new(pattern)
+            .dot_matches_new_line(true)
+            .unicode(unicode)
+            .build()
+    };
+    build(false).or_else(|_| build(true))
+}
+
+fn parse_byte_range(range: &str) -> Result<RangeInclusive<u8>, String> {
+    let parse = |b: &str| {
+        b.trim()
+            .parse::<u8>()
+            .map_err(|_| format!("invalid byte value {}", b.trim()))
+    };
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                Err(format!("invalid byte range {}", range.trim()))
+            } else {
+                Ok(start..=end)
+            }
+        }
+        None => parse(range).map(|b| b..=b),
+    }
+}
+
+fn parse_argument(
+    op: OperatorType,
+    arg: Option<&str>,
+) -> Result<OperatorArgument, OperatorParseError> {
+    use OperatorType::*;
+
+    let invalid = |reason: String| OperatorParseError::InvalidArgument {
+        op: op.name(),
+        reason,
+    };
+    let required = || arg.ok_or(OperatorParseError::MissingArgument(op.name()));
+    let words = |arg: &str| -> Vec<String> { arg.split_whitespace().map(Into::into).collect() };
+
+    Ok(match op {
+        DetectSQLi | DetectXSS | GeoLookup | ValidateUrlEncoding | ValidateUtf8Encoding
+        | NoMatch | UnconditionalMatch => OperatorArgument::None,
+        PatternMatch => {
+            let phrases = words(required()?);
+            if phrases.is_empty() {
+                return Err(invalid("empty phrase list".into()));
+            }
+            OperatorArgument::PhraseList(phrases)
+        }
+        ValidateDTD | ValidateSchema | InspectFile => {
+            let files: Vec<PathBuf> = words(required()?).into_iter().map(Into::into).collect();
+            if files.len() != 1 {
+                return Err(invalid("expected a single file".into()));
+            }
+            OperatorArgument::FileList(files)
+        }
+        PatternMatchFromFile | IpMatchFromFile => {
+            let files: Vec<PathBuf> = words(required()?).into_iter().map(Into::into).collect();
+            if files.is_empty() {
+                return Err(invalid("empty file list".into()));
+            }
+            OperatorArgument::FileList(files)
+        }
+        IpMatch => OperatorArgument::CidrList(
+            required()?
+                .split(',')
+                .map(|cidr| cidr.trim().parse::<Cidr>())
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?,
+        ),
+        ValidateByteRange => OperatorArgument::ByteRanges(
+            required()?
+                .split(',')
+                .map(parse_byte_range)
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?,
+        ),
+        Eq | Ge | Gt | Le | Lt => {
+            // numbers are parsed the same way as the input they're compared to
+            let arg = arg.unwrap_or_default().trim();
+            let macro_string = MacroString::parse(arg);
+            if !macro_string.is_literal() {
+                OperatorArgument::MacroString(macro_string)
+            } else {
+                OperatorArgument::Number(atoi(arg.as_bytes()))
+            }
+        }
+        RegexSubstitute => {
+            let substitution: Substitution = required()?.parse().map_err(invalid)?;
+            compile_regex(&substitution.pattern).map_err(|err| invalid(err.to_string()))?;
+            OperatorArgument::Substitution(substitution)
+        }
+        VerifyCC => {
+            let pattern = required()?;
+            compile_regex(pattern).map_err(|err| invalid(err.to_string()))?;
+            OperatorArgument::Regex(pattern.into())
+        }
+        Regex => {
+            let pattern = required()?;
+            let macro_string = MacroString::parse(pattern);
+            if !macro_string.is_literal() {
+                // can only be compiled once the macros are expanded
+                OperatorArgument::MacroString(macro_string)
+            } else {
+                compile_regex(pattern).map_err(|err| invalid(err.to_string()))?;
+                OperatorArgument::Regex(pattern.into())
+            }
+        }
+        BeginsWith | Contains | EndsWith | StringEquals | StringMatch | Within
+        | RealtimeBlackhole => OperatorArgument::MacroString(MacroString::parse(required()?)),
+    })
+}
+
+pub fn parse_operator(
+    op: &str,
+    argument: Option<String>,
+    span: Span,
+) -> Result<Operator, OperatorParseError> {
     use OperatorParseError::*;
-    match OperatorType::from_name(&op) {
-        Some(op) => Ok(Operator { op, arg: argument }),
+    match OperatorType::from_name(op) {
+        Some(op) => Ok(Operator {
+            op,
+            argument: parse_argument(op, argument.as_deref())?,
+            arg: argument,
+            span,
+        }),
         None => Err(UnknownOperator(op.into()))?,
     }
 }
+
+/// Parses a number the way ModSecurity does (using `atoi`): leading whitespace is skipped, an
+/// optional sign is followed by digits, and anything after the digits is ignored. Values that
+/// don't start with a number are treated as 0.
+pub fn atoi(input: &[u8]) -> i64 {
+    let mut bytes = input
+        .iter()
+        .skip_while(|b| b.is_ascii_whitespace())
+        .peekable();
+    let negative = match bytes.peek() {
+        Some(b'-') => {
+            bytes.next();
+            true
+        }
+        Some(b'+') => {
+            bytes.next();
+            false
+        }
+        _ => false,
+    };
+
+    let mut value: i64 = 0;
+    for b in bytes.take_while(|b| b.is_ascii_digit()) {
+        let digit = (b - b'0') as i64;
+        value = match negative {
+            true => value.saturating_mul(10).saturating_sub(digit),
+            false => value.saturating_mul(10).saturating_add(digit),
+        };
+    }
+    value
+}
@@ -0,0 +1,218 @@
+//! Canonical formatting of rule configs in the CRS house style.
+//!
+//! Every directive with actions is written with one action per line (using backslash
+//! continuations), actions are sorted into a canonical order, all transformations share a single
+//! line, and chained rules are indented below their chain starter:
+//!
+//! ```text
+//! SecRule ARGS "@rx foo" \
+//!     "id:942100,\
+//!     phase:2,\
+//!     block,\
+//!     t:none,t:urlDecodeUni,\
+//!     msg:'SQL Injection Attack',\
+//!     tag:'paranoia-level/1',\
+//!     ver:'OWASP_CRS/4.0.0',\
+//!     severity:'CRITICAL',\
+//!     chain"
+//!     SecRule MATCHED_VARS "@rx bar" \
+//!         "setvar:'tx.sql_injection_score=+%{tx.critical_anomaly_score}'"
+//! ```
+
+use super::cst::{ConfDocument, Item};
+use super::{Action, ActionArgument, ActionType, CRSEntry, CRSParseError, Quoting};
+use std::fmt::Write;
+
+/// Number of spaces used for each level of indentation.
+const INDENT: usize = 4;
+
+/// Position of an action in the canonical ordering, actions with equal rank keep their relative
+/// order.
+fn action_rank(action: ActionType) -> u8 {
+    use ActionType::*;
+    match action {
+        Id => 0,
+        Phase => 1,
+        Allow | Block | Deny | Drop | Pass | Pause | Proxy | Redirect => 2,
+        Status => 3,
+        Capture => 4,
+        Transform => 5,
+        Log | NoLog => 6,
+        AuditLog | NoAuditLog => 7,
+        Msg => 8,
+        LogData => 9,
+        Tag => 10,
+        SanitiseArg
+        | SanitiseMatched
+        | SanitiseMatchedBytes
+        | SanitiseRequestHeader
+        | SanitiseResponseHeader => 11,
+        Revision => 12,
+        Version => 13,
+        Severity => 14,
+        Accuracy => 15,
+        Maturity => 16,
+        MultiMatch => 17,
+        XmlNs => 18,
+        Ctl => 19,
+        InitCollection => 20,
+        SetUser | SetSession | SetResource => 21,
+        SetEnv => 22,
+        Setvar => 23,
+        ExpireVar | DeprecateVar => 24,
+        Append | Prepend => 25,
+        Exec => 26,
+        Chain => 27,
+        Skip | SkipAfter => 28,
+    }
+}
+
+/// Whether an action's argument is always written single-quoted, even if it doesn't need to be.
+fn always_quoted(action: ActionType) -> bool {
+    use ActionType::*;
+    matches!(
+        action,
+        Msg | LogData | Tag | Version | Revision | Severity | Setvar | SetEnv
+    )
+}
+
+/// Writes an action argument using the canonical quoting style for the action.
+fn format_argument(action: ActionType, arg: &ActionArgument) -> String {
+    let quoting = if always_quoted(action) {
+        Quoting::Single
+    } else {
+        Quoting::Unquoted
+    };
+    ActionArgument::new(arg.value(), quoting).to_canonical_string()
+}
+
+fn format_action(action: &Action) -> String {
+    match &action.arg {
+        Some(arg) => format!(
+            "{}:{}",
+            action.action.name(),
+            format_argument(action.action, arg)
+        ),
+        None => action.action.name().to_string(),
+    }
+}
+
+/// Groups actions into output lines, in canonical order.
+fn action_lines(actions: &[Action]) -> Vec<String> {
+    let mut sorted: Vec<&Action> = actions.iter().collect();
+    sorted.sort_by_key(|a| action_rank(a.action));
+
+    let mut lines: Vec<String> = vec![];
+    let mut transforms: Option<usize> = None;
+    for action in sorted {
+        let formatted = format_action(action);
+        if action.action == ActionType::Transform {
+            // all transformations go on the same line
+            match transforms {
+                Some(line) => {
+                    lines[line].push(',');
+                    lines[line].push_str(&formatted);
+                }
+                None => {
+                    transforms = Some(lines.len());
+                    lines.push(formatted);
+                }
+            }
+        } else {
+            lines.push(formatted);
+        }
+    }
+    lines
+}
+
+fn write_actions(out: &mut String, actions: &[Action], indent: usize) -> std::fmt::Result {
+    let separator = format!(",\\\n{:indent$}", "", indent = indent + INDENT);
+    write!(out, " \\\n{:indent$}\"", "", indent = indent + INDENT)?;
+    out.push_str(&action_lines(actions).join(&separator));
+    out.push('"');
+    Ok(())
+}
+
+/// Formats a single entry in the canonical style, indented by `indent` spaces. The result does
+/// not include a trailing newline.
+pub fn format_entry(entry: &CRSEntry, indent: usize) -> String {
+    let mut out = format!("{:indent$}", "", indent = indent);
+    // writing to a String can't fail
+    let _ = write_entry(&mut out, entry, indent);
+    out
+}
+
+fn write_entry(out: &mut String, entry: &CRSEntry, indent: usize) -> std::fmt::Result {
+    match entry {
+        CRSEntry::SecMarker { marker, .. } => write!(out, "SecMarker \"{}\"", marker),
+        CRSEntry::SecComponentSignature { signature, .. } => {
+            write!(out, "SecComponentSignature \"{}\"", signature)
+        }
+        CRSEntry::SecCollectionTimeout { seconds, .. } => {
+            write!(out, "SecCollectionTimeout {}", seconds)
+        }
+        CRSEntry::SecAction { actions, .. } => {
+            out.push_str("SecAction");
+            write_actions(out, actions, indent)
+        }
+        CRSEntry::SecRule {
+            inputs,
+            test,
+            actions,
+            ..
+        } => {
+            let inputs: Vec<String> = inputs.iter().map(ToString::to_string).collect();
+            write!(out, "SecRule {} \"{}\"", inputs.join("|"), test)?;
+            if actions.is_empty() {
+                Ok(())
+            } else {
+                write_actions(out, actions, indent)
+            }
+        }
+    }
+}
+
+fn is_chain_starter(entry: &CRSEntry) -> bool {
+    match entry {
+        CRSEntry::SecRule { actions, .. } => actions.iter().any(|a| a.action == ActionType::Chain),
+        _ => false,
+    }
+}
+
+/// Formats a whole document. Comments and blank lines are kept (with trailing whitespace
+/// removed), while directives that failed to parse are left untouched.
+pub fn format_document(document: &ConfDocument) -> String {
+    let mut out = String::with_capacity(document.items.len() * 64);
+    let mut in_chain = false;
+
+    for item in &document.items {
+        match item {
+            Item::Blank(_) => out.push('\n'),
+            Item::Comment(text) => {
+                out.push_str(text.trim_end());
+                out.push('\n');
+            }
+            Item::Unparsed(text) => out.push_str(text),
+            Item::Directive(directive) => {
+                let entry = directive.entry();
+                let indent = if in_chain { INDENT } else { 0 };
+                out.push_str(&format_entry(entry, indent));
+                out.push('\n');
+                in_chain = is_chain_starter(entry);
+            }
+        }
+    }
+
+    out
+}
+
+/// Parses and formats rule config text. Fails if any directive can't be parsed, since the output
+/// could otherwise silently differ in meaning from the input.
+pub fn format_str(input: &str) -> Result<String, Vec<CRSParseError>> {
+    let (document, errors) = ConfDocument::parse(input);
+    if errors.is_empty() {
+        Ok(format_document(&document))
+    } else {
+        Err(errors)
+    }
+}
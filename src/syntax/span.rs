@@ -0,0 +1,166 @@
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A location within a parsed configuration file.
+///
+/// Lines and columns are 1-based (matching what editors and pest report), while `offset` is the
+/// 0-based byte offset into the parsed input. A line of 0 is used for positions that are unknown.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Returns true if this position doesn't point at an actual location in the input.
+    #[inline]
+    pub fn is_unknown(&self) -> bool {
+        self.line == 0
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The source region (file, start and end position) that a syntax element was parsed from.
+///
+/// Spans are metadata rather than part of the syntax itself, so two spans always compare (and
+/// hash) as equal. This keeps the derived `PartialEq` implementations of [`super::CRSEntry`] and
+/// its children purely structural, which round-trip validation relies on since re-parsed entries
+/// have different locations than the originals.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    pub file: Option<Arc<Path>>,
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Converts pest locations into [`Span`]s for a given file.
+///
+/// Input that was cut out of a larger file (e.g. a single directive) can be parsed separately by
+/// providing the position the input starts at, and the resulting spans will still be relative to
+/// the start of the original file.
+#[derive(Debug, Clone)]
+pub(crate) struct SpanContext {
+    file: Option<Arc<Path>>,
+    base: Position,
+}
+
+impl SpanContext {
+    pub fn new(file: Option<Arc<Path>>) -> Self {
+        Self {
+            file,
+            base: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+        }
+    }
+
+    pub fn file(&self) -> Option<&Arc<Path>> {
+        self.file.as_ref()
+    }
+
+    /// Returns a context for parsing a fragment of the input that starts at `base`.
+    pub fn with_base(&self, base: Position) -> Self {
+        Self {
+            file: self.file.clone(),
+            base,
+        }
+    }
+
+    fn position(&self, offset: usize, (line, column): (usize, usize)) -> Position {
+        Position {
+            offset: self.base.offset + offset,
+            line: self.base.line + line - 1,
+            column: if line == 1 {
+                self.base.column + column - 1
+            } else {
+                column
+            },
+        }
+    }
+
+    pub fn span(&self, span: pest::Span) -> Span {
+        let (start, end) = (span.start_pos(), span.end_pos());
+        Span {
+            file: self.file.clone(),
+            start: self.position(start.pos(), start.line_col()),
+            end: self.position(end.pos(), end.line_col()),
+        }
+    }
+
+    pub fn error_span<R: pest::RuleType>(&self, error: &pest::error::Error<R>) -> Span {
+        use pest::error::{InputLocation, LineColLocation};
+
+        let (start, end) = match error.location {
+            InputLocation::Pos(pos) => (pos, pos),
+            InputLocation::Span(span) => span,
+        };
+        let (start_line_col, end_line_col) = match error.line_col {
+            LineColLocation::Pos(pos) => (pos, pos),
+            LineColLocation::Span(start, end) => (start, end),
+        };
+
+        Span {
+            file: self.file.clone(),
+            start: self.position(start, start_line_col),
+            end: self.position(end, end_line_col),
+        }
+    }
+}
+
+impl Default for SpanContext {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Span {
+    /// Creates a span that refers to an entire file, rather than a location within it.
+    pub fn for_file(file: Option<&Arc<Path>>) -> Self {
+        Self {
+            file: file.cloned(),
+            ..Default::default()
+        }
+    }
+
+    /// The path of the file this span was parsed from, if known.
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+}
+
+impl PartialEq for Span {
+    #[inline]
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Hash for Span {
+    #[inline]
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl Display for Span {
+    /// Formats the span as `file:line:column`, omitting whichever parts are unknown.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.start.is_unknown()) {
+            (Some(file), false) => write!(f, "{}:{}", file.display(), self.start),
+            (Some(file), true) => write!(f, "{}", file.display()),
+            (None, false) => Display::fmt(&self.start, f),
+            (None, true) => f.write_str("<unknown>"),
+        }
+    }
+}
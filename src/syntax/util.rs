@@ -1,3 +1,4 @@
+use super::Position;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
@@ -72,3 +73,88 @@ pub fn get_rule_configs<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
         Ok(Default::default())
     }
 }
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SegmentKind {
+    /// Empty or whitespace-only line.
+    Blank,
+    /// A line comment (starting with `#`).
+    Comment,
+    /// A directive, including any lines joined to it with backslash continuations.
+    Directive,
+}
+
+/// A run of whole lines in a config file.
+#[derive(Debug, Copy, Clone)]
+pub struct Segment<'a> {
+    pub kind: SegmentKind,
+    /// The text of the segment, including its final line ending (if there is one).
+    pub text: &'a str,
+    pub start: Position,
+}
+
+/// Splits a config file into blank lines, comments, and directives without parsing them.
+///
+/// Concatenating the text of every returned segment gives back the original input.
+pub fn split_directives(input: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut directive_start: Option<Position> = None;
+
+    let mut offset = 0;
+    for (index, line) in input.split_inclusive('\n').enumerate() {
+        let line_start = Position {
+            offset,
+            line: index + 1,
+            column: 1,
+        };
+        offset += line.len();
+
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        let start = match directive_start {
+            Some(start) => start,
+            None => {
+                let trimmed = content.trim_start();
+                let kind = if trimmed.is_empty() {
+                    Some(SegmentKind::Blank)
+                } else if trimmed.starts_with('#') {
+                    Some(SegmentKind::Comment)
+                } else {
+                    None
+                };
+
+                if let Some(kind) = kind {
+                    segments.push(Segment {
+                        kind,
+                        text: line,
+                        start: line_start,
+                    });
+                    continue;
+                }
+                line_start
+            }
+        };
+
+        // directives continue onto the next line as long as the line ends with a backslash
+        if content.ends_with('\\') {
+            directive_start = Some(start);
+        } else {
+            directive_start = None;
+            segments.push(Segment {
+                kind: SegmentKind::Directive,
+                text: &input[start.offset..offset],
+                start,
+            });
+        }
+    }
+
+    // unterminated line continuation at the end of the input
+    if let Some(start) = directive_start {
+        segments.push(Segment {
+            kind: SegmentKind::Directive,
+            text: &input[start.offset..],
+            start,
+        });
+    }
+
+    segments
+}
@@ -0,0 +1,142 @@
+//! Runs the `crs-lint` binary against rule configs written to a temporary directory.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A rule that follows every convention.
+const CLEAN: &str = r#"SecRule ARGS "@rx a" "id:1,phase:2,block,t:none,ver:'OWASP_CRS/4.0.0',severity:'CRITICAL',tag:'attack-sqli'"
+"#;
+
+/// A rule without an id, and a rule without a severity.
+const INVALID: &str = r#"SecRule ARGS "@rx a" "phase:2,block,ver:'OWASP_CRS/4.0.0',severity:'CRITICAL'"
+SecRule ARGS "@rx b" "id:2,phase:2,block,ver:'OWASP_CRS/4.0.0'"
+"#;
+
+/// A config in a directory of its own, which is removed when it's dropped.
+struct Config(PathBuf);
+
+impl Deref for Config {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.0.parent().unwrap());
+    }
+}
+
+fn config(name: &str, contents: &str) -> Config {
+    let dir = std::env::temp_dir().join(format!("crs-lint-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("rules.conf");
+    fs::write(&path, contents).unwrap();
+    Config(path)
+}
+
+fn crs_lint(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_crs-lint"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn clean_configs_pass() {
+    let path = config("clean", CLEAN);
+    let output = crs_lint(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+
+    let output = crs_lint(&["--format", "json", path.parent().unwrap().to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output).trim(), "[]");
+}
+
+#[test]
+fn reports_diagnostics_as_text() {
+    let path = config("text", INVALID);
+    let output = crs_lint(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let file = path.display();
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{file}:1:1: error[missing-id]: rule is missing an id\n\
+             {file}:2:1: warning[missing-severity]: rule is missing severity (rule 2)\n"
+        )
+    );
+}
+
+#[test]
+fn reports_diagnostics_as_json() {
+    let path = config("json", INVALID);
+    let output = crs_lint(&["--format", "json", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let diagnostics: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    let file = path.display().to_string();
+    assert_eq!(
+        diagnostics,
+        serde_json::json!([
+            {
+                "check": "missing-id",
+                "level": "error",
+                "message": "rule is missing an id",
+                "file": file,
+                "line": 1,
+                "column": 1
+            },
+            {
+                "check": "missing-severity",
+                "level": "warning",
+                "message": "rule is missing severity",
+                "rule_id": "2",
+                "file": file,
+                "line": 2,
+                "column": 1
+            }
+        ])
+    );
+}
+
+#[test]
+fn warnings_alone_pass() {
+    let path = config("warnings", &CLEAN.replace("severity:'CRITICAL',", ""));
+    let output = crs_lint(&["--format", "json", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let diagnostics: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(diagnostics[0]["check"], "missing-severity");
+}
+
+#[test]
+fn reports_parse_errors_with_the_other_diagnostics() {
+    let path = config("parse-error", &format!("{}SecRule ARGS\n", INVALID));
+    let output = crs_lint(&["--format", "json", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let diagnostics: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    let checks: Vec<_> = diagnostics
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["check"].as_str().unwrap())
+        .collect();
+    assert_eq!(checks, ["parse-error", "missing-id", "missing-severity"]);
+}
+
+#[test]
+fn rejects_invalid_usage() {
+    assert_eq!(crs_lint(&[]).status.code(), Some(2));
+    assert_eq!(crs_lint(&["--format", "xml", "x"]).status.code(), Some(2));
+    assert_eq!(crs_lint(&["--verbose", "x"]).status.code(), Some(2));
+    assert_eq!(crs_lint(&["does-not-exist.conf"]).status.code(), Some(2));
+    assert_eq!(crs_lint(&["--help"]).status.code(), Some(0));
+}
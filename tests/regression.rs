@@ -0,0 +1,104 @@
+//! Runs CRS regression suites in-process, against the crate's own engine: a small fixture in
+//! `tests/fixtures/crs`, and the full suite from a CRS checkout in `coreruleset`, or in the
+//! directory named by `CRS_DIR`, if there is one.
+
+use std::path::{Path, PathBuf};
+use test_crs::engine::rule::EngineConfig;
+use test_crs::engine::ruleset::RuleSet;
+use test_crs::ftw::{File, InProcessRunner};
+use test_crs::syntax;
+
+/// What happened when a suite was run.
+#[derive(Debug, Default)]
+struct SuiteResult {
+    /// The rules that failed to compile, and were left out of the rule set.
+    compile_errors: Vec<String>,
+    /// The number of tests that were run.
+    tests: usize,
+    failures: Vec<String>,
+}
+
+/// Runs the tests of a directory laid out like CRS, with the rules in `rules` and the tests in
+/// `tests/regression/tests/*/*.yaml`.
+fn run_suite(crs: &Path) -> Result<SuiteResult, String> {
+    let files = syntax::parse_all_conf(crs.join("rules"))
+        .map_err(|error| format!("failed to parse the rules in {}, {}", crs.display(), error))?;
+    let entries = files.iter().flat_map(|file| &file.entries);
+    let report = RuleSet::compile(entries, &EngineConfig::default());
+    let mut result = SuiteResult {
+        compile_errors: report.errors.iter().map(ToString::to_string).collect(),
+        ..Default::default()
+    };
+    let runner = InProcessRunner::new(report.rules);
+
+    let pattern = crs.join("tests/regression/tests/*/*.yaml");
+    let paths = glob::glob(&pattern.to_string_lossy()).map_err(|error| error.to_string())?;
+    for path in paths {
+        let path = path.map_err(|error| error.to_string())?;
+        let file = match File::from_path(&path) {
+            Ok(file) => file,
+            Err(error) => {
+                result
+                    .failures
+                    .push(format!("{}: {}", path.display(), error));
+                continue;
+            }
+        };
+        for test in runner.run_file(&file) {
+            result.tests += 1;
+            if test.status.is_failed() {
+                result
+                    .failures
+                    .push(format!("{}: {}", test.title, test.status));
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn fixture_suite() -> Result<(), String> {
+    let crs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/crs");
+    let result = run_suite(&crs)?;
+
+    // the @detectSQLi rule and the @inspectFile chain are left out, without affecting the
+    // other rules
+    assert_eq!(
+        result.compile_errors.len(),
+        2,
+        "{:?}",
+        result.compile_errors
+    );
+    assert!(result.compile_errors[0].contains("detectSQLi"));
+    assert!(result.compile_errors[1].contains("runav.pl"));
+    assert_eq!(result.tests, 10);
+    assert!(
+        result.failures.is_empty(),
+        "{} tests failed:\n{}",
+        result.failures.len(),
+        result.failures.join("\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn crs_regression_suite() -> Result<(), String> {
+    let crs = PathBuf::from(std::env::var("CRS_DIR").unwrap_or_else(|_| "coreruleset".into()));
+    if !crs.join("rules").is_dir() {
+        eprintln!("no CRS checkout in {}, skipping", crs.display());
+        return Ok(());
+    }
+
+    let result = run_suite(&crs)?;
+    for error in &result.compile_errors {
+        eprintln!("rule left out, {}", error);
+    }
+    assert!(
+        result.failures.is_empty(),
+        "{} of {} tests failed:\n{}",
+        result.failures.len(),
+        result.tests,
+        result.failures.join("\n")
+    );
+    Ok(())
+}